@@ -0,0 +1,217 @@
+// Selection of the backend server that the reverse proxy forwards each connection to.
+
+use crate::client_identity::IdentityForwarding;
+use crate::errors::*;
+use crate::tls::UpstreamTlsSettings;
+use error_chain::bail;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Servers that take turns receiving connections
+#[derive(Clone, Debug, PartialEq)]
+struct Group {
+    servers: Vec<SocketAddr>,
+    next: usize,
+}
+
+impl Group {
+    fn new(servers: Vec<SocketAddr>) -> Group {
+        Group { servers, next: 0 }
+    }
+
+    fn next_server(&mut self) -> Option<SocketAddr> {
+        let server = *self.servers.get(self.next % self.servers.len().max(1))?;
+        self.next = self.next.wrapping_add(1);
+        Some(server)
+    }
+}
+
+/// Backend servers of the reverse proxy. Connections are spread over the servers round robin.
+///
+/// Connections that negotiated an ALPN protocol with a route go to that route's servers, e.g. to
+/// send HTTP/2 connections to backends that support it. Everything else goes to the default
+/// servers.
+///
+/// Servers with upstream TLS settings are connected to over TLS, the rest over plain TCP. Servers
+/// with identity forwarding are told which forward proxy each connection came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backends {
+    default: Group,
+    alpn_routes: Vec<(Vec<u8>, Group)>,
+    upstream_tls: HashMap<SocketAddr, UpstreamTlsSettings>,
+    identity_forwarding: HashMap<SocketAddr, IdentityForwarding>,
+}
+
+impl Backends {
+    pub fn new(servers: Vec<SocketAddr>) -> Backends {
+        Backends {
+            default: Group::new(servers),
+            alpn_routes: Vec::new(),
+            upstream_tls: HashMap::new(),
+            identity_forwarding: HashMap::new(),
+        }
+    }
+
+    /// Sends connections that negotiated the ALPN protocol to the servers. Adding servers for a
+    /// protocol that already has a route adds them to that route.
+    pub fn add_alpn_route(&mut self, protocol: &[u8], servers: Vec<SocketAddr>) {
+        match self
+            .alpn_routes
+            .iter_mut()
+            .find(|(route_protocol, _)| route_protocol == protocol)
+        {
+            Some((_, group)) => group.servers.extend(servers),
+            None => self
+                .alpn_routes
+                .push((protocol.to_vec(), Group::new(servers))),
+        }
+    }
+
+    /// Connects to the server over TLS with the settings. Returns an error if the server isn't one
+    /// of the default servers or in an ALPN route, since the settings would never be used.
+    pub fn set_upstream_tls(
+        &mut self,
+        server: SocketAddr,
+        settings: UpstreamTlsSettings,
+    ) -> Result<()> {
+        if !self.contains(server) {
+            bail!("{} has TLS settings but isn't a backend server", server);
+        }
+        self.upstream_tls.insert(server, settings);
+        Ok(())
+    }
+
+    /// TLS settings of the servers that are connected to over TLS
+    pub fn upstream_tls(&self) -> &HashMap<SocketAddr, UpstreamTlsSettings> {
+        &self.upstream_tls
+    }
+
+    /// Passes the identity of forward proxies to the server. Returns an error if the server isn't
+    /// one of the default servers or in an ALPN route, or if the settings are invalid.
+    pub fn set_identity_forwarding(
+        &mut self,
+        server: SocketAddr,
+        forwarding: IdentityForwarding,
+    ) -> Result<()> {
+        if !self.contains(server) {
+            bail!(
+                "{} has identity forwarding but isn't a backend server",
+                server
+            );
+        }
+        forwarding.validate()?;
+        self.identity_forwarding.insert(server, forwarding);
+        Ok(())
+    }
+
+    /// How the server gets the identity of forward proxies, None if it doesn't
+    pub fn identity_forwarding(&self, server: SocketAddr) -> Option<&IdentityForwarding> {
+        self.identity_forwarding.get(&server)
+    }
+
+    /// Whether the server is one of the default servers or in an ALPN route
+    fn contains(&self, server: SocketAddr) -> bool {
+        std::iter::once(&self.default)
+            .chain(self.alpn_routes.iter().map(|(_, group)| group))
+            .any(|group| group.servers.contains(&server))
+    }
+
+    /// Returns the server for a new connection, given the ALPN protocol it negotiated. Returns None
+    /// if there are no servers to use.
+    pub fn select(&mut self, alpn_protocol: Option<&[u8]>) -> Option<SocketAddr> {
+        let route = alpn_protocol.and_then(|protocol| {
+            self.alpn_routes
+                .iter_mut()
+                .find(|(route_protocol, _)| route_protocol == protocol)
+        });
+        match route {
+            Some((_, group)) => group.next_server(),
+            None => self.default.next_server(),
+        }
+    }
+}
+
+impl From<Vec<SocketAddr>> for Backends {
+    fn from(servers: Vec<SocketAddr>) -> Backends {
+        Backends::new(servers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backends::Backends;
+    use crate::client_identity::{HeaderNames, IdentityForwarding, TlvTypes};
+    use crate::tls::UpstreamTlsSettings;
+    use std::net::SocketAddr;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn select_round_robin() {
+        let mut backends = Backends::new(vec![addr(1), addr(2)]);
+
+        assert_eq!(backends.select(None), Some(addr(1)));
+        assert_eq!(backends.select(None), Some(addr(2)));
+        assert_eq!(backends.select(None), Some(addr(1)));
+    }
+
+    #[test]
+    fn select_by_alpn() {
+        let mut backends = Backends::new(vec![addr(1)]);
+        backends.add_alpn_route(b"h2", vec![addr(2)]);
+        backends.add_alpn_route(b"h2", vec![addr(3)]);
+
+        assert_eq!(backends.select(Some(b"h2")), Some(addr(2)));
+        assert_eq!(backends.select(Some(b"h2")), Some(addr(3)));
+        assert_eq!(backends.select(Some(b"http/1.1")), Some(addr(1)));
+        assert_eq!(backends.select(None), Some(addr(1)));
+    }
+
+    #[test]
+    fn select_without_servers() {
+        let mut backends = Backends::new(vec![]);
+        backends.add_alpn_route(b"h2", vec![]);
+
+        assert_eq!(backends.select(None), None);
+        assert_eq!(backends.select(Some(b"h2")), None);
+    }
+
+    #[test]
+    fn upstream_tls_for_known_servers() {
+        let mut backends = Backends::new(vec![addr(1)]);
+        backends.add_alpn_route(b"h2", vec![addr(2)]);
+        let settings = UpstreamTlsSettings::new("backend.lab", "ca_cert.pem").unwrap();
+
+        backends
+            .set_upstream_tls(addr(1), settings.clone())
+            .unwrap();
+        backends
+            .set_upstream_tls(addr(2), settings.clone())
+            .unwrap();
+        assert!(backends.set_upstream_tls(addr(3), settings).is_err());
+        assert_eq!(backends.upstream_tls().len(), 2);
+    }
+
+    #[test]
+    fn identity_forwarding_for_known_servers() {
+        let mut backends = Backends::new(vec![addr(1), addr(2)]);
+        let proxy_protocol = IdentityForwarding::ProxyProtocol(TlvTypes::default());
+
+        backends
+            .set_identity_forwarding(addr(1), proxy_protocol.clone())
+            .unwrap();
+        assert_eq!(backends.identity_forwarding(addr(1)), Some(&proxy_protocol));
+        assert_eq!(backends.identity_forwarding(addr(2)), None);
+        assert!(backends
+            .set_identity_forwarding(addr(3), proxy_protocol)
+            .is_err());
+
+        let mut names = HeaderNames::default();
+        names.set("sans", "x-client-cert-subject").unwrap();
+        assert!(backends
+            .set_identity_forwarding(addr(2), IdentityForwarding::HttpHeaders(names))
+            .is_err());
+    }
+}
@@ -0,0 +1,897 @@
+// Identity of the forward proxies that authenticated with a client certificate, passed on to the
+// backends the reverse proxy forwards their connections to. Raw TCP backends get it in PROXY
+// protocol v2 TLVs sent before the connection's data, and HTTP backends in request headers.
+
+use crate::errors::*;
+use crate::tls;
+use error_chain::bail;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use tokio_rustls::rustls::{ProtocolVersion, ServerSession, Session};
+
+/// Names of the ways identity can be forwarded, used by the CLI
+pub const MODE_NAMES: &[&str] = &["proxy-v2", "http"];
+/// Names of the fields that HeaderNames::set() and TlvTypes::set() rename
+pub const FIELD_NAMES: &[&str] = &["subject", "sans", "fingerprint"];
+/// TLV types the PROXY protocol spec leaves for applications
+pub const CUSTOM_TLV_TYPES: RangeInclusive<u8> = 0xe0..=0xef;
+
+/// PROXY protocol v2 signature, the version 2 PROXY command, and the address families
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V2_COMMAND: u8 = 0x21;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// TLV and flags from the PROXY protocol spec for connections received over TLS
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+
+/// Longest request line and headers accepted from clients of HTTP backends
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Longest chunk size or trailer line accepted from clients of HTTP backends
+const MAX_LINE_SIZE: usize = 4096;
+
+/// Identity taken from a verified client certificate
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    /// Formatted like `CN=forward-proxy, O=Example`
+    pub subject: String,
+    /// Formatted like `DNS:forward-proxy`, in the order they appear in the certificate
+    pub alt_names: Vec<String>,
+    /// Lowercase hex SHA-256 hash of the DER certificate
+    pub fingerprint: String,
+}
+
+impl ClientIdentity {
+    pub fn from_cert(cert: &[u8]) -> Result<ClientIdentity> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+        Ok(ClientIdentity {
+            subject: tls::cert_subject(cert)?,
+            alt_names: tls::cert_subject_alt_names(cert)?,
+            fingerprint: digest
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        })
+    }
+
+    /// The fields as text, in the order of FIELD_NAMES. SANs are left out if there are none.
+    fn fields(&self) -> [Option<String>; 3] {
+        let alt_names = match self.alt_names.is_empty() {
+            true => None,
+            false => Some(self.alt_names.join(", ")),
+        };
+        [
+            Some(self.subject.clone()),
+            alt_names,
+            Some(self.fingerprint.clone()),
+        ]
+    }
+}
+
+/// TLS connection accepted from a forward proxy
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientTls {
+    /// Formatted like `TLSv1.3`
+    pub version: String,
+    /// None if the forward proxy didn't present a certificate
+    pub identity: Option<ClientIdentity>,
+}
+
+impl ClientTls {
+    /// Reads the version and client certificate of a completed handshake. The certificate has
+    /// already been verified by rustls.
+    pub fn from_session(session: &ServerSession) -> Result<ClientTls> {
+        let version = match session.get_protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            version => format!("{:?}", version),
+        };
+        let identity = match session.get_peer_certificates() {
+            Some(certs) if !certs.is_empty() => Some(
+                ClientIdentity::from_cert(&certs[0].0)
+                    .chain_err(|| "can't read the client certificate")?,
+            ),
+            _ => None,
+        };
+        Ok(ClientTls { version, identity })
+    }
+}
+
+/// Looks up a field in FIELD_NAMES
+fn field_index(field: &str) -> Result<usize> {
+    match FIELD_NAMES.iter().position(|name| *name == field) {
+        Some(index) => Ok(index),
+        None => bail!(
+            "unknown identity field \"{}\", expected one of {}",
+            field,
+            FIELD_NAMES.join(", ")
+        ),
+    }
+}
+
+/// Headers that HTTP backends get the identity in
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderNames {
+    pub subject: String,
+    pub alt_names: String,
+    pub fingerprint: String,
+}
+
+impl Default for HeaderNames {
+    fn default() -> HeaderNames {
+        HeaderNames {
+            subject: "X-Client-Cert-Subject".to_string(),
+            alt_names: "X-Client-Cert-SAN".to_string(),
+            fingerprint: "X-Client-Cert-Fingerprint".to_string(),
+        }
+    }
+}
+
+impl HeaderNames {
+    /// Renames the header of a field in FIELD_NAMES
+    pub fn set(&mut self, field: &str, name: &str) -> Result<()> {
+        let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if name.is_empty() || !name.chars().all(is_token_char) {
+            bail!("\"{}\" isn't a valid header name", name);
+        }
+        *self.names_mut()[field_index(field)?] = name.to_string();
+        Ok(())
+    }
+
+    fn names(&self) -> [&String; 3] {
+        [&self.subject, &self.alt_names, &self.fingerprint]
+    }
+
+    fn names_mut(&mut self) -> [&mut String; 3] {
+        [
+            &mut self.subject,
+            &mut self.alt_names,
+            &mut self.fingerprint,
+        ]
+    }
+
+    fn validate(&self) -> Result<()> {
+        let names = self.names();
+        for (i, name) in names.iter().enumerate() {
+            if names[..i]
+                .iter()
+                .any(|other| normalize_header_name(other) == normalize_header_name(name))
+            {
+                bail!("identity header {} is used for two fields", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// TLV types that raw TCP backends get the identity in, on top of the spec's PP2_TYPE_SSL
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlvTypes {
+    pub subject: u8,
+    pub alt_names: u8,
+    pub fingerprint: u8,
+}
+
+impl Default for TlvTypes {
+    fn default() -> TlvTypes {
+        TlvTypes {
+            subject: 0xe0,
+            alt_names: 0xe1,
+            fingerprint: 0xe2,
+        }
+    }
+}
+
+impl TlvTypes {
+    /// Changes the TLV type of a field in FIELD_NAMES. Types must be in CUSTOM_TLV_TYPES so they
+    /// can't clash with the types the spec defines.
+    pub fn set(&mut self, field: &str, tlv_type: u8) -> Result<()> {
+        if !CUSTOM_TLV_TYPES.contains(&tlv_type) {
+            bail!(
+                "TLV type {:#04x} isn't in the custom range {:#04x}-{:#04x}",
+                tlv_type,
+                CUSTOM_TLV_TYPES.start(),
+                CUSTOM_TLV_TYPES.end()
+            );
+        }
+        *self.types_mut()[field_index(field)?] = tlv_type;
+        Ok(())
+    }
+
+    fn types(&self) -> [u8; 3] {
+        [self.subject, self.alt_names, self.fingerprint]
+    }
+
+    fn types_mut(&mut self) -> [&mut u8; 3] {
+        [
+            &mut self.subject,
+            &mut self.alt_names,
+            &mut self.fingerprint,
+        ]
+    }
+
+    fn validate(&self) -> Result<()> {
+        let types = self.types();
+        for (i, tlv_type) in types.iter().enumerate() {
+            if types[..i].contains(tlv_type) {
+                bail!("identity TLV type {:#04x} is used for two fields", tlv_type);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a backend gets the identity of the forward proxies whose connections it receives
+#[derive(Clone, Debug, PartialEq)]
+pub enum IdentityForwarding {
+    /// A PROXY protocol v2 header sent before anything else, for raw TCP backends. It also carries
+    /// the forward proxy's address, and is sent even when there is no client certificate.
+    ProxyProtocol(TlvTypes),
+    /// Headers added to every HTTP/1 request. Headers with the same names sent by the client are
+    /// removed, so they can't be forged.
+    HttpHeaders(HeaderNames),
+}
+
+impl IdentityForwarding {
+    /// Returns the default settings of a mode in MODE_NAMES
+    pub fn from_mode(mode: &str) -> Result<IdentityForwarding> {
+        match mode {
+            "proxy-v2" => Ok(IdentityForwarding::ProxyProtocol(TlvTypes::default())),
+            "http" => Ok(IdentityForwarding::HttpHeaders(HeaderNames::default())),
+            _ => bail!(
+                "unknown identity forwarding mode \"{}\", expected one of {}",
+                mode,
+                MODE_NAMES.join(", ")
+            ),
+        }
+    }
+
+    /// Returns an error if two fields would be sent under the same name
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            IdentityForwarding::ProxyProtocol(types) => types.validate(),
+            IdentityForwarding::HttpHeaders(names) => names.validate(),
+        }
+    }
+}
+
+fn push_tlv(buf: &mut Vec<u8>, tlv_type: u8, value: &[u8]) -> Result<()> {
+    let len = u16::try_from(value.len()).chain_err(|| "PROXY protocol TLV is too long")?;
+    buf.push(tlv_type);
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+/// Builds the PROXY protocol v2 header for a connection from `source` to the reverse proxy's
+/// `destination` address. Connections received over TLS get a PP2_TYPE_SSL TLV, and the identity
+/// TLVs if the forward proxy presented a certificate.
+pub fn proxy_v2_header(
+    source: SocketAddr,
+    destination: SocketAddr,
+    tls: Option<&ClientTls>,
+    types: &TlvTypes,
+) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let family = match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            body.extend_from_slice(&source.ip().octets());
+            body.extend_from_slice(&destination.ip().octets());
+            TCP_OVER_IPV4
+        }
+        // Mixed families can't be sent, so IPv4 addresses are mapped into IPv6
+        _ => {
+            for addr in [source, destination] {
+                let ip = match addr {
+                    SocketAddr::V4(addr) => addr.ip().to_ipv6_mapped(),
+                    SocketAddr::V6(addr) => *addr.ip(),
+                };
+                body.extend_from_slice(&ip.octets());
+            }
+            TCP_OVER_IPV6
+        }
+    };
+    body.extend_from_slice(&source.port().to_be_bytes());
+    body.extend_from_slice(&destination.port().to_be_bytes());
+
+    if let Some(tls) = tls {
+        let mut ssl = Vec::new();
+        // rustls only completes handshakes with certificates it verified, so verify is 0 when
+        // there is one
+        match tls.identity {
+            Some(_) => {
+                ssl.push(PP2_CLIENT_SSL | PP2_CLIENT_CERT_CONN);
+                ssl.extend_from_slice(&0u32.to_be_bytes());
+            }
+            None => {
+                ssl.push(PP2_CLIENT_SSL);
+                ssl.extend_from_slice(&1u32.to_be_bytes());
+            }
+        }
+        push_tlv(&mut ssl, PP2_SUBTYPE_SSL_VERSION, tls.version.as_bytes())?;
+        push_tlv(&mut body, PP2_TYPE_SSL, &ssl)?;
+
+        if let Some(identity) = &tls.identity {
+            for (tlv_type, value) in types.types().iter().zip(identity.fields()) {
+                if let Some(value) = value {
+                    push_tlv(&mut body, *tlv_type, value.as_bytes())?;
+                }
+            }
+        }
+    }
+
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    header.push(PROXY_V2_COMMAND);
+    header.push(family);
+    let len = u16::try_from(body.len()).chain_err(|| "PROXY protocol header is too long")?;
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&body);
+    Ok(header)
+}
+
+/// Lowercases a header name and treats `_` as `-`, since CGI style backends turn both into `_`
+/// and would take a forged `X_Client_Cert_Subject` for the real header
+fn normalize_header_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace('_', "-")
+}
+
+/// Where the HeaderInjector is in the stream of requests
+#[derive(Debug, PartialEq)]
+enum RequestState {
+    /// Reading a request line and headers, held back until the empty line that ends them
+    Head(Vec<u8>),
+    /// Forwarding this many more bytes of a body with a Content-Length
+    Body(u64),
+    /// Reading the size line of the next chunk of a chunked body
+    ChunkSize(Vec<u8>),
+    /// Forwarding this many more bytes of chunk data
+    ChunkData(u64),
+    /// Reading trailer lines after the last chunk, until an empty line
+    Trailer(Vec<u8>),
+}
+
+/// Adds the identity headers to each HTTP/1 request sent by a client, and removes any the client
+/// sent itself. Bodies are followed through their Content-Length or chunked encoding to find where
+/// the next request starts.
+///
+/// CONNECT and upgrade requests are refused. Whether the backend switched protocols is only known
+/// from its response, which isn't looked at, and a client could otherwise follow a refused upgrade
+/// with requests carrying forged headers.
+pub struct HeaderInjector {
+    /// `Name: value` lines added to every request
+    added: Vec<u8>,
+    /// Normalized names of the headers removed from requests
+    removed: Vec<String>,
+    state: RequestState,
+}
+
+impl HeaderInjector {
+    /// Requests only get headers if there is an identity, but headers with the names are removed
+    /// either way
+    pub fn new(names: &HeaderNames, identity: Option<&ClientIdentity>) -> HeaderInjector {
+        let mut added = Vec::new();
+        if let Some(identity) = identity {
+            for (name, value) in names.names().iter().zip(identity.fields()) {
+                if let Some(value) = value {
+                    // Certificate fields can hold anything, including line breaks
+                    let value: String = value
+                        .chars()
+                        .map(|c| if c.is_control() { '?' } else { c })
+                        .collect();
+                    added.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+                }
+            }
+        }
+        HeaderInjector {
+            added,
+            removed: names
+                .names()
+                .iter()
+                .map(|name| normalize_header_name(name))
+                .collect(),
+            state: RequestState::Head(Vec::new()),
+        }
+    }
+
+    /// Returns the data to forward in place of data read from the client. Request heads are held
+    /// back until they are complete. Returns an error if the data isn't HTTP/1.
+    pub fn push(&mut self, mut data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len());
+        while !data.is_empty() {
+            let consumed = self.step(data, &mut out)?;
+            data = &data[consumed..];
+        }
+        Ok(out)
+    }
+
+    /// Handles data up to the end of the current state, returning how much was used
+    fn step(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<usize> {
+        let state = std::mem::replace(&mut self.state, RequestState::Head(Vec::new()));
+        let (consumed, next_state) = match state {
+            // Empty lines before a request are allowed, and forwarded as they are
+            RequestState::Head(head) if head.is_empty() && matches!(data[0], b'\r' | b'\n') => {
+                out.push(data[0]);
+                (1, RequestState::Head(head))
+            }
+            RequestState::Head(mut head) => {
+                let (consumed, complete) = read_head(&mut head, data)?;
+                match complete {
+                    true => (consumed, self.rewrite_head(&head, out)?),
+                    false => (consumed, RequestState::Head(head)),
+                }
+            }
+            RequestState::Body(left) => {
+                let len = forward(data, left, out);
+                match left - len {
+                    0 => (len as usize, RequestState::Head(Vec::new())),
+                    left => (len as usize, RequestState::Body(left)),
+                }
+            }
+            RequestState::ChunkData(left) => {
+                let len = forward(data, left, out);
+                match left - len {
+                    0 => (len as usize, RequestState::ChunkSize(Vec::new())),
+                    left => (len as usize, RequestState::ChunkData(left)),
+                }
+            }
+            RequestState::ChunkSize(mut line) => {
+                let (consumed, complete) = read_line(&mut line, data)?;
+                if !complete {
+                    return self.keep(RequestState::ChunkSize(line), consumed);
+                }
+                out.extend_from_slice(&line);
+                let size = String::from_utf8_lossy(&line);
+                // Chunk extensions follow a semicolon
+                let size = size.split(';').next().unwrap_or_default().trim();
+                match size {
+                    // The line break that ends the previous chunk's data
+                    "" => (consumed, RequestState::ChunkSize(Vec::new())),
+                    size if !size.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                        bail!("invalid chunk size \"{}\"", size)
+                    }
+                    size => match u64::from_str_radix(size, 16) {
+                        Ok(0) => (consumed, RequestState::Trailer(Vec::new())),
+                        Ok(size) => (consumed, RequestState::ChunkData(size)),
+                        Err(_) => bail!("invalid chunk size \"{}\"", size),
+                    },
+                }
+            }
+            RequestState::Trailer(mut line) => {
+                let (consumed, complete) = read_line(&mut line, data)?;
+                if !complete {
+                    return self.keep(RequestState::Trailer(line), consumed);
+                }
+                if line == b"\r\n" {
+                    out.extend_from_slice(&line);
+                    (consumed, RequestState::Head(Vec::new()))
+                } else if line[0] == b' ' || line[0] == b'\t' {
+                    bail!("obsolete line folding in HTTP trailer");
+                } else {
+                    // Trailer fields are merged into the headers by some backends
+                    if !self.is_removed(&line) {
+                        out.extend_from_slice(&line);
+                    }
+                    (consumed, RequestState::Trailer(Vec::new()))
+                }
+            }
+        };
+        self.state = next_state;
+        Ok(consumed)
+    }
+
+    fn keep(&mut self, state: RequestState, consumed: usize) -> Result<usize> {
+        self.state = state;
+        Ok(consumed)
+    }
+
+    /// Whether a header line has the name of one of the identity headers
+    fn is_removed(&self, line: &[u8]) -> bool {
+        match line.iter().position(|&byte| byte == b':') {
+            Some(colon) => {
+                let name = String::from_utf8_lossy(&line[..colon]);
+                self.removed.contains(&normalize_header_name(name.trim()))
+            }
+            None => false,
+        }
+    }
+
+    /// Writes a complete request head without the identity headers the client sent, and with the
+    /// real ones added. Returns the state to read the request's body in.
+    fn rewrite_head(&self, head: &[u8], out: &mut Vec<u8>) -> Result<RequestState> {
+        let mut lines = head
+            .split(|&byte| byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        let request_line = lines.next().unwrap_or_default();
+        let version = request_line.split(|&byte| byte == b' ').next_back();
+        if !version.is_some_and(|version| version.starts_with(b"HTTP/1.")) {
+            bail!(
+                "not an HTTP/1 request: \"{}\"",
+                String::from_utf8_lossy(request_line)
+            );
+        }
+        if request_line.starts_with(b"CONNECT ") {
+            bail!("CONNECT requests can't be forwarded with identity headers");
+        }
+        out.extend_from_slice(request_line);
+        out.extend_from_slice(b"\r\n");
+
+        let mut content_length = None;
+        let mut chunked = false;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            // Backends that don't support obsolete line folding would take the continuation for a
+            // header of its own
+            if line[0] == b' ' || line[0] == b'\t' {
+                bail!("obsolete line folding in HTTP request head");
+            }
+            let colon = line
+                .iter()
+                .position(|&byte| byte == b':')
+                .ok_or("invalid HTTP header line")?;
+            match line[..colon].last() {
+                None => bail!("invalid HTTP header line"),
+                // Backends disagree on whether `Name :` is the header `Name`
+                Some(b' ') | Some(b'\t') => bail!("whitespace before the colon of an HTTP header"),
+                _ => (),
+            }
+            if self.is_removed(line) {
+                continue;
+            }
+
+            let name = String::from_utf8_lossy(&line[..colon]).to_ascii_lowercase();
+            let value = String::from_utf8_lossy(&line[colon + 1..]);
+            let value = value.trim();
+            match name.as_str() {
+                "content-length" => {
+                    // u64::from_str also takes a leading +, which backends may not
+                    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                        bail!("invalid Content-Length \"{}\"", value);
+                    }
+                    let len = value
+                        .parse()
+                        .chain_err(|| format!("invalid Content-Length \"{}\"", value))?;
+                    if content_length.is_some_and(|other| other != len) {
+                        bail!("conflicting Content-Length headers");
+                    }
+                    content_length = Some(len);
+                }
+                "transfer-encoding" => {
+                    // Backends disagree on lists of codings and repeated headers, so only a single
+                    // chunked coding is accepted
+                    if chunked || !value.eq_ignore_ascii_case("chunked") {
+                        bail!("unsupported Transfer-Encoding \"{}\"", value);
+                    }
+                    chunked = true;
+                }
+                // Some backends switch protocols without the Connection option, so either refuses
+                "upgrade" => bail!("upgrade requests can't be forwarded with identity headers"),
+                "connection"
+                    if value
+                        .split(',')
+                        .any(|option| option.trim().eq_ignore_ascii_case("upgrade")) =>
+                {
+                    bail!("upgrade requests can't be forwarded with identity headers")
+                }
+                _ => (),
+            }
+            out.extend_from_slice(line);
+            out.extend_from_slice(b"\r\n");
+        }
+        // Backends that take the Content-Length would see a different request boundary
+        if chunked && content_length.is_some() {
+            bail!("request has both Transfer-Encoding and Content-Length");
+        }
+        out.extend_from_slice(&self.added);
+        out.extend_from_slice(b"\r\n");
+
+        Ok(match (chunked, content_length) {
+            (true, _) => RequestState::ChunkSize(Vec::new()),
+            (false, Some(len)) if len > 0 => RequestState::Body(len),
+            _ => RequestState::Head(Vec::new()),
+        })
+    }
+}
+
+/// Adds data to a request head up to the empty line that ends it. Returns how much data was used
+/// and whether the head is complete.
+fn read_head(head: &mut Vec<u8>, data: &[u8]) -> Result<(usize, bool)> {
+    let mut result = (data.len(), false);
+    for (i, &byte) in data.iter().enumerate() {
+        push_byte(head, byte)?;
+        if head.ends_with(b"\r\n\r\n") {
+            result = (i + 1, true);
+            break;
+        }
+    }
+    if head.len() > MAX_HEAD_SIZE {
+        bail!("HTTP request head is longer than {} bytes", MAX_HEAD_SIZE);
+    }
+    Ok(result)
+}
+
+/// Adds data to a line up to its line feed. Returns how much data was used and whether the line
+/// is complete.
+fn read_line(line: &mut Vec<u8>, data: &[u8]) -> Result<(usize, bool)> {
+    let mut result = (data.len(), false);
+    for (i, &byte) in data.iter().enumerate() {
+        push_byte(line, byte)?;
+        if byte == b'\n' {
+            result = (i + 1, true);
+            break;
+        }
+    }
+    if line.len() > MAX_LINE_SIZE {
+        bail!(
+            "HTTP chunk size or trailer line is longer than {} bytes",
+            MAX_LINE_SIZE
+        );
+    }
+    Ok(result)
+}
+
+/// Adds a byte to a request head or line. Returns an error for a carriage return or line feed that
+/// isn't part of a CRLF, since lenient backends break lines at either and would see different
+/// headers than the ones checked here.
+fn push_byte(buf: &mut Vec<u8>, byte: u8) -> Result<()> {
+    match (buf.last(), byte) {
+        (Some(b'\r'), b'\n') => (),
+        (Some(b'\r'), _) => bail!("carriage return without a line feed in HTTP request"),
+        (_, b'\n') => bail!("line feed without a carriage return in HTTP request"),
+        _ => (),
+    }
+    buf.push(byte);
+    Ok(())
+}
+
+/// Forwards up to `left` bytes of data, returning how many were forwarded
+fn forward(data: &[u8], left: u64, out: &mut Vec<u8>) -> u64 {
+    let len = usize::try_from(left).unwrap_or(usize::MAX).min(data.len());
+    out.extend_from_slice(&data[..len]);
+    len as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_identity::{
+        proxy_v2_header, ClientIdentity, ClientTls, HeaderInjector, HeaderNames,
+        IdentityForwarding, TlvTypes,
+    };
+    use crate::tls::load_certs;
+    use std::path::Path;
+
+    fn identity() -> ClientIdentity {
+        ClientIdentity {
+            subject: "CN=forward-proxy".to_string(),
+            alt_names: vec!["DNS:forward-proxy".to_string(), "IP:10.0.0.1".to_string()],
+            fingerprint: "ab01".to_string(),
+        }
+    }
+
+    const ADDED: &str = "X-Client-Cert-Subject: CN=forward-proxy\r\n\
+                         X-Client-Cert-SAN: DNS:forward-proxy, IP:10.0.0.1\r\n\
+                         X-Client-Cert-Fingerprint: ab01\r\n";
+
+    fn inject(injector: &mut HeaderInjector, data: &str) -> String {
+        String::from_utf8(injector.push(data.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn identity_from_cert() {
+        let cert = &load_certs(Path::new("tests/certs/client_cert.pem")).unwrap()[0];
+        assert_eq!(
+            ClientIdentity::from_cert(&cert.0).unwrap(),
+            ClientIdentity {
+                subject: "CN=forward-proxy".to_string(),
+                alt_names: vec!["DNS:forward-proxy".to_string()],
+                // From `openssl x509 -noout -fingerprint -sha256`
+                fingerprint: "399988f838a36838627976c09b8f7db53a98a989f55f0e02f7755aa996eb836c"
+                    .to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn proxy_v2_headers() {
+        let source = "10.0.0.1:51000".parse().unwrap();
+        let destination = "10.0.0.2:9443".parse().unwrap();
+        let header = proxy_v2_header(source, destination, None, &TlvTypes::default()).unwrap();
+        assert_eq!(
+            header,
+            [
+                &b"\r\n\r\n\0\r\nQUIT\n"[..],
+                &[0x21, 0x11, 0, 12],
+                &[10, 0, 0, 1, 10, 0, 0, 2],
+                &[0xc7, 0x38, 0x24, 0xe3]
+            ]
+            .concat()
+        );
+
+        let tls = ClientTls {
+            version: "TLSv1.3".to_string(),
+            identity: Some(identity()),
+        };
+        let mut types = TlvTypes::default();
+        types.set("fingerprint", 0xea).unwrap();
+        let header = proxy_v2_header(source, destination, Some(&tls), &types).unwrap();
+        let tlvs = [
+            &[0x20, 0, 15, 0x03, 0, 0, 0, 0, 0x21, 0, 7][..],
+            b"TLSv1.3",
+            &[0xe0, 0, 16],
+            b"CN=forward-proxy",
+            &[0xe1, 0, 30],
+            b"DNS:forward-proxy, IP:10.0.0.1",
+            &[0xea, 0, 4],
+            b"ab01",
+        ]
+        .concat();
+        assert_eq!(&header[14..16], &(12 + tlvs.len() as u16).to_be_bytes());
+        assert_eq!(&header[28..], &tlvs[..]);
+
+        // Without a client certificate only the TLS version is sent, and verify isn't 0
+        let tls = ClientTls {
+            version: "TLSv1.2".to_string(),
+            identity: None,
+        };
+        let header = proxy_v2_header(source, destination, Some(&tls), &types).unwrap();
+        assert_eq!(&header[28..36], &[0x20, 0, 15, 0x01, 0, 0, 0, 1]);
+        assert_eq!(header.len(), 28 + 18);
+
+        // IPv4 clients of an IPv6 socket are mapped
+        let source = "[::ffff:10.0.0.1]:51000".parse().unwrap();
+        let destination = "[::1]:9443".parse().unwrap();
+        let header = proxy_v2_header(source, destination, None, &types).unwrap();
+        assert_eq!(&header[13..16], &[0x21, 0, 36]);
+        assert_eq!(
+            header[16..32],
+            "::ffff:10.0.0.1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+    }
+
+    #[test]
+    fn field_names() {
+        let mut names = HeaderNames::default();
+        names.set("subject", "X-SSL-Subject").unwrap();
+        assert_eq!(names.subject, "X-SSL-Subject");
+        assert!(names.set("subject", "X SSL").is_err());
+        assert!(names.set("issuer", "X-SSL-Issuer").is_err());
+        // CGI style backends would see the same header twice
+        names.set("fingerprint", "x_ssl_subject").unwrap();
+        assert!(IdentityForwarding::HttpHeaders(names).validate().is_err());
+
+        let mut types = TlvTypes::default();
+        assert!(types.set("subject", 0x20).is_err());
+        types.set("sans", 0xe0).unwrap();
+        assert!(IdentityForwarding::ProxyProtocol(types).validate().is_err());
+
+        assert_eq!(
+            IdentityForwarding::from_mode("http").unwrap(),
+            IdentityForwarding::HttpHeaders(HeaderNames::default())
+        );
+        assert!(IdentityForwarding::from_mode("smtp").is_err());
+    }
+
+    #[test]
+    fn inject_headers() {
+        let names = HeaderNames::default();
+        let mut injector = HeaderInjector::new(&names, Some(&identity()));
+
+        // Split across reads, with a body and a second request on the same connection
+        assert_eq!(inject(&mut injector, "POST /a HTTP/1.1\r\nHost: a\r"), "");
+        assert_eq!(
+            inject(
+                &mut injector,
+                "\nContent-Length: 23\r\n\r\nGET / HTTP/1.1\r\n\r\nabc\r\n"
+            ),
+            format!(
+                "POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 23\r\n{}\r\n\
+                 GET / HTTP/1.1\r\n\r\nabc\r\n",
+                ADDED
+            )
+        );
+        assert_eq!(
+            inject(&mut injector, "\r\nGET /b HTTP/1.1\r\n\r\n"),
+            format!("\r\nGET /b HTTP/1.1\r\n{}\r\n", ADDED)
+        );
+    }
+
+    #[test]
+    fn remove_forged_headers() {
+        let mut injector = HeaderInjector::new(&HeaderNames::default(), Some(&identity()));
+        assert_eq!(
+            inject(
+                &mut injector,
+                "GET / HTTP/1.1\r\nx-client-cert-subject: CN=admin\r\nHost: a\r\n\
+                 X_Client_Cert_Fingerprint: 00\r\nAccept: */*\r\n\
+                 X-Client-Cert-SAN: DNS:admin\r\n\r\n"
+            ),
+            format!("GET / HTTP/1.1\r\nHost: a\r\nAccept: */*\r\n{}\r\n", ADDED)
+        );
+
+        // Without an identity, forged headers are still removed
+        let mut injector = HeaderInjector::new(&HeaderNames::default(), None);
+        assert_eq!(
+            inject(
+                &mut injector,
+                "GET / HTTP/1.1\r\nX-Client-Cert-Subject: CN=admin\r\n\r\n"
+            ),
+            "GET / HTTP/1.1\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn follow_chunked_bodies() {
+        let mut injector = HeaderInjector::new(&HeaderNames::default(), Some(&identity()));
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n";
+        let body = "5;ext=1\r\nGET /\r\n14\r\n HTTP/1.1\r\n\r\nabcdefg\r\n0\r\n";
+        assert_eq!(
+            inject(&mut injector, &format!("{}\r\n{}", head, body)),
+            format!("{}{}\r\n{}", head, ADDED, body)
+        );
+        // Trailers end the request, and can't carry forged headers either
+        assert_eq!(
+            inject(
+                &mut injector,
+                "Expires: 0\r\nX-Client-Cert-Subject: CN=admin\r\n\r\nGET / HTTP/1.1\r\n\r\n"
+            ),
+            format!("Expires: 0\r\n\r\nGET / HTTP/1.1\r\n{}\r\n", ADDED)
+        );
+    }
+
+    #[test]
+    fn refuse_upgrades() {
+        // If the backend refuses the upgrade, the next request would reach it untouched
+        let forged = "GET / HTTP/1.1\r\nX-Client-Cert-Subject: CN=admin\r\n\r\n";
+        for head in [
+            "GET /ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n",
+            "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n",
+            "CONNECT backend:443 HTTP/1.1\r\nHost: backend:443\r\n",
+        ] {
+            for identity in [Some(identity()), None] {
+                let mut injector = HeaderInjector::new(&HeaderNames::default(), identity.as_ref());
+                let pipelined = format!("{}\r\n{}", head, forged);
+                assert!(injector.push(pipelined.as_bytes()).is_err(), "{}", head);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_invalid_requests() {
+        let names = HeaderNames::default();
+        for request in [
+            "SSH-2.0-OpenSSH_9.0\r\n\r\n",
+            "GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nContent-Length: \r\n\r\n",
+            "GET / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n0\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost\r\n\r\n",
+            "GET / HTTP/1.1\r\n: a\r\n\r\n",
+            // Headers that lenient backends would read differently
+            "GET / HTTP/1.1\r\nHost: a\r\n X-Client-Cert-Subject: CN=admin\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Client-Cert-Subject : CN=admin\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\nX-Client-Cert-Subject: CN=admin\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\rX-Client-Cert-Subject: CN=admin\r\n\r\n",
+            "GET / HTTP/1.1\n\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\nX-Client-Cert-SAN: a\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nA: 1\r\n B: 2\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+5\r\nhello\r\n0\r\n\r\n",
+        ] {
+            let mut injector = HeaderInjector::new(&names, None);
+            assert!(injector.push(request.as_bytes()).is_err(), "{}", request);
+        }
+
+        let mut injector = HeaderInjector::new(&names, None);
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n", "a".repeat(70_000));
+        assert!(injector.push(long_header.as_bytes()).is_err());
+    }
+}
@@ -1,16 +1,94 @@
+mod checksum;
 mod clients;
+mod codecs;
 mod header;
+mod mode;
+mod reassembly;
 mod scheme;
+mod stream;
 
+pub type ChecksumKind = checksum::ChecksumKind;
+pub const CHECKSUM_NAMES: &[&str] = checksum::CHECKSUM_NAMES;
+pub type ChecksumMismatch = checksum::ChecksumMismatch;
 pub type Compressor<W> = clients::Compressor<W>;
 pub type Decompressor<W> = clients::Decompressor<W>;
+pub type FrameReassembler = reassembly::FrameReassembler;
+pub const MAX_FRAME_SIZE: usize = reassembly::MAX_FRAME_SIZE;
+pub type Mode = mode::Mode;
+pub const MODE_NAMES: &[&str] = mode::MODE_NAMES;
+pub type Scheme = scheme::Scheme;
+pub const SCHEME_NAMES: &[&str] = scheme::SCHEME_NAMES;
+pub type StreamCompressor = stream::StreamCompressor;
+pub type StreamDecompressor = stream::StreamDecompressor;
 
-/// Given a buffer of bytes, returns a Vec of slices of each compressed frame in the buffer.
-pub fn split_frames(data: &[u8]) -> Vec<&[u8]> {
+/// Given a buffer of bytes, returns a Vec of slices of each complete compressed frame in the buffer,
+/// along with any trailing bytes that belong to an incomplete frame. The proxies use
+/// FrameReassembler instead, this is for tests that look at the frames they send.
+#[cfg(test)]
+pub fn split_frames(data: &[u8]) -> std::io::Result<(Vec<&[u8]>, &[u8])> {
     clients::split_frames(data)
 }
 
+/// Default brotli quality level. Brotli's own default of 11 is too slow to keep up with a proxied
+/// connection, while 5 still compresses text noticeably better than deflate.
+pub const DEFAULT_BROTLI_QUALITY: u32 = 5;
+/// Highest quality level supported by brotli
+pub const MAX_BROTLI_QUALITY: u32 = 11;
+
+/// Settings used when compressing data
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct CompressionSettings {
+    pub scheme: Scheme,
+    /// Compression level, None for the scheme's default. Higher levels compress better but are
+    /// slower. The valid range depends on the scheme, see Scheme::level_range(). Schemes without
+    /// levels ignore it.
+    pub level: Option<u32>,
+    /// Whether each read is compressed independently or as part of one stream per connection.
+    /// Schemes that don't support streaming always use independent frames.
+    pub mode: Mode,
+    /// Payloads smaller than this many bytes are sent in stored frames without being compressed,
+    /// since compressing them rarely saves enough to be worth the CPU time
+    pub min_payload_size: usize,
+    /// Checksum added to each frame so the receiver can detect corrupted payloads, None to leave
+    /// it out
+    pub checksum: Option<ChecksumKind>,
+}
+
+impl CompressionSettings {
+    /// Creates settings for the scheme using default values for everything else
+    pub fn new(scheme: Scheme) -> CompressionSettings {
+        CompressionSettings {
+            scheme,
+            level: None,
+            mode: Mode::Independent,
+            min_payload_size: 0,
+            checksum: None,
+        }
+    }
+
+    /// Returns an InvalidInput error if the level is outside the scheme's range
+    pub fn validate(&self) -> std::io::Result<()> {
+        if let (Some(level), Some(range)) = (self.level, self.scheme.level_range()) {
+            if !range.contains(&level) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} compression level must be between {} and {}, got {}",
+                        self.scheme,
+                        range.start(),
+                        range.end(),
+                        level
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 pub enum Direction {
-    Compress,
+    /// Compress using the given settings
+    Compress(CompressionSettings),
+    /// Decompress using whichever scheme each frame's header specifies
     Decompress,
 }
@@ -0,0 +1,252 @@
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::io::prelude::*;
+
+#[repr(u8)]
+#[derive(FromPrimitive, ToPrimitive, PartialEq, Debug, Copy, Clone)]
+/// Algorithms used to check the integrity of a frame's uncompressed payload
+pub enum ChecksumKind {
+    Crc32 = 1,
+    /// 32 bit variant of xxHash, with a seed of 0
+    Xxh32 = 2,
+}
+
+/// Names used to select a checksum algorithm, e.g. on the command line
+pub const CHECKSUM_NAMES: &[&str] = &["crc32", "xxhash"];
+
+impl ChecksumKind {
+    pub fn to_bytes(self) -> Option<Vec<u8>> {
+        Some(self.to_u8()?.to_be_bytes().to_vec())
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Option<ChecksumKind> {
+        match buffer.len() {
+            0 => None,
+            _ => ChecksumKind::from_u8(buffer[0]),
+        }
+    }
+
+    /// Size in bytes when serialized
+    pub fn serialized_size() -> usize {
+        1
+    }
+}
+
+impl std::str::FromStr for ChecksumKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<ChecksumKind, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "crc32" => Ok(ChecksumKind::Crc32),
+            "xxhash" | "xxh32" => Ok(ChecksumKind::Xxh32),
+            _ => Err(format!("unknown checksum algorithm \"{}\"", name)),
+        }
+    }
+}
+
+/// Checksum of a frame's uncompressed payload, as recorded in its header
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Checksum {
+    pub kind: ChecksumKind,
+    pub value: u32,
+}
+
+impl Checksum {
+    pub fn compute(kind: ChecksumKind, data: &[u8]) -> Checksum {
+        let mut hasher = Hasher::new(kind);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Returns a ChecksumMismatch error if the data doesn't match this checksum
+    pub fn verify(self, data: &[u8]) -> std::io::Result<()> {
+        Checksum::compute(self.kind, data).verify_matches(self)
+    }
+
+    /// Returns a ChecksumMismatch error if this checksum, computed from the received data, doesn't
+    /// match the expected one
+    pub fn verify_matches(self, expected: Checksum) -> std::io::Result<()> {
+        if self == expected {
+            return Ok(());
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            ChecksumMismatch {
+                kind: expected.kind,
+                expected: expected.value,
+                actual: self.value,
+            },
+        ))
+    }
+}
+
+/// Incrementally computes a checksum
+pub enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Xxh32(xxhash_rust::xxh32::Xxh32),
+}
+
+impl Hasher {
+    pub fn new(kind: ChecksumKind) -> Hasher {
+        match kind {
+            ChecksumKind::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            ChecksumKind::Xxh32 => Hasher::Xxh32(xxhash_rust::xxh32::Xxh32::new(0)),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(data),
+            Hasher::Xxh32(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finish(&self) -> Checksum {
+        match self {
+            Hasher::Crc32(hasher) => Checksum {
+                kind: ChecksumKind::Crc32,
+                value: hasher.clone().finalize(),
+            },
+            Hasher::Xxh32(hasher) => Checksum {
+                kind: ChecksumKind::Xxh32,
+                value: hasher.digest(),
+            },
+        }
+    }
+}
+
+/// Error returned when the uncompressed payload of a frame doesn't match the checksum in its
+/// header. It is wrapped in an std::io::Error of kind InvalidData, use
+/// ChecksumMismatch::from_io_error() to get it back.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct ChecksumMismatch {
+    pub kind: ChecksumKind,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl ChecksumMismatch {
+    /// Returns the checksum mismatch that caused the error, if any
+    pub fn from_io_error(error: &std::io::Error) -> Option<&ChecksumMismatch> {
+        error.get_ref()?.downcast_ref::<ChecksumMismatch>()
+    }
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} checksum mismatch, expected {:#010x} but got {:#010x}",
+            self.kind, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Writer that checksums everything written through it
+pub struct ChecksumWriter<W: Write> {
+    writer: W,
+    hasher: Option<Hasher>,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    /// Creates a writer that checksums data with the given algorithm, or passes it straight
+    /// through if there is none
+    pub fn new(writer: W, kind: Option<ChecksumKind>) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            writer,
+            hasher: kind.map(Hasher::new),
+        }
+    }
+
+    /// Checksum of everything written so far, None if no algorithm was given
+    pub fn checksum(&self) -> Option<Checksum> {
+        self.hasher.as_ref().map(Hasher::finish)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::checksum::{
+        Checksum, ChecksumKind, ChecksumMismatch, ChecksumWriter, CHECKSUM_NAMES,
+    };
+    use std::io::Write;
+
+    #[test]
+    fn known_checksums() {
+        // Reference values for the ASCII string "123456789"
+        assert_eq!(
+            Checksum::compute(ChecksumKind::Crc32, b"123456789").value,
+            0xcbf4_3926
+        );
+        assert_eq!(
+            Checksum::compute(ChecksumKind::Xxh32, b"123456789").value,
+            0x937b_ad67
+        );
+    }
+
+    #[test]
+    fn verify_reports_mismatch() {
+        let checksum = Checksum::compute(ChecksumKind::Crc32, b"Hello world!");
+        assert!(checksum.verify(b"Hello world!").is_ok());
+
+        let error = checksum.verify(b"Hello world?").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let mismatch = ChecksumMismatch::from_io_error(&error).unwrap();
+        assert_eq!(mismatch.kind, ChecksumKind::Crc32);
+        assert_eq!(mismatch.expected, checksum.value);
+
+        assert!(ChecksumMismatch::from_io_error(&std::io::Error::other("other")).is_none());
+    }
+
+    #[test]
+    fn writer_checksums_chunks() {
+        let mut writer = ChecksumWriter::new(Vec::new(), Some(ChecksumKind::Xxh32));
+        writer.write_all(b"Hello ").unwrap();
+        writer.write_all(b"world!").unwrap();
+
+        assert_eq!(
+            writer.checksum(),
+            Some(Checksum::compute(ChecksumKind::Xxh32, b"Hello world!"))
+        );
+        assert_eq!(writer.into_inner(), b"Hello world!");
+
+        let writer = ChecksumWriter::new(Vec::<u8>::new(), None);
+        assert_eq!(writer.checksum(), None);
+    }
+
+    #[test]
+    fn checksum_names_parse() {
+        for name in CHECKSUM_NAMES {
+            assert!(name.parse::<ChecksumKind>().is_ok());
+        }
+        assert!("md5".parse::<ChecksumKind>().is_err());
+    }
+}
@@ -1,188 +1,299 @@
-use crate::compression::header::{Header, HEADER_MAGIC_VALUE};
+use crate::compression::checksum::{Checksum, ChecksumKind, ChecksumWriter};
+use crate::compression::codecs::{Decoder, Encoder, OutputLimit};
+use crate::compression::header::Header;
+use crate::compression::mode::Mode;
 use crate::compression::scheme::Scheme;
-use flate2::write::{DeflateDecoder, DeflateEncoder};
-use flate2::Compression;
+use crate::compression::CompressionSettings;
+use std::convert::TryFrom;
 use std::io::prelude::*;
 
-pub struct Compressor<C: Write> {
-    encoder: C,
-    wrote_header: bool,
+/// Compresses everything written to it into a single compression frame.
+///
+/// The frame header records the length of the compressed payload, so nothing is written to the
+/// underlying writer until finish() is called. If compressing doesn't make the payload smaller,
+/// e.g. for data that is already compressed, or if the input is smaller than the configured minimum
+/// payload size, the input is written as a stored frame instead.
+pub struct Compressor<W: Write> {
+    writer: W,
+    scheme: Scheme,
+    min_payload_size: usize,
+    checksum: Option<ChecksumKind>,
+    encoder: Encoder<Vec<u8>>,
+    /// Uncompressed input, kept so a stored frame can be written instead of the compressed one
+    input: Vec<u8>,
 }
 
-// TODO: can assume deflate for now, add other compression schemes
-impl<W: Write> Compressor<DeflateEncoder<W>> {
-    pub fn new(writer: W) -> Compressor<DeflateEncoder<W>> {
-        Compressor {
-            encoder: DeflateEncoder::new(writer, Compression::default()),
-            wrote_header: false,
-        }
-    }
-
-    pub fn finish(self) -> std::io::Result<W> {
-        self.encoder.finish()
+impl<W: Write> Compressor<W> {
+    /// Creates a compressor using the deflate compression scheme
+    pub fn new(writer: W) -> Compressor<W> {
+        Compressor::with_scheme(writer, Scheme::Deflate)
+            .expect("Creating a deflate encoder should not fail")
     }
 
-    pub fn get_ref(&self) -> &W {
-        self.encoder.get_ref()
+    /// Creates a compressor for the scheme using its default settings
+    pub fn with_scheme(writer: W, scheme: Scheme) -> std::io::Result<Compressor<W>> {
+        Compressor::with_settings(writer, CompressionSettings::new(scheme))
     }
 
-    pub fn get_mut(&mut self) -> &mut W {
-        self.encoder.get_mut()
+    pub fn with_settings(
+        writer: W,
+        settings: CompressionSettings,
+    ) -> std::io::Result<Compressor<W>> {
+        Ok(Compressor {
+            writer,
+            scheme: settings.scheme,
+            min_payload_size: settings.min_payload_size,
+            checksum: settings.checksum,
+            encoder: Encoder::with_settings(settings, Vec::new())?,
+            input: Vec::new(),
+        })
     }
 
-    fn write_header(&mut self) -> std::io::Result<usize> {
-        if self.wrote_header {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Header already written",
-            ));
-        }
-
-        let header = Header::new(Scheme::Deflate);
-        let header_bytes = match header.to_bytes() {
+    /// Writes the completed frame, header first, to the underlying writer and returns the writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let checksum = self
+            .checksum
+            .map(|kind| Checksum::compute(kind, &self.input));
+        let compressed = self.encoder.finish()?;
+        let (scheme, payload) =
+            if self.input.len() >= self.min_payload_size && compressed.len() < self.input.len() {
+                (self.scheme, compressed)
+            } else {
+                (Scheme::Stored, self.input)
+            };
+        let length = u32::try_from(payload.len())
+            .map_err(|_| std::io::Error::other("Compressed payload is too large for one frame"))?;
+
+        let header_bytes = match Header::new(scheme, length)
+            .with_checksum(checksum)
+            .to_bytes()
+        {
             Some(data) => data,
-            None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Could not convert header to bytes",
-                ))
-            }
+            None => return Err(std::io::Error::other("Could not convert header to bytes")),
         };
         // We need to write the header to the underlying writer so the header is not compressed
-        let result = self.encoder.get_mut().write(&header_bytes);
+        self.writer.write_all(&header_bytes)?;
+        self.writer.write_all(&payload)?;
 
-        if result.is_ok() {
-            self.wrote_header = true;
-        }
+        Ok(self.writer)
+    }
 
-        result
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
     }
 }
 
-impl<W: Write> Write for Compressor<DeflateEncoder<W>> {
+impl<W: Write> Write for Compressor<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         // We only return the number of bytes written from the input buffer. This is how flate2's
         // write() works: https://github.com/rust-lang/flate2-rs/blob/7546110602fcc934ae506ed8d5cd9516e945d1ee/src/zio.rs#L218
-        if !self.wrote_header {
-            self.write_header()?;
-        }
-
-        self.encoder.write(buf)
+        let written = self.encoder.write(buf)?;
+        self.input.extend_from_slice(&buf[..written]);
+        Ok(written)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
+        // The frame can't be written out until its length is known, so this only flushes the
+        // encoder into the pending payload
         self.encoder.flush()
     }
 }
 
-pub struct Decompressor<D: Write> {
-    decoder: D,
-    // TODO: should probably move the header check to the constructor, so that we can construct the
-    //  correct kind of decoder
-    parsed_header: bool,
+/// Decompresses a single independent compression frame.
+///
+/// The header must be present in the first write, and determines which decoder is used. For v2
+/// headers, writing data past the end of the recorded payload length is an error. If the
+/// header has a checksum, finish() returns a ChecksumMismatch error when the decompressed payload
+/// doesn't match it. A frame that decompresses to more than MAX_FRAME_SIZE bytes is an error.
+pub struct Decompressor<W: Write> {
+    /// Holds the writer until the header has been parsed and the decoder can be constructed
+    writer: Option<W>,
+    decoder: Option<Decoder<ChecksumWriter<OutputLimit<W>>>>,
+    /// Payload bytes still expected for the frame, None if the header doesn't record a length
+    remaining: Option<usize>,
+    /// Checksum recorded in the header
+    checksum: Option<Checksum>,
 }
 
-// TODO: can assume deflate for now, add other compression schemes
-impl<W: Write> Decompressor<DeflateDecoder<W>> {
-    pub fn new(writer: W) -> Decompressor<DeflateDecoder<W>> {
+impl<W: Write> Decompressor<W> {
+    pub fn new(writer: W) -> Decompressor<W> {
         Decompressor {
-            decoder: DeflateDecoder::new(writer),
-            parsed_header: false,
+            writer: Some(writer),
+            decoder: None,
+            remaining: None,
+            checksum: None,
         }
     }
 
     pub fn finish(self) -> std::io::Result<W> {
-        self.decoder.finish()
+        if let Some(remaining) = self.remaining {
+            if remaining > 0 {
+                return Err(std::io::Error::other(format!(
+                    "Compression frame is truncated, missing {} bytes",
+                    remaining
+                )));
+            }
+        }
+
+        match (self.decoder, self.writer) {
+            (Some(decoder), _) => {
+                let writer = decoder.finish()?;
+                if let (Some(expected), Some(actual)) = (self.checksum, writer.checksum()) {
+                    actual.verify_matches(expected)?;
+                }
+                Ok(writer.into_inner().into_inner())
+            }
+            (None, Some(_)) => Err(std::io::Error::other(
+                "Compression frame finished before its header was received",
+            )),
+            (None, None) => unreachable!("Decompressor always holds either a writer or a decoder"),
+        }
     }
 
     pub fn get_ref(&self) -> &W {
-        self.decoder.get_ref()
+        match (&self.decoder, &self.writer) {
+            (Some(decoder), _) => decoder.get_ref().get_ref().get_ref(),
+            (None, Some(writer)) => writer,
+            (None, None) => unreachable!("Decompressor always holds either a writer or a decoder"),
+        }
     }
 
     pub fn get_mut(&mut self) -> &mut W {
-        self.decoder.get_mut()
+        match (&mut self.decoder, &mut self.writer) {
+            (Some(decoder), _) => decoder.get_mut().get_mut().get_mut(),
+            (None, Some(writer)) => writer,
+            (None, None) => unreachable!("Decompressor always holds either a writer or a decoder"),
+        }
     }
 
     fn parse_header(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if self.parsed_header {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Header already parsed",
-            ));
-        }
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => return Err(std::io::Error::other("Header already parsed")),
+        };
 
         let header = match Header::from_bytes(buf) {
-            Some(header) => Ok(header),
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "A compression header must be present in the first few bytes of the buffer",
-            )),
-        }?;
+            Some(header) => header,
+            None => {
+                self.writer = Some(writer);
+                return Err(std::io::Error::other(
+                    "A compression header must be present in the first few bytes of the buffer",
+                ));
+            }
+        };
 
-        if header.scheme != Scheme::Deflate {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Only the deflate compression scheme is currently supported",
+        if header.mode != Mode::Independent {
+            self.writer = Some(writer);
+            return Err(std::io::Error::other(
+                "Streaming frames can't be decompressed on their own",
             ));
-        };
+        }
 
-        self.parsed_header = true;
-        Ok(Header::serialized_size())
+        let writer = ChecksumWriter::new(
+            OutputLimit::new(writer),
+            header.checksum.map(|checksum| checksum.kind),
+        );
+        match Decoder::new(header.scheme, writer) {
+            Ok(decoder) => self.decoder = Some(decoder),
+            Err((e, writer)) => {
+                self.writer = Some(writer.into_inner().into_inner());
+                return Err(e);
+            }
+        }
+        self.remaining = header.length.map(|length| length as usize);
+        self.checksum = header.checksum;
+        Ok(header.serialized_size())
     }
 }
 
-impl<W: Write> Write for Decompressor<DeflateDecoder<W>> {
+impl<W: Write> Write for Decompressor<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let mut written = 0;
         // We return the number of bytes parsed from the input buffer, rather than the number of
         // bytes written to the stream. This is how flate2's write() works:
         // https://github.com/rust-lang/flate2-rs/blob/7546110602fcc934ae506ed8d5cd9516e945d1ee/src/zio.rs#L218
-        if !self.parsed_header {
+        if self.decoder.is_none() {
             written += self.parse_header(buf)?;
         }
 
-        written += self.decoder.write(&buf[written..])?;
+        let mut payload = &buf[written..];
+        if let Some(remaining) = self.remaining {
+            if remaining == 0 && !payload.is_empty() {
+                return Err(std::io::Error::other(
+                    "Data found past the end of the compression frame",
+                ));
+            }
+            payload = &payload[..payload.len().min(remaining)];
+        }
+
+        let decoder = self
+            .decoder
+            .as_mut()
+            .expect("Decoder is constructed once the header is parsed");
+        let decoded = decoder.write(payload)?;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= decoded;
+        }
+        written += decoded;
 
         Ok(written)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.decoder.flush()
+        match (&mut self.decoder, &mut self.writer) {
+            (Some(decoder), _) => decoder.flush(),
+            (None, Some(writer)) => writer.flush(),
+            (None, None) => unreachable!("Decompressor always holds either a writer or a decoder"),
+        }
     }
 }
 
-/// Given a buffer of bytes, returns a Vec of slices of each compressed frame in the buffer.
+/// Given a buffer of bytes, returns a Vec of slices of each complete compressed frame in the buffer,
+/// along with any trailing bytes that belong to a frame whose payload is not yet complete.
 ///
-/// This method determines a new frame by searching for the compression headers' magic value.
-pub fn split_frames(data: &[u8]) -> Vec<&[u8]> {
-    let magic_bytes: [u8; 2] = HEADER_MAGIC_VALUE.to_be_bytes();
-    assert_eq!(
-        magic_bytes.len(),
-        2,
-        "Expected compression header magic value to be 2 bytes"
-    );
-
-    let mut indices: Vec<usize> = data
-        .windows(2)
-        .enumerate()
-        .filter_map(|(i, byte_pair)| {
-            if byte_pair == magic_bytes {
-                Some(i)
-            } else {
-                None
-            }
-        })
-        .collect();
-    // We assume that the last compression frame ends at the end of the buffer
-    indices.push(data.len());
-    indices.windows(2).map(|is| &data[is[0]..is[1]]).collect()
+/// v2 frames are found by walking the payload lengths recorded in each header. v1 headers don't
+/// record a length, so a v1 frame is assumed to run until the next header magic value or the end of
+/// the buffer.
+#[cfg(test)]
+pub fn split_frames(data: &[u8]) -> std::io::Result<(Vec<&[u8]>, &[u8])> {
+    let mut frames = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let header = Header::from_bytes(rest).ok_or_else(|| {
+            std::io::Error::other("Expected a compression header at the start of each frame")
+        })?;
+        let header_size = header.serialized_size();
+
+        let frame_size = match header.length {
+            Some(length) => header_size + length as usize,
+            None => crate::compression::header::find_magic_value(&rest[header_size..])
+                .map_or(rest.len(), |index| header_size + index),
+        };
+        if frame_size > rest.len() {
+            break;
+        }
+
+        let (frame, tail) = rest.split_at(frame_size);
+        frames.push(frame);
+        rest = tail;
+    }
+
+    Ok((frames, rest))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::compression::clients::{Compressor, Decompressor};
-    use crate::compression::header::Header;
+    use crate::compression::checksum::{Checksum, ChecksumKind, ChecksumMismatch};
+    use crate::compression::clients::{split_frames, Compressor, Decompressor};
+    use crate::compression::header::{Header, HEADER_MAGIC_VALUE};
     use crate::compression::scheme::Scheme;
+    use crate::compression::{CompressionSettings, MAX_FRAME_SIZE};
+    use crate::test_util::incompressible;
     use flate2::write::{DeflateDecoder, DeflateEncoder};
     use std::io::prelude::*;
     use std::io::BufWriter;
@@ -194,8 +305,8 @@ mod tests {
         for chunk in data_chunks {
             enc.write_all(chunk).unwrap();
         }
-        let expected_header = Header::new(Scheme::Deflate);
         let expected_compressed = enc.finish().unwrap();
+        let expected_header = Header::new(Scheme::Deflate, expected_compressed.len() as u32);
         [expected_header.to_bytes().unwrap(), expected_compressed].concat()
     }
 
@@ -208,7 +319,7 @@ mod tests {
         compressor.write_all(message).unwrap();
         let result = compressor.finish().unwrap();
 
-        assert!(result.len() > 0);
+        assert!(!result.is_empty());
         assert_eq!(result, expected_result);
     }
 
@@ -226,7 +337,7 @@ mod tests {
         }
         let result = compressor.finish().unwrap();
 
-        assert!(result.len() > 0);
+        assert!(!result.is_empty());
         assert_eq!(result, expected_result);
     }
 
@@ -234,25 +345,27 @@ mod tests {
     fn can_wrap_compressor_write() {
         let compressor = Compressor::new(Vec::new());
         let mut writer = BufWriter::new(compressor);
-        let message = "Hello world! This is quite compressed....".as_bytes();
+        // Repeated so that compressing saves space and the frame isn't stored
+        let message = "Hello world! This is quite compressed....".repeat(4);
+        let message = message.as_bytes();
 
-        let expected_header = Header::new(Scheme::Deflate);
         let mut reference_dec = DeflateDecoder::new(Vec::new());
 
         writer.write_all(message).unwrap();
         writer.flush().unwrap();
-        let result = writer.get_ref().get_ref();
+        let result = writer.into_inner().ok().unwrap().finish().unwrap();
 
-        let header_only = &result[..Header::serialized_size()];
-        let buf_only = &result[Header::serialized_size()..];
+        let header = Header::from_bytes(&result).unwrap();
+        let buf_only = &result[header.serialized_size()..];
 
-        assert_eq!(header_only, expected_header.to_bytes().unwrap());
+        assert_eq!(header.scheme, Scheme::Deflate);
+        assert_eq!(header.length, Some(buf_only.len() as u32));
 
         reference_dec.write_all(buf_only).unwrap();
         reference_dec.flush().unwrap();
         let decompressed_result = reference_dec.get_ref();
 
-        assert!(result.len() > 0);
+        assert!(!result.is_empty());
         assert_eq!(decompressed_result.as_slice(), message);
     }
 
@@ -265,7 +378,7 @@ mod tests {
         decompressor.write_all(&compressed_payload).unwrap();
         let result = decompressor.finish().unwrap();
 
-        assert!(result.len() > 0);
+        assert!(!result.is_empty());
         assert_eq!(result, expected_message);
     }
 
@@ -277,11 +390,11 @@ mod tests {
 
         let mut decompressor = Decompressor::new(Vec::new());
         for payload in [payload_chunks.0, payload_chunks.1].iter() {
-            decompressor.write_all(&payload).unwrap();
+            decompressor.write_all(payload).unwrap();
         }
         let result = decompressor.finish().unwrap();
 
-        assert!(result.len() > 0);
+        assert!(!result.is_empty());
         assert_eq!(result, expected_message);
     }
 
@@ -313,7 +426,272 @@ mod tests {
         writer.flush().unwrap();
         let result = writer.get_ref().get_ref();
 
-        assert!(result.len() > 0);
+        assert!(!result.is_empty());
         assert_eq!(result.as_slice(), message);
     }
+
+    #[test]
+    fn decompress_v1_frame() {
+        let expected_message = "Hello world! This is quite compressed....".as_bytes();
+        let mut enc = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(expected_message).unwrap();
+        let v1_frame = [
+            Header::new_v1(Scheme::Deflate).to_bytes().unwrap(),
+            enc.finish().unwrap(),
+        ]
+        .concat();
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        decompressor.write_all(&v1_frame).unwrap();
+        let result = decompressor.finish().unwrap();
+
+        assert_eq!(result, expected_message);
+    }
+
+    #[test]
+    fn decompress_rejects_data_past_frame() {
+        let message = "Hello world! This is quite compressed....".as_bytes();
+        let frame = get_deflate_compressed(&[message]);
+        let payload = [frame.as_slice(), b"trailing"].concat();
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        let decompress_result = decompressor.write_all(&payload);
+        assert_eq!(
+            decompress_result.unwrap_err().kind(),
+            std::io::ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn decompress_rejects_truncated_frame() {
+        let message = "Hello world! This is quite compressed....".as_bytes();
+        let frame = get_deflate_compressed(&[message]);
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        decompressor.write_all(&frame[..frame.len() - 1]).unwrap();
+        assert!(decompressor.finish().is_err());
+    }
+
+    #[test]
+    fn split_frames_with_magic_value_in_payload() {
+        let magic_bytes = HEADER_MAGIC_VALUE.to_be_bytes();
+        let payloads: [&[u8]; 3] = [
+            &[magic_bytes[0], magic_bytes[1], 0x01, 0x02],
+            &[0x03, magic_bytes[0], magic_bytes[1]],
+            &[magic_bytes[0], magic_bytes[1]],
+        ];
+        let frames: Vec<Vec<u8>> = payloads
+            .iter()
+            .map(|payload| {
+                let header = Header::new(Scheme::Deflate, payload.len() as u32);
+                [header.to_bytes().unwrap().as_slice(), payload].concat()
+            })
+            .collect();
+        let data = frames.concat();
+
+        let (result, remainder) = split_frames(&data).unwrap();
+
+        assert_eq!(result, frames);
+        assert!(remainder.is_empty());
+    }
+
+    #[test]
+    fn split_frames_v1() {
+        let v1_header = Header::new_v1(Scheme::Deflate).to_bytes().unwrap();
+        let frames = vec![
+            [v1_header.as_slice(), &[0x01, 0x02]].concat(),
+            [v1_header.as_slice(), &[0x03]].concat(),
+        ];
+        let data = frames.concat();
+
+        let (result, remainder) = split_frames(&data).unwrap();
+
+        assert_eq!(result, frames);
+        assert!(remainder.is_empty());
+    }
+
+    #[test]
+    fn split_frames_returns_incomplete_frame() {
+        let message = "Hello world! This is quite compressed....".as_bytes();
+        let frame = get_deflate_compressed(&[message]);
+        let data = [frame.as_slice(), &frame[..frame.len() - 1]].concat();
+
+        let (result, remainder) = split_frames(&data).unwrap();
+
+        assert_eq!(result, vec![frame.as_slice()]);
+        assert_eq!(remainder, &frame[..frame.len() - 1]);
+    }
+
+    #[test]
+    fn split_frames_requires_header() {
+        let message = "Hello world! This is quite compressed....".as_bytes();
+        assert!(split_frames(message).is_err());
+    }
+
+    #[test]
+    fn compress_zstd() {
+        // Repeated so that compressing saves space and the frame isn't stored
+        let message = "Hello world! This is quite compressed....".repeat(4);
+        let message = message.as_bytes();
+
+        let mut compressor = Compressor::with_scheme(Vec::new(), Scheme::Zstd).unwrap();
+        compressor.write_all(message).unwrap();
+        let result = compressor.finish().unwrap();
+
+        let header = Header::from_bytes(&result).unwrap();
+        let payload = &result[header.serialized_size()..];
+        assert_eq!(header.scheme, Scheme::Zstd);
+        assert_eq!(header.length, Some(payload.len() as u32));
+        assert_eq!(zstd::decode_all(payload).unwrap(), message);
+    }
+
+    #[test]
+    fn decompress_zstd() {
+        let expected_message = "Hello world! This is quite compressed....".as_bytes();
+        let payload = zstd::encode_all(expected_message, 0).unwrap();
+        let header = Header::new(Scheme::Zstd, payload.len() as u32);
+        let frame = [header.to_bytes().unwrap(), payload].concat();
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        decompressor.write_all(&frame).unwrap();
+        let result = decompressor.finish().unwrap();
+
+        assert_eq!(result, expected_message);
+    }
+
+    #[test]
+    fn compress_incompressible_data_as_stored() {
+        let message = incompressible(1024);
+
+        for scheme in [Scheme::Deflate, Scheme::Zstd, Scheme::Brotli, Scheme::Lz4].iter() {
+            let mut compressor = Compressor::with_scheme(Vec::new(), *scheme).unwrap();
+            compressor.write_all(&message).unwrap();
+            let result = compressor.finish().unwrap();
+
+            let header = Header::from_bytes(&result).unwrap();
+            assert_eq!(header.scheme, Scheme::Stored, "not stored for {:?}", scheme);
+            assert_eq!(&result[header.serialized_size()..], message.as_slice());
+        }
+    }
+
+    #[test]
+    fn compress_empty_input_as_stored() {
+        let result = Compressor::new(Vec::new()).finish().unwrap();
+
+        assert_eq!(result, Header::new(Scheme::Stored, 0).to_bytes().unwrap());
+    }
+
+    #[test]
+    fn compress_below_min_payload_size_as_stored() {
+        let message = "Hello world! This is quite compressed....".repeat(4);
+        let mut settings = CompressionSettings::new(Scheme::Deflate);
+        settings.min_payload_size = message.len() + 1;
+
+        let mut compressor = Compressor::with_settings(Vec::new(), settings).unwrap();
+        compressor.write_all(message.as_bytes()).unwrap();
+        let result = compressor.finish().unwrap();
+        assert_eq!(Header::from_bytes(&result).unwrap().scheme, Scheme::Stored);
+
+        settings.min_payload_size = message.len();
+        let mut compressor = Compressor::with_settings(Vec::new(), settings).unwrap();
+        compressor.write_all(message.as_bytes()).unwrap();
+        let result = compressor.finish().unwrap();
+        assert_eq!(Header::from_bytes(&result).unwrap().scheme, Scheme::Deflate);
+    }
+
+    #[test]
+    fn decompress_checksummed_frames() {
+        let message = "Hello world! This is quite compressed....".repeat(4);
+
+        for kind in [ChecksumKind::Crc32, ChecksumKind::Xxh32].iter() {
+            let mut settings = CompressionSettings::new(Scheme::Zstd);
+            settings.checksum = Some(*kind);
+            let mut compressor = Compressor::with_settings(Vec::new(), settings).unwrap();
+            compressor.write_all(message.as_bytes()).unwrap();
+            let frame = compressor.finish().unwrap();
+            let header = Header::from_bytes(&frame).unwrap();
+            assert_eq!(
+                header.checksum,
+                Some(Checksum::compute(*kind, message.as_bytes()))
+            );
+
+            let mut decompressor = Decompressor::new(Vec::new());
+            decompressor.write_all(&frame).unwrap();
+            assert_eq!(decompressor.finish().unwrap(), message.as_bytes());
+        }
+    }
+
+    #[test]
+    fn decompress_reports_checksum_mismatch() {
+        let message = incompressible(100);
+        let mut settings = CompressionSettings::new(Scheme::Deflate);
+        settings.checksum = Some(ChecksumKind::Xxh32);
+        let mut compressor = Compressor::with_settings(Vec::new(), settings).unwrap();
+        compressor.write_all(&message).unwrap();
+        let mut frame = compressor.finish().unwrap();
+
+        // The data doesn't compress so it is stored, and flipping a bit in the payload corrupts the
+        // output without breaking the decoder
+        let last = frame.len() - 1;
+        frame[last] ^= 0x01;
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        decompressor.write_all(&frame).unwrap();
+        let error = decompressor.finish().unwrap_err();
+        let mismatch = ChecksumMismatch::from_io_error(&error).unwrap();
+        assert_eq!(mismatch.kind, ChecksumKind::Xxh32);
+        assert_eq!(
+            mismatch.expected,
+            Checksum::compute(ChecksumKind::Xxh32, &message).value
+        );
+    }
+
+    #[test]
+    fn decompress_stored_frame() {
+        let message = incompressible(100);
+        let header = Header::new(Scheme::Stored, message.len() as u32);
+        let frame = [header.to_bytes().unwrap(), message.clone()].concat();
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        decompressor.write_all(&frame).unwrap();
+        let result = decompressor.finish().unwrap();
+
+        assert_eq!(result, message);
+    }
+
+    #[test]
+    fn decompress_rejects_oversized_output() {
+        for scheme in [Scheme::Deflate, Scheme::Zstd, Scheme::Brotli, Scheme::Lz4].iter() {
+            for (len, fits) in [(MAX_FRAME_SIZE, true), (MAX_FRAME_SIZE + 1, false)].iter() {
+                let mut compressor = Compressor::with_scheme(Vec::new(), *scheme).unwrap();
+                compressor.write_all(&vec![0; *len]).unwrap();
+                let frame = compressor.finish().unwrap();
+                assert!(frame.len() < 10_000);
+
+                let mut decompressor = Decompressor::new(Vec::new());
+                let result = decompressor
+                    .write_all(&frame)
+                    .and_then(|_| decompressor.finish());
+                assert_eq!(result.is_ok(), *fits, "{:?} {}", scheme, len);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_brotli() {
+        // Repeated so that compressing saves space and the frame isn't stored
+        let message = "<p>Hello world! This is quite compressed....</p>".repeat(4);
+        let message = message.as_bytes();
+
+        let mut compressor = Compressor::with_scheme(Vec::new(), Scheme::Brotli).unwrap();
+        compressor.write_all(message).unwrap();
+        let frame = compressor.finish().unwrap();
+        assert_eq!(Header::from_bytes(&frame).unwrap().scheme, Scheme::Brotli);
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        decompressor.write_all(&frame).unwrap();
+        let result = decompressor.finish().unwrap();
+
+        assert_eq!(result, message);
+    }
 }
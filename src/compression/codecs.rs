@@ -0,0 +1,462 @@
+use crate::compression::scheme::Scheme;
+use crate::compression::{CompressionSettings, DEFAULT_BROTLI_QUALITY, MAX_FRAME_SIZE};
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression;
+use std::io::prelude::*;
+
+/// Size of the internal buffers used by the brotli encoder and decoder
+const BROTLI_BUFFER_SIZE: usize = 4096;
+/// Base 2 logarithm of the brotli sliding window size, 22 is the value recommended by RFC 7932
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// Encoder for one of the supported compression schemes. Compressed data is written to the
+/// wrapped writer.
+pub enum Encoder<W: Write> {
+    Stored(W),
+    Deflate(DeflateEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Brotli(Box<brotli::CompressorWriter<W>>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn with_settings(settings: CompressionSettings, writer: W) -> std::io::Result<Encoder<W>> {
+        settings.validate()?;
+
+        Ok(match settings.scheme {
+            Scheme::Stored => Encoder::Stored(writer),
+            Scheme::Deflate => Encoder::Deflate(DeflateEncoder::new(
+                writer,
+                settings
+                    .level
+                    .map_or_else(Compression::default, Compression::new),
+            )),
+            Scheme::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                writer,
+                settings
+                    .level
+                    .map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |level| level as i32),
+            )?),
+            Scheme::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                writer,
+                BROTLI_BUFFER_SIZE,
+                settings.level.unwrap_or(DEFAULT_BROTLI_QUALITY),
+                BROTLI_WINDOW_BITS,
+            ))),
+            Scheme::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(writer)),
+        })
+    }
+
+    /// Finishes the compressed stream and returns the wrapped writer
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            Encoder::Stored(writer) => Ok(writer),
+            Encoder::Deflate(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Brotli(mut encoder) => {
+                // into_inner() ignores errors when finishing the stream, so flush first to catch
+                // errors from the wrapped writer
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            Encoder::Lz4(encoder) => Ok(encoder.finish()?),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Encoder::Stored(writer) => writer,
+            Encoder::Deflate(encoder) => encoder.get_mut(),
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+            Encoder::Lz4(encoder) => encoder.get_mut(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Stored(writer) => writer.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Stored(writer) => writer.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Decoder for one of the supported compression schemes. Decompressed data is written to the
+/// wrapped writer.
+pub enum Decoder<W: Write> {
+    Stored(W),
+    Deflate(DeflateDecoder<W>),
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+    Brotli(Box<brotli::DecompressorWriter<CountingWriter<W>>>),
+    /// lz4_flex only provides a reader for decoding frames, so the compressed input is collected
+    /// and decoded when the decoder is finished
+    Lz4 {
+        writer: W,
+        input: Vec<u8>,
+    },
+}
+
+impl<W: Write> Decoder<W> {
+    /// Creates a decoder for the scheme. If that fails the writer is handed back with the error.
+    pub fn new(scheme: Scheme, writer: W) -> Result<Decoder<W>, (std::io::Error, W)> {
+        Ok(match scheme {
+            Scheme::Stored => Decoder::Stored(writer),
+            Scheme::Deflate => Decoder::Deflate(DeflateDecoder::new(writer)),
+            // The zstd context is created first, since the writer would be lost if that failed
+            Scheme::Zstd => match zstd::stream::raw::Decoder::new() {
+                Ok(context) => {
+                    Decoder::Zstd(zstd::stream::write::Decoder::with_decoder(writer, context))
+                }
+                Err(e) => return Err((e, writer)),
+            },
+            Scheme::Brotli => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(
+                CountingWriter::new(writer),
+                BROTLI_BUFFER_SIZE,
+            ))),
+            Scheme::Lz4 => Decoder::Lz4 {
+                writer,
+                input: Vec::new(),
+            },
+        })
+    }
+
+    /// Writes out any remaining decompressed data and returns the wrapped writer
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            Decoder::Stored(writer) => Ok(writer),
+            Decoder::Deflate(decoder) => decoder.finish(),
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+            Decoder::Brotli(decoder) => decoder
+                .into_inner()
+                .map(CountingWriter::into_inner)
+                .map_err(|_| std::io::Error::other("Brotli stream is incomplete or invalid")),
+            Decoder::Lz4 { mut writer, input } => {
+                let mut decoder = lz4_flex::frame::FrameDecoder::new(input.as_slice());
+                std::io::copy(&mut decoder, &mut writer)?;
+                Ok(writer)
+            }
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        match self {
+            Decoder::Stored(writer) => writer,
+            Decoder::Deflate(decoder) => decoder.get_ref(),
+            Decoder::Zstd(decoder) => decoder.get_ref(),
+            Decoder::Brotli(decoder) => &decoder.get_ref().writer,
+            Decoder::Lz4 { writer, .. } => writer,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Decoder::Stored(writer) => writer,
+            Decoder::Deflate(decoder) => decoder.get_mut(),
+            Decoder::Zstd(decoder) => decoder.get_mut(),
+            Decoder::Brotli(decoder) => &mut decoder.get_mut().writer,
+            Decoder::Lz4 { writer, .. } => writer,
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Decoder::Stored(writer) => writer.write(buf),
+            Decoder::Deflate(decoder) => decoder.write(buf),
+            Decoder::Zstd(decoder) => decoder.write(buf),
+            Decoder::Brotli(decoder) => decoder.write(buf),
+            Decoder::Lz4 { input, .. } => {
+                input.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Decoder::Stored(writer) => writer.flush(),
+            Decoder::Deflate(decoder) => decoder.flush(),
+            Decoder::Zstd(decoder) => decoder.flush(),
+            Decoder::Brotli(decoder) => {
+                // The brotli writer can stop part way through the output for the data it was
+                // given, and only continues when it's written to again. Keep writing nothing until
+                // it stops producing output, so a flushed stream is fully decompressed.
+                loop {
+                    let count = decoder.get_ref().count;
+                    decoder.write(&[])?;
+                    if decoder.get_ref().count == count {
+                        break;
+                    }
+                }
+                decoder.flush()
+            }
+            Decoder::Lz4 { writer, .. } => writer.flush(),
+        }
+    }
+}
+
+/// Writer that counts the bytes written through it
+pub struct CountingWriter<W: Write> {
+    writer: W,
+    count: usize,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(writer: W) -> CountingWriter<W> {
+        CountingWriter { writer, count: 0 }
+    }
+
+    fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writer that fails once more than MAX_FRAME_SIZE bytes have been written to it since it was
+/// created or last reset. Decoders write into one so a small frame can't decompress into an
+/// unbounded amount of data.
+pub struct OutputLimit<W: Write> {
+    writer: W,
+    written: usize,
+}
+
+impl<W: Write> OutputLimit<W> {
+    pub fn new(writer: W) -> OutputLimit<W> {
+        OutputLimit { writer, written: 0 }
+    }
+
+    /// Allows another MAX_FRAME_SIZE bytes to be written, for the next frame
+    pub fn reset(&mut self) {
+        self.written = 0;
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Write for OutputLimit<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() > MAX_FRAME_SIZE - self.written {
+            return Err(std::io::Error::other(format!(
+                "Compression frame decompresses to more than {} bytes",
+                MAX_FRAME_SIZE
+            )));
+        }
+        let written = self.writer.write(buf)?;
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::codecs::{Decoder, Encoder};
+    use crate::compression::scheme::Scheme;
+    use crate::compression::CompressionSettings;
+    use std::io::prelude::*;
+
+    #[test]
+    fn round_trip_all_schemes() {
+        let message = "Hello world! This is quite compressed....".as_bytes();
+
+        for scheme in [
+            Scheme::Stored,
+            Scheme::Deflate,
+            Scheme::Zstd,
+            Scheme::Brotli,
+            Scheme::Lz4,
+        ]
+        .iter()
+        {
+            let mut encoder =
+                Encoder::with_settings(CompressionSettings::new(*scheme), Vec::new()).unwrap();
+            encoder.write_all(message).unwrap();
+            let compressed = encoder.finish().unwrap();
+
+            let mut decoder = Decoder::new(*scheme, Vec::new()).unwrap();
+            decoder.write_all(&compressed).unwrap();
+            let result = decoder.finish().unwrap();
+
+            assert_eq!(result, message, "round trip failed for {:?}", scheme);
+        }
+    }
+
+    #[test]
+    fn zstd_encoder_produces_zstd_frame() {
+        let message = "Hello world! This is quite compressed....".as_bytes();
+
+        let mut encoder =
+            Encoder::with_settings(CompressionSettings::new(Scheme::Zstd), Vec::new()).unwrap();
+        encoder.write_all(message).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), message);
+    }
+
+    #[test]
+    fn brotli_quality_affects_output() {
+        let message =
+            "<html><body><p>Hello world! This is quite compressed....</p></body></html>".repeat(20);
+
+        let compressed: Vec<Vec<u8>> = [0, 11]
+            .iter()
+            .map(|quality| {
+                let mut settings = CompressionSettings::new(Scheme::Brotli);
+                settings.level = Some(*quality);
+                let mut encoder = Encoder::with_settings(settings, Vec::new()).unwrap();
+                encoder.write_all(message.as_bytes()).unwrap();
+                encoder.finish().unwrap()
+            })
+            .collect();
+
+        assert!(compressed[1].len() < compressed[0].len());
+        for data in compressed {
+            let mut decoder = Decoder::new(Scheme::Brotli, Vec::new()).unwrap();
+            decoder.write_all(&data).unwrap();
+            assert_eq!(decoder.finish().unwrap(), message.as_bytes());
+        }
+    }
+
+    #[test]
+    fn compression_level_affects_output() {
+        let message = "Hello world! This is quite compressed, and it repeats a lot....".repeat(50);
+
+        for scheme in [Scheme::Deflate, Scheme::Zstd].iter() {
+            let range = scheme.level_range().unwrap();
+            let compressed: Vec<Vec<u8>> = [*range.start(), *range.end()]
+                .iter()
+                .map(|level| {
+                    let mut settings = CompressionSettings::new(*scheme);
+                    settings.level = Some(*level);
+                    let mut encoder = Encoder::with_settings(settings, Vec::new()).unwrap();
+                    encoder.write_all(message.as_bytes()).unwrap();
+                    encoder.finish().unwrap()
+                })
+                .collect();
+
+            assert!(
+                compressed[1].len() < compressed[0].len(),
+                "level had no effect for {:?}",
+                scheme
+            );
+            for data in compressed {
+                let mut decoder = Decoder::new(*scheme, Vec::new()).unwrap();
+                decoder.write_all(&data).unwrap();
+                assert_eq!(decoder.finish().unwrap(), message.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn encoder_rejects_invalid_level() {
+        for scheme in [Scheme::Deflate, Scheme::Zstd, Scheme::Brotli].iter() {
+            let mut settings = CompressionSettings::new(*scheme);
+            settings.level = Some(scheme.level_range().unwrap().end() + 1);
+            assert!(Encoder::with_settings(settings, Vec::new()).is_err());
+        }
+
+        // Schemes without levels ignore the setting
+        let mut settings = CompressionSettings::new(Scheme::Lz4);
+        settings.level = Some(100);
+        assert!(Encoder::with_settings(settings, Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn brotli_decoder_rejects_truncated_stream() {
+        let message = "Hello world! This is quite compressed....".repeat(20);
+
+        let mut encoder =
+            Encoder::with_settings(CompressionSettings::new(Scheme::Brotli), Vec::new()).unwrap();
+        encoder.write_all(message.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoder = Decoder::new(Scheme::Brotli, Vec::new()).unwrap();
+        decoder
+            .write_all(&compressed[..compressed.len() / 2])
+            .unwrap();
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn flushed_decoder_writes_all_output() {
+        let message = vec![0; 100_000];
+
+        for scheme in [Scheme::Deflate, Scheme::Zstd, Scheme::Brotli].iter() {
+            let mut encoder =
+                Encoder::with_settings(CompressionSettings::new(*scheme), Vec::new()).unwrap();
+            encoder.write_all(&message).unwrap();
+            encoder.flush().unwrap();
+
+            let mut decoder = Decoder::new(*scheme, Vec::new()).unwrap();
+            decoder.write_all(encoder.get_mut()).unwrap();
+            decoder.flush().unwrap();
+            assert_eq!(decoder.get_ref().len(), message.len(), "{:?}", scheme);
+        }
+    }
+
+    #[test]
+    fn lz4_encoder_produces_lz4_frame() {
+        let message = "Hello world! This is quite compressed....".as_bytes();
+
+        let mut encoder =
+            Encoder::with_settings(CompressionSettings::new(Scheme::Lz4), Vec::new()).unwrap();
+        encoder.write_all(message).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut result = Vec::new();
+        lz4_flex::frame::FrameDecoder::new(compressed.as_slice())
+            .read_to_end(&mut result)
+            .unwrap();
+        assert_eq!(result, message);
+    }
+
+    #[test]
+    fn lz4_decoder_rejects_invalid_stream() {
+        let mut decoder = Decoder::new(Scheme::Lz4, Vec::new()).unwrap();
+        decoder.write_all(b"not an lz4 frame").unwrap();
+        assert!(decoder.finish().is_err());
+    }
+}
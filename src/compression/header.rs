@@ -1,58 +1,208 @@
+use crate::compression::checksum::{Checksum, ChecksumKind};
+use crate::compression::mode::Mode;
 use crate::compression::scheme::Scheme;
+use std::convert::TryInto;
 
 #[derive(PartialEq, Debug, Copy, Clone)]
 // TODO: compression is not supported in TLS 1.3, and rustls doesn't support compression at all.
 //  Should we drop compression support from our project, or keep it in with warnings that it can
 //  potentially leak information?
 /// Custom compression header to indicate that the following content is compressed
+///
+/// Two wire formats are understood:
+///  - v1: `magic (2 bytes) | scheme (1 byte)`. The payload runs until the next magic value.
+///  - v2: `magic (2 bytes) | version (1 byte) | mode (1 byte) | scheme (1 byte) |
+///    payload length (4 bytes) | checksum kind (1 byte) | checksum (4 bytes)`. The checksum covers
+///    the uncompressed payload, and is left out when the checksum kind is NO_CHECKSUM.
+///
+/// v1 frames are always independent. Deflate (1) was the only scheme defined for v1 headers, so the
+/// byte following the magic value doubles as the version number.
 pub struct Header {
     pub magic: u16,
+    pub version: u8,
+    pub mode: Mode,
     pub scheme: Scheme,
+    /// Size in bytes of the compressed payload following the header. v1 headers don't record it.
+    pub length: Option<u32>,
+    /// Checksum of the uncompressed payload. v1 headers don't record it.
+    pub checksum: Option<Checksum>,
 }
 
 // TODO: what should the magic value be? How many bytes should it be?
 /// Magic value that indicates this is the start of a compression Header record
 pub const HEADER_MAGIC_VALUE: u16 = 0xbeef;
 
+/// Legacy header version that relies on scanning for the magic value to find frame boundaries
+pub const HEADER_VERSION_1: u8 = 1;
+/// Current header version, which records the length of the payload
+pub const HEADER_VERSION_2: u8 = 2;
+
+/// Checksum kind recorded for frames without a checksum
+const NO_CHECKSUM: u8 = 0;
+
 impl Header {
-    pub fn new(scheme: Scheme) -> Header {
+    /// Creates a v2 header for an independent frame with a payload of `length` bytes
+    pub fn new(scheme: Scheme, length: u32) -> Header {
+        Header::with_mode(scheme, Mode::Independent, length)
+    }
+
+    /// Creates a v2 header for a payload of `length` bytes
+    pub fn with_mode(scheme: Scheme, mode: Mode, length: u32) -> Header {
+        Header {
+            magic: HEADER_MAGIC_VALUE,
+            version: HEADER_VERSION_2,
+            mode,
+            scheme,
+            length: Some(length),
+            checksum: None,
+        }
+    }
+
+    /// Adds a checksum of the uncompressed payload, or removes it if None
+    pub fn with_checksum(self, checksum: Option<Checksum>) -> Header {
+        Header { checksum, ..self }
+    }
+
+    /// Creates a legacy v1 header
+    pub fn new_v1(scheme: Scheme) -> Header {
         Header {
             magic: HEADER_MAGIC_VALUE,
+            version: HEADER_VERSION_1,
+            mode: Mode::Independent,
             scheme,
+            length: None,
+            checksum: None,
         }
     }
 
-    pub fn to_bytes(&self) -> Option<Vec<u8>> {
-        return Some(
-            [
-                &self.magic.to_be_bytes(),
-                self.scheme.to_bytes()?.as_slice(),
-            ]
-            .concat(),
-        );
+    pub fn to_bytes(self) -> Option<Vec<u8>> {
+        match self.version {
+            HEADER_VERSION_1 => Some(
+                [
+                    &self.magic.to_be_bytes(),
+                    self.scheme.to_bytes()?.as_slice(),
+                ]
+                .concat(),
+            ),
+            HEADER_VERSION_2 => {
+                let mut bytes = [
+                    &self.magic.to_be_bytes(),
+                    &[self.version][..],
+                    self.mode.to_bytes()?.as_slice(),
+                    self.scheme.to_bytes()?.as_slice(),
+                    &self.length?.to_be_bytes(),
+                ]
+                .concat();
+                match self.checksum {
+                    Some(checksum) => {
+                        bytes.extend(checksum.kind.to_bytes()?);
+                        bytes.extend_from_slice(&checksum.value.to_be_bytes());
+                    }
+                    None => bytes.push(NO_CHECKSUM),
+                }
+                Some(bytes)
+            }
+            _ => None,
+        }
     }
 
+    /// Parses a header from the start of the buffer. Returns None if the buffer doesn't start with
+    /// a complete, valid header.
     pub fn from_bytes(buf: &[u8]) -> Option<Header> {
         let magic_bytes = HEADER_MAGIC_VALUE.to_be_bytes();
-        if buf[..magic_bytes.len()] != magic_bytes {
+        if buf.len() <= magic_bytes.len() || buf[..magic_bytes.len()] != magic_bytes {
             return None;
         }
 
-        let scheme = Scheme::from_bytes(&buf[magic_bytes.len()..])?;
-        Some(Header::new(scheme))
+        let rest = &buf[magic_bytes.len()..];
+        match rest[0] {
+            HEADER_VERSION_1 => Some(Header::new_v1(Scheme::from_bytes(rest)?)),
+            HEADER_VERSION_2 => {
+                let mode = Mode::from_bytes(rest.get(1..)?)?;
+                let scheme = Scheme::from_bytes(rest.get(1 + Mode::serialized_size()..)?)?;
+                let length_start = 1 + Mode::serialized_size() + Scheme::serialized_size();
+                let length_bytes = rest.get(length_start..length_start + 4)?;
+                let length = u32::from_be_bytes(length_bytes.try_into().ok()?);
+                let header = Header::with_mode(scheme, mode, length);
+
+                let kind_bytes = rest.get(length_start + 4..)?;
+                if kind_bytes.first() == Some(&NO_CHECKSUM) {
+                    return Some(header);
+                }
+                let kind = ChecksumKind::from_bytes(kind_bytes)?;
+                let value_start = length_start + 4 + ChecksumKind::serialized_size();
+                let value_bytes = rest.get(value_start..value_start + 4)?;
+                let value = u32::from_be_bytes(value_bytes.try_into().ok()?);
+                Some(header.with_checksum(Some(Checksum { kind, value })))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if the buffer could be the start of a valid header that hasn't been fully
+    /// received yet, i.e. it is shorter than a header and consistent with one so far.
+    pub fn is_partial(buf: &[u8]) -> bool {
+        let magic_bytes = HEADER_MAGIC_VALUE.to_be_bytes();
+        if !magic_bytes.starts_with(&buf[..buf.len().min(magic_bytes.len())]) {
+            return false;
+        }
+
+        let fields = buf.get(magic_bytes.len() + 1..).unwrap_or(&[]);
+        // Checks the field at the index if it has been received yet
+        let valid_if_present = |index: usize, is_valid: fn(&[u8]) -> bool| match fields.get(index..)
+        {
+            Some(rest) if !rest.is_empty() => is_valid(rest),
+            _ => true,
+        };
+        let is_mode = |rest: &[u8]| Mode::from_bytes(rest).is_some();
+        let is_scheme = |rest: &[u8]| Scheme::from_bytes(rest).is_some();
+        let is_checksum_kind =
+            |rest: &[u8]| rest[0] == NO_CHECKSUM || ChecksumKind::from_bytes(rest).is_some();
+
+        match buf.get(magic_bytes.len()) {
+            None => true,
+            Some(&HEADER_VERSION_2) => {
+                let checksum_kind_index = Mode::serialized_size() + Scheme::serialized_size() + 4;
+                valid_if_present(0, is_mode)
+                    && valid_if_present(Mode::serialized_size(), is_scheme)
+                    && valid_if_present(checksum_kind_index, is_checksum_kind)
+                    && Header::from_bytes(buf).is_none()
+            }
+            // v1 headers are complete once the scheme byte has been received
+            Some(_) => false,
+        }
     }
 
     /// Size in bytes when serialized
-    pub fn serialized_size() -> usize {
+    pub fn serialized_size(self) -> usize {
         // TODO: connect size to size of serialized HEADER_MAGIC_VALUE. Shouldn't require converting
         //  it to bytes first.
-        2 + Scheme::serialized_size()
+        match self.version {
+            HEADER_VERSION_1 => 2 + Scheme::serialized_size(),
+            _ => {
+                2 + 1
+                    + Mode::serialized_size()
+                    + Scheme::serialized_size()
+                    + 4
+                    + ChecksumKind::serialized_size()
+                    + self.checksum.map_or(0, |_| 4)
+            }
+        }
     }
 }
 
+/// Returns the index of the first occurrence of the header magic value in the buffer
+pub fn find_magic_value(data: &[u8]) -> Option<usize> {
+    let magic_bytes: [u8; 2] = HEADER_MAGIC_VALUE.to_be_bytes();
+    data.windows(magic_bytes.len())
+        .position(|byte_pair| byte_pair == magic_bytes)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::compression::header::{Header, HEADER_MAGIC_VALUE};
+    use crate::compression::checksum::{Checksum, ChecksumKind};
+    use crate::compression::header::{Header, HEADER_MAGIC_VALUE, HEADER_VERSION_2};
+    use crate::compression::mode::Mode;
     use crate::compression::scheme::Scheme;
 
     #[test]
@@ -63,12 +213,65 @@ mod tests {
             None => panic!("Could not convert scheme to bytes"),
         };
         let magic_bytes = &HEADER_MAGIC_VALUE.to_be_bytes();
-        let expected_result: Vec<u8> = [magic_bytes, scheme_bytes.as_slice()].concat();
-        assert_eq!(Header::new(scheme).to_bytes(), Some(expected_result));
+        let length_bytes = &42u32.to_be_bytes();
+        let expected_result: Vec<u8> = [
+            magic_bytes,
+            &[HEADER_VERSION_2][..],
+            Mode::Independent.to_bytes().unwrap().as_slice(),
+            scheme_bytes.as_slice(),
+            length_bytes,
+            &[0],
+        ]
+        .concat();
+        assert_eq!(Header::new(scheme, 42).to_bytes(), Some(expected_result));
     }
 
     #[test]
     fn header_from_bytes() {
+        let scheme = Scheme::Deflate;
+        let scheme_bytes = match scheme.to_bytes() {
+            Some(data) => data,
+            None => panic!("Could not convert scheme to bytes"),
+        };
+        let magic_bytes = &HEADER_MAGIC_VALUE.to_be_bytes();
+        let length_bytes = &42u32.to_be_bytes();
+        let bytes: Vec<u8> = [
+            magic_bytes,
+            &[HEADER_VERSION_2][..],
+            Mode::Streaming.to_bytes().unwrap().as_slice(),
+            scheme_bytes.as_slice(),
+            length_bytes,
+            &[0],
+        ]
+        .concat();
+        let expected_header = Header::with_mode(scheme, Mode::Streaming, 42);
+        assert_eq!(Header::from_bytes(&bytes), Some(expected_header));
+        assert_eq!(expected_header.serialized_size(), bytes.len());
+    }
+
+    #[test]
+    fn checksum_header_round_trip() {
+        for kind in [ChecksumKind::Crc32, ChecksumKind::Xxh32].iter() {
+            let checksum = Checksum {
+                kind: *kind,
+                value: 0xdead_beef,
+            };
+            let header =
+                Header::with_mode(Scheme::Zstd, Mode::Streaming, 42).with_checksum(Some(checksum));
+
+            let bytes = header.to_bytes().unwrap();
+            assert_eq!(bytes.len(), header.serialized_size());
+            assert_eq!(Header::from_bytes(&bytes), Some(header));
+            for end in 0..bytes.len() {
+                assert_eq!(Header::from_bytes(&bytes[..end]), None);
+                assert!(Header::is_partial(&bytes[..end]));
+            }
+            assert!(!Header::is_partial(&bytes));
+        }
+    }
+
+    #[test]
+    fn v1_header_from_bytes() {
         let scheme = Scheme::Deflate;
         let scheme_bytes = match scheme.to_bytes() {
             Some(data) => data,
@@ -76,7 +279,46 @@ mod tests {
         };
         let magic_bytes = &HEADER_MAGIC_VALUE.to_be_bytes();
         let bytes: Vec<u8> = [magic_bytes, scheme_bytes.as_slice()].concat();
-        let expected_header = Header::new(scheme);
+        let expected_header = Header::new_v1(scheme);
         assert_eq!(Header::from_bytes(&bytes), Some(expected_header));
+        assert_eq!(expected_header.to_bytes(), Some(bytes));
+    }
+
+    #[test]
+    fn header_from_truncated_bytes() {
+        let bytes = Header::new(Scheme::Deflate, 42).to_bytes().unwrap();
+        for end in 0..bytes.len() {
+            assert_eq!(Header::from_bytes(&bytes[..end]), None);
+        }
+    }
+
+    #[test]
+    fn header_with_undefined_mode_or_checksum() {
+        let bytes = Header::new(Scheme::Deflate, 42).to_bytes().unwrap();
+        let mut undefined_mode = bytes.clone();
+        undefined_mode[3] = 0xff;
+        assert_eq!(Header::from_bytes(&undefined_mode), None);
+
+        let mut undefined_checksum = bytes;
+        *undefined_checksum.last_mut().unwrap() = 0xff;
+        assert_eq!(Header::from_bytes(&undefined_checksum), None);
+    }
+
+    #[test]
+    fn header_is_partial() {
+        let bytes = Header::new(Scheme::Deflate, 42).to_bytes().unwrap();
+        for end in 0..bytes.len() {
+            assert!(Header::is_partial(&bytes[..end]));
+        }
+        assert!(!Header::is_partial(&bytes));
+        assert!(!Header::is_partial(&[0xde, 0xad]));
+        assert!(!Header::is_partial(&[0xbe, 0xef, 0xff]));
+        // Invalid mode
+        assert!(!Header::is_partial(&[0xbe, 0xef, HEADER_VERSION_2, 0xff]));
+        // Invalid checksum kind
+        let mut bytes = bytes;
+        *bytes.last_mut().unwrap() = 0xff;
+        assert!(!Header::is_partial(&bytes));
+        assert_eq!(Header::from_bytes(&bytes), None);
     }
 }
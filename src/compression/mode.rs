@@ -0,0 +1,68 @@
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::{FromPrimitive, ToPrimitive};
+
+#[repr(u8)]
+#[derive(FromPrimitive, ToPrimitive, PartialEq, Debug, Copy, Clone)]
+/// How the payload of a compression frame relates to the other frames on the connection
+pub enum Mode {
+    /// The payload is a complete compressed stream that can be decompressed on its own
+    Independent = 0,
+    /// The payload continues the compressed stream started by the first streaming frame on the
+    /// connection, and ends with a sync flush so it can be decompressed as soon as it arrives.
+    /// The compression dictionary is kept across frames.
+    Streaming = 1,
+}
+
+/// Names used to select a compression mode, e.g. on the command line
+pub const MODE_NAMES: &[&str] = &["independent", "streaming"];
+
+impl Mode {
+    pub fn to_bytes(self) -> Option<Vec<u8>> {
+        Some(self.to_u8()?.to_be_bytes().to_vec())
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Option<Mode> {
+        match buffer.len() {
+            0 => None,
+            _ => Mode::from_u8(buffer[0]),
+        }
+    }
+
+    /// Size in bytes when serialized
+    pub fn serialized_size() -> usize {
+        1
+    }
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Mode, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "independent" => Ok(Mode::Independent),
+            "streaming" => Ok(Mode::Streaming),
+            _ => Err(format!("unknown compression mode \"{}\"", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::mode::{Mode, MODE_NAMES};
+
+    #[test]
+    fn mode_bytes() {
+        assert_eq!(Mode::Streaming.to_bytes(), Some(vec![0x01]));
+        assert_eq!(Mode::from_bytes(&[0x00]), Some(Mode::Independent));
+        assert_eq!(Mode::from_bytes(&[0x02]), None);
+        assert_eq!(Mode::from_bytes(&[]), None);
+    }
+
+    #[test]
+    fn mode_names_parse() {
+        for name in MODE_NAMES {
+            assert!(name.parse::<Mode>().is_ok());
+        }
+        assert!("dictionary".parse::<Mode>().is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::compression::header::{find_magic_value, Header};
+
+/// Largest compressed payload accepted in one frame. The proxies compress each read separately, so
+/// real frames are far smaller. This stops a peer from making the reassembler buffer gigabytes by
+/// sending a header with a huge length.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Buffers compressed data received over a connection and hands back complete compression frames.
+///
+/// Reads from a socket don't line up with frame boundaries: a read can end part way through a
+/// header or a payload, or contain several frames. Data is held until a whole frame is available.
+pub struct FrameReassembler {
+    buf: Vec<u8>,
+}
+
+impl FrameReassembler {
+    pub fn new() -> FrameReassembler {
+        FrameReassembler { buf: Vec::new() }
+    }
+
+    /// Adds data received from the connection to the end of the buffer
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Number of buffered bytes that haven't been returned as part of a frame yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Removes and returns the next complete frame, including its header. Returns Ok(None) if more
+    /// data is needed to complete the frame.
+    ///
+    /// v1 frames don't record their length, so they are only complete once the header of the
+    /// following frame has been received, or once finish() is called.
+    ///
+    /// Returns an error if the frame's payload is larger than MAX_FRAME_SIZE.
+    pub fn next_frame(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        if self.buf.is_empty() || Header::is_partial(&self.buf) {
+            return Ok(None);
+        }
+
+        let header = Header::from_bytes(&self.buf).ok_or_else(|| {
+            std::io::Error::other("Expected a compression header at the start of the frame")
+        })?;
+        let header_size = header.serialized_size();
+
+        let frame_size = match header.length {
+            Some(length) => header_size + length as usize,
+            None => match find_magic_value(&self.buf[header_size..]) {
+                Some(index) => header_size + index,
+                None => {
+                    check_payload_size(self.buf.len() - header_size)?;
+                    return Ok(None);
+                }
+            },
+        };
+        check_payload_size(frame_size - header_size)?;
+        if frame_size > self.buf.len() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.buf.drain(..frame_size).collect()))
+    }
+
+    /// Returns the final frame once the connection has closed. This is only needed to complete a
+    /// trailing v1 frame, complete frames should be taken with next_frame() first. Any other
+    /// leftover data is an incomplete frame and returns an error.
+    pub fn finish(self) -> std::io::Result<Option<Vec<u8>>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        match Header::from_bytes(&self.buf) {
+            Some(header) if header.length.is_none() => Ok(Some(self.buf)),
+            _ => Err(std::io::Error::other(format!(
+                "Connection closed with an incomplete compression frame of {} bytes buffered",
+                self.buf.len()
+            ))),
+        }
+    }
+}
+
+/// Returns an error if a frame's payload is larger than MAX_FRAME_SIZE
+fn check_payload_size(size: usize) -> std::io::Result<()> {
+    if size > MAX_FRAME_SIZE {
+        return Err(std::io::Error::other(format!(
+            "Compression frame payload of {} bytes is larger than the maximum of {} bytes",
+            size, MAX_FRAME_SIZE
+        )));
+    }
+    Ok(())
+}
+
+impl Default for FrameReassembler {
+    fn default() -> Self {
+        FrameReassembler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::header::{Header, HEADER_MAGIC_VALUE};
+    use crate::compression::reassembly::{FrameReassembler, MAX_FRAME_SIZE};
+    use crate::compression::scheme::Scheme;
+    use crate::compression::{Compressor, Decompressor};
+    use crate::test_util::incompressible;
+    use std::io::Write;
+
+    /// Helper function to return a compressed frame for the input data
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut compressor = Compressor::new(Vec::new());
+        compressor.write_all(data).unwrap();
+        compressor.finish().unwrap()
+    }
+
+    /// Helper function that feeds the data to a reassembler in chunks of the given sizes, cycling
+    /// through the sizes, and returns all frames in the order they were produced
+    fn reassemble(data: &[u8], chunk_sizes: &[usize]) -> Vec<Vec<u8>> {
+        let mut reassembler = FrameReassembler::new();
+        let mut frames = Vec::new();
+        let mut rest = data;
+        for size in chunk_sizes.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+            reassembler.push(chunk);
+            while let Some(frame) = reassembler.next_frame().unwrap() {
+                frames.push(frame);
+            }
+            rest = tail;
+        }
+        frames.extend(reassembler.finish().unwrap());
+        frames
+    }
+
+    #[test]
+    fn reassemble_every_chunk_size() {
+        let frames = vec![
+            compress(b"Hello world! This is quite compressed...."),
+            compress(&incompressible(3000)),
+            compress(b"And a short one"),
+        ];
+        let data = frames.concat();
+
+        for chunk_size in 1..=data.len() {
+            assert_eq!(reassemble(&data, &[chunk_size]), frames);
+        }
+    }
+
+    #[test]
+    fn reassemble_irregular_chunks() {
+        let frames: Vec<Vec<u8>> = (0..20).map(|i| compress(&incompressible(i * 97))).collect();
+        let data = frames.concat();
+
+        assert_eq!(reassemble(&data, &[1, 7, 2, 1024, 3, 8, 1, 4096]), frames);
+        assert_eq!(reassemble(&data, &[data.len()]), frames);
+    }
+
+    #[test]
+    fn reassembled_frames_decompress() {
+        let message = incompressible(10000);
+        let data: Vec<u8> = message.chunks(1024).flat_map(compress).collect();
+
+        let decompressed: Vec<u8> = reassemble(&data, &[5, 1000, 13])
+            .iter()
+            .flat_map(|frame| {
+                let mut decompressor = Decompressor::new(Vec::new());
+                decompressor.write_all(frame).unwrap();
+                decompressor.finish().unwrap()
+            })
+            .collect();
+
+        assert_eq!(decompressed, message);
+    }
+
+    #[test]
+    fn reassemble_v1_frames() {
+        let v1_header = Header::new_v1(Scheme::Deflate).to_bytes().unwrap();
+        let frames = vec![
+            [v1_header.as_slice(), &[0x01, 0x02, 0x03]].concat(),
+            [v1_header.as_slice(), &[0x04]].concat(),
+        ];
+        let data = frames.concat();
+
+        for chunk_size in 1..=data.len() {
+            assert_eq!(reassemble(&data, &[chunk_size]), frames);
+        }
+    }
+
+    #[test]
+    fn reassemble_magic_value_in_payload() {
+        let magic_bytes = HEADER_MAGIC_VALUE.to_be_bytes();
+        let payload = [magic_bytes[0], magic_bytes[1], 0x02, 0x01];
+        let header = Header::new(Scheme::Deflate, payload.len() as u32);
+        let frame = [header.to_bytes().unwrap().as_slice(), &payload].concat();
+        let data = [frame.as_slice(), frame.as_slice()].concat();
+
+        assert_eq!(reassemble(&data, &[3]), vec![frame.clone(), frame]);
+    }
+
+    #[test]
+    fn reassemble_rejects_invalid_header() {
+        let mut reassembler = FrameReassembler::new();
+        reassembler.push(b"not a compression frame");
+        assert!(reassembler.next_frame().is_err());
+    }
+
+    #[test]
+    fn reassemble_rejects_oversized_frame() {
+        let header = Header::new(Scheme::Deflate, MAX_FRAME_SIZE as u32 + 1);
+        let mut reassembler = FrameReassembler::new();
+        reassembler.push(&header.to_bytes().unwrap());
+        assert!(reassembler.next_frame().is_err());
+
+        let header = Header::new(Scheme::Deflate, MAX_FRAME_SIZE as u32);
+        let mut reassembler = FrameReassembler::new();
+        reassembler.push(&header.to_bytes().unwrap());
+        assert_eq!(reassembler.next_frame().unwrap(), None);
+
+        // v1 frames can't be buffered forever waiting for the next header either
+        let mut reassembler = FrameReassembler::new();
+        reassembler.push(&Header::new_v1(Scheme::Deflate).to_bytes().unwrap());
+        reassembler.push(&vec![0; MAX_FRAME_SIZE]);
+        assert_eq!(reassembler.next_frame().unwrap(), None);
+        reassembler.push(&[0]);
+        assert!(reassembler.next_frame().is_err());
+    }
+
+    #[test]
+    fn finish_rejects_incomplete_frame() {
+        let frame = compress(b"Hello world! This is quite compressed....");
+
+        let mut reassembler = FrameReassembler::new();
+        reassembler.push(&frame[..frame.len() - 1]);
+        assert_eq!(reassembler.next_frame().unwrap(), None);
+        assert_eq!(reassembler.buffered(), frame.len() - 1);
+        assert!(reassembler.finish().is_err());
+
+        let mut reassembler = FrameReassembler::new();
+        reassembler.push(&frame[..3]);
+        assert_eq!(reassembler.next_frame().unwrap(), None);
+        assert!(reassembler.finish().is_err());
+    }
+}
@@ -1,17 +1,28 @@
+use crate::compression::MAX_BROTLI_QUALITY;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use std::ops::RangeInclusive;
 
 #[repr(u8)]
 #[derive(FromPrimitive, ToPrimitive, PartialEq, Debug, Copy, Clone)]
 /// Supported compression schemes
 /// Follows the format specified in IETF RFC 3749
 pub enum Scheme {
-    // TODO: should we support additional compression schemes?
+    /// The payload is stored uncompressed, as for the null method in IETF RFC 3749. Used for
+    /// frames where compressing would not have saved any space.
+    Stored = 0,
     Deflate = 1,
+    // Schemes that aren't defined by an RFC use values from the private use range (224-255)
+    Zstd = 224,
+    Brotli = 225,
+    Lz4 = 226,
 }
 
+/// Names used to select a compression scheme, e.g. on the command line
+pub const SCHEME_NAMES: &[&str] = &["deflate", "zstd", "brotli", "lz4"];
+
 impl Scheme {
-    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+    pub fn to_bytes(self) -> Option<Vec<u8>> {
         Some(self.to_u8()?.to_be_bytes().to_vec())
     }
 
@@ -22,6 +33,25 @@ impl Scheme {
         }
     }
 
+    /// Range of compression levels supported by the scheme, None if it doesn't have levels
+    pub fn level_range(self) -> Option<RangeInclusive<u32>> {
+        match self {
+            Scheme::Deflate => Some(0..=9),
+            Scheme::Zstd => Some(1..=22),
+            Scheme::Brotli => Some(0..=MAX_BROTLI_QUALITY),
+            Scheme::Stored | Scheme::Lz4 => None,
+        }
+    }
+
+    /// Returns true if the scheme can be used for streaming frames. This needs a decoder that
+    /// can decompress a sync-flushed stream as it arrives, which lz4_flex doesn't provide.
+    pub fn supports_streaming(self) -> bool {
+        match self {
+            Scheme::Deflate | Scheme::Zstd | Scheme::Brotli => true,
+            Scheme::Stored | Scheme::Lz4 => false,
+        }
+    }
+
     /// Size in bytes when serialized
     pub fn serialized_size() -> usize {
         // TODO: connect size to size of serialized Scheme. Shouldn't require converting
@@ -30,9 +60,23 @@ impl Scheme {
     }
 }
 
+impl std::str::FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Scheme, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "deflate" => Ok(Scheme::Deflate),
+            "zstd" => Ok(Scheme::Zstd),
+            "brotli" => Ok(Scheme::Brotli),
+            "lz4" => Ok(Scheme::Lz4),
+            _ => Err(format!("unknown compression scheme \"{}\"", name)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::compression::scheme::Scheme;
+    use crate::compression::scheme::{Scheme, SCHEME_NAMES};
 
     #[test]
     fn scheme_to_bytes() {
@@ -46,4 +90,26 @@ mod tests {
         let expected_scheme = Scheme::Deflate;
         assert_eq!(Scheme::from_bytes(&bytes), Some(expected_scheme));
     }
+
+    #[test]
+    fn stored_scheme_bytes() {
+        assert_eq!(Scheme::Stored.to_bytes(), Some(vec![0x00]));
+        assert_eq!(Scheme::from_bytes(&[0x00]), Some(Scheme::Stored));
+    }
+
+    #[test]
+    fn scheme_from_name() {
+        assert_eq!("deflate".parse::<Scheme>(), Ok(Scheme::Deflate));
+        assert_eq!("zstd".parse::<Scheme>(), Ok(Scheme::Zstd));
+        assert_eq!("Brotli".parse::<Scheme>(), Ok(Scheme::Brotli));
+        assert_eq!("lz4".parse::<Scheme>(), Ok(Scheme::Lz4));
+        assert!("gzip".parse::<Scheme>().is_err());
+    }
+
+    #[test]
+    fn scheme_names_parse() {
+        for name in SCHEME_NAMES {
+            assert!(name.parse::<Scheme>().is_ok());
+        }
+    }
 }
@@ -0,0 +1,316 @@
+use crate::compression::checksum::{Checksum, ChecksumKind};
+use crate::compression::clients::{Compressor, Decompressor};
+use crate::compression::codecs::{Decoder, Encoder, OutputLimit};
+use crate::compression::header::Header;
+use crate::compression::mode::Mode;
+use crate::compression::scheme::Scheme;
+use crate::compression::CompressionSettings;
+use std::convert::TryFrom;
+use std::io::prelude::*;
+
+/// Compresses the data sent over a connection into streaming frames.
+///
+/// One encoder is used for the lifetime of the connection, so the dictionary built up from earlier
+/// data keeps improving the compression ratio. The encoder is sync flushed at the end of each frame
+/// so the receiver can decompress the frame as soon as it arrives.
+///
+/// Data smaller than the configured minimum payload size is sent in an independent stored frame
+/// instead, and doesn't become part of the stream.
+pub struct StreamCompressor {
+    scheme: Scheme,
+    min_payload_size: usize,
+    checksum: Option<ChecksumKind>,
+    encoder: Encoder<Vec<u8>>,
+}
+
+impl StreamCompressor {
+    pub fn new(settings: CompressionSettings) -> std::io::Result<StreamCompressor> {
+        if !settings.scheme.supports_streaming() {
+            return Err(std::io::Error::other(format!(
+                "The {:?} compression scheme doesn't support streaming mode",
+                settings.scheme
+            )));
+        }
+
+        Ok(StreamCompressor {
+            scheme: settings.scheme,
+            min_payload_size: settings.min_payload_size,
+            checksum: settings.checksum,
+            encoder: Encoder::with_settings(settings, Vec::new())?,
+        })
+    }
+
+    /// Compresses the data into a single streaming frame and returns the frame, header first
+    pub fn compress_frame(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        if data.len() < self.min_payload_size {
+            let mut stored = CompressionSettings::new(Scheme::Stored);
+            stored.checksum = self.checksum;
+            let mut compressor = Compressor::with_settings(Vec::new(), stored)?;
+            compressor.write_all(data)?;
+            return compressor.finish();
+        }
+
+        self.encoder.write_all(data)?;
+        self.encoder.flush()?;
+        let payload = std::mem::take(self.encoder.get_mut());
+        let length = u32::try_from(payload.len())
+            .map_err(|_| std::io::Error::other("Compressed payload is too large for one frame"))?;
+
+        let checksum = self.checksum.map(|kind| Checksum::compute(kind, data));
+
+        let header_bytes = match Header::with_mode(self.scheme, Mode::Streaming, length)
+            .with_checksum(checksum)
+            .to_bytes()
+        {
+            Some(data) => data,
+            None => return Err(std::io::Error::other("Could not convert header to bytes")),
+        };
+
+        Ok([header_bytes, payload].concat())
+    }
+}
+
+/// Decompresses the frames received over a connection.
+///
+/// Independent frames are decompressed on their own. Streaming frames share one decoder for the
+/// lifetime of the connection, which is created from the first streaming frame's scheme. Each frame
+/// can decompress to at most MAX_FRAME_SIZE bytes.
+pub struct StreamDecompressor {
+    stream: Option<(Scheme, Decoder<OutputLimit<Vec<u8>>>)>,
+}
+
+impl StreamDecompressor {
+    pub fn new() -> StreamDecompressor {
+        StreamDecompressor { stream: None }
+    }
+
+    /// Decompresses one complete frame, including its header
+    pub fn decompress_frame(&mut self, frame: &[u8]) -> std::io::Result<Vec<u8>> {
+        let header = Header::from_bytes(frame).ok_or_else(|| {
+            std::io::Error::other("Expected a compression header at the start of the frame")
+        })?;
+
+        if header.mode == Mode::Independent {
+            let mut decompressor = Decompressor::new(Vec::new());
+            decompressor.write_all(frame)?;
+            return decompressor.finish();
+        }
+
+        let payload = &frame[header.serialized_size()..];
+        if header.length != u32::try_from(payload.len()).ok() {
+            return Err(std::io::Error::other(
+                "Streaming frame length doesn't match its header",
+            ));
+        }
+
+        if self.stream.is_none() {
+            self.stream = Some((
+                header.scheme,
+                Decoder::new(header.scheme, OutputLimit::new(Vec::new())).map_err(|(e, _)| e)?,
+            ));
+        }
+        let (scheme, decoder) = self
+            .stream
+            .as_mut()
+            .expect("Stream decoder is created by the first streaming frame");
+        if *scheme != header.scheme {
+            return Err(std::io::Error::other(format!(
+                "Streaming frame uses the {:?} scheme but the stream uses {:?}",
+                header.scheme, scheme
+            )));
+        }
+
+        decoder.get_mut().reset();
+        decoder.write_all(payload)?;
+        decoder.flush()?;
+        let decompressed = std::mem::take(decoder.get_mut().get_mut());
+        if let Some(checksum) = header.checksum {
+            checksum.verify(&decompressed)?;
+        }
+        Ok(decompressed)
+    }
+}
+
+impl Default for StreamDecompressor {
+    fn default() -> Self {
+        StreamDecompressor::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::checksum::{ChecksumKind, ChecksumMismatch};
+    use crate::compression::clients::{Compressor, Decompressor};
+    use crate::compression::header::Header;
+    use crate::compression::mode::Mode;
+    use crate::compression::scheme::Scheme;
+    use crate::compression::stream::{StreamCompressor, StreamDecompressor};
+    use crate::compression::{CompressionSettings, MAX_FRAME_SIZE};
+    use std::io::Write;
+
+    fn streaming_settings(scheme: Scheme) -> CompressionSettings {
+        let mut settings = CompressionSettings::new(scheme);
+        settings.mode = Mode::Streaming;
+        settings
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let messages = [
+            "GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            "GET /style.css HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            "GET /script.js HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ];
+
+        for scheme in [Scheme::Deflate, Scheme::Zstd, Scheme::Brotli].iter() {
+            let mut compressor = StreamCompressor::new(streaming_settings(*scheme)).unwrap();
+            let mut decompressor = StreamDecompressor::new();
+
+            for message in messages.iter() {
+                let frame = compressor.compress_frame(message.as_bytes()).unwrap();
+                let header = Header::from_bytes(&frame).unwrap();
+                assert_eq!(header.mode, Mode::Streaming);
+                assert_eq!(header.scheme, *scheme);
+
+                // Each frame decompresses fully as soon as it is received
+                assert_eq!(
+                    decompressor.decompress_frame(&frame).unwrap(),
+                    message.as_bytes(),
+                    "round trip failed for {:?}",
+                    scheme
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stream_frames_smaller_than_independent_frames() {
+        let message = "GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept: text/html\r\n\r\n";
+
+        let mut compressor = StreamCompressor::new(streaming_settings(Scheme::Deflate)).unwrap();
+        compressor.compress_frame(message.as_bytes()).unwrap();
+        let second_frame = compressor.compress_frame(message.as_bytes()).unwrap();
+
+        let mut independent = Compressor::new(Vec::new());
+        independent.write_all(message.as_bytes()).unwrap();
+        let independent_frame = independent.finish().unwrap();
+
+        assert!(second_frame.len() < independent_frame.len());
+    }
+
+    #[test]
+    fn stream_below_min_payload_size_as_stored() {
+        let mut settings = streaming_settings(Scheme::Zstd);
+        settings.min_payload_size = 10;
+        let mut compressor = StreamCompressor::new(settings).unwrap();
+        let mut decompressor = StreamDecompressor::new();
+
+        for message in ["ok".as_bytes(), b"a longer message", b"hi"].iter() {
+            let frame = compressor.compress_frame(message).unwrap();
+            let header = Header::from_bytes(&frame).unwrap();
+            if message.len() < 10 {
+                assert_eq!(header.scheme, Scheme::Stored);
+                assert_eq!(header.mode, Mode::Independent);
+            } else {
+                assert_eq!(header.mode, Mode::Streaming);
+            }
+            assert_eq!(&decompressor.decompress_frame(&frame).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn stream_checksum_mismatch() {
+        let mut settings = streaming_settings(Scheme::Deflate);
+        settings.checksum = Some(ChecksumKind::Crc32);
+        let mut compressor = StreamCompressor::new(settings).unwrap();
+        let mut decompressor = StreamDecompressor::new();
+
+        let frame = compressor.compress_frame(b"Hello world!").unwrap();
+        assert_eq!(
+            Header::from_bytes(&frame).unwrap().checksum.unwrap().kind,
+            ChecksumKind::Crc32
+        );
+        assert_eq!(
+            decompressor.decompress_frame(&frame).unwrap(),
+            b"Hello world!"
+        );
+
+        let mut frame = compressor.compress_frame(b"Hello again!").unwrap();
+        // Corrupt the checksum so the payload still decompresses
+        let checksum_index = Header::from_bytes(&frame).unwrap().serialized_size() - 1;
+        frame[checksum_index] ^= 0xff;
+        let error = decompressor.decompress_frame(&frame).unwrap_err();
+        assert!(ChecksumMismatch::from_io_error(&error).is_some());
+    }
+
+    #[test]
+    fn stream_decompressor_handles_independent_frames() {
+        let message = "Hello world! This is quite compressed....".repeat(4);
+        let mut compressor = Compressor::new(Vec::new());
+        compressor.write_all(message.as_bytes()).unwrap();
+        let frame = compressor.finish().unwrap();
+
+        let mut decompressor = StreamDecompressor::new();
+        assert_eq!(
+            decompressor.decompress_frame(&frame).unwrap(),
+            message.as_bytes()
+        );
+    }
+
+    #[test]
+    fn stream_decompressor_rejects_scheme_change() {
+        let mut deflate = StreamCompressor::new(streaming_settings(Scheme::Deflate)).unwrap();
+        let mut zstd = StreamCompressor::new(streaming_settings(Scheme::Zstd)).unwrap();
+
+        let mut decompressor = StreamDecompressor::new();
+        decompressor
+            .decompress_frame(&deflate.compress_frame(b"first").unwrap())
+            .unwrap();
+        assert!(decompressor
+            .decompress_frame(&zstd.compress_frame(b"second").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn streaming_frame_requires_stream_decompressor() {
+        let mut compressor = StreamCompressor::new(streaming_settings(Scheme::Deflate)).unwrap();
+        let frame = compressor.compress_frame(b"Hello world!").unwrap();
+
+        let mut decompressor = Decompressor::new(Vec::new());
+        assert!(decompressor.write_all(&frame).is_err());
+    }
+
+    #[test]
+    fn stream_decompressor_rejects_oversized_output() {
+        for scheme in [Scheme::Deflate, Scheme::Zstd, Scheme::Brotli].iter() {
+            let mut compressor = StreamCompressor::new(streaming_settings(*scheme)).unwrap();
+            let mut decompressor = StreamDecompressor::new();
+
+            // The limit applies to each frame, not to the whole stream
+            for _ in 0..2 {
+                let frame = compressor.compress_frame(&vec![0; MAX_FRAME_SIZE]).unwrap();
+                assert_eq!(
+                    decompressor.decompress_frame(&frame).unwrap().len(),
+                    MAX_FRAME_SIZE,
+                    "{:?}",
+                    scheme
+                );
+            }
+
+            let frame = compressor
+                .compress_frame(&vec![0; MAX_FRAME_SIZE + 1])
+                .unwrap();
+            assert!(frame.len() < 10_000);
+            assert!(
+                decompressor.decompress_frame(&frame).is_err(),
+                "{:?}",
+                scheme
+            );
+        }
+    }
+
+    #[test]
+    fn stream_compressor_rejects_lz4() {
+        assert!(StreamCompressor::new(streaming_settings(Scheme::Lz4)).is_err());
+    }
+}
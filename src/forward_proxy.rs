@@ -1,38 +1,37 @@
-use crate::compression::Direction;
+use crate::compression::{CompressionSettings, Direction};
 use crate::errors::*;
 use crate::iostream::IoStream;
+use crate::negotiation;
 use crate::proxy_common::proxy_conn;
 use crate::reverse_proxy;
+use crate::sniff;
+use crate::tls::{self, ClientTlsSettings, ReloadableConfig, ServerNameTable};
 use dns_lookup::lookup_addr;
-use error_chain::bail;
+use error_chain::ChainedError;
 use nix::sys::socket;
-use std::fs::File;
-use std::io::BufReader;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
 use std::sync::Arc;
 use tokio::io::split;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector, TlsStream};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::{webpki::DNSNameRef, TlsConnector, TlsStream};
 
 pub const PROXY_REDIR_PORT: u16 = 8080;
 
 pub fn run(
     local_addr: SocketAddr,
-    compress: bool,
-    encrypt: bool,
-    root_certs_path: Option<&Path>,
+    compression: Vec<CompressionSettings>,
+    tls: Option<ClientTlsSettings>,
 ) -> Result<()> {
     let rt = tokio::runtime::Runtime::new().chain_err(|| "failed to create tokio runtime")?;
-    rt.block_on(run_async(local_addr, compress, encrypt, root_certs_path))
+    rt.block_on(run_async(local_addr, compression, tls))
 }
 
 pub async fn run_async(
     local_addr: SocketAddr,
-    compress: bool,
-    encrypt: bool,
-    root_certs_path: Option<&Path>,
+    compression: Vec<CompressionSettings>,
+    tls: Option<ClientTlsSettings>,
 ) -> Result<()> {
     println!("opening listener socket on {}", local_addr);
     let listen_socket = TcpListener::bind(local_addr)
@@ -45,87 +44,206 @@ pub async fn run_async(
         &true,
     )?;
 
-    forward_proxy(listen_socket, compress, encrypt, root_certs_path).await
+    forward_proxy(listen_socket, compression, tls).await
 }
 
+/// TLS state shared by the connections to reverse proxies
+struct TlsClient {
+    config: Arc<ReloadableConfig<ClientConfig>>,
+    server_names: ServerNameTable,
+}
+
+/// Returns the name the reverse proxy at the address is expected to have in its certificate. The
+/// configured table takes priority, reverse DNS is only a fallback for addresses that aren't in it.
+fn server_name(addr: IpAddr, server_names: &ServerNameTable) -> Result<String> {
+    if let Some(name) = server_names.lookup(addr) {
+        return Ok(name.to_owned());
+    }
+    lookup_addr(&addr).chain_err(|| {
+        format!(
+            "no TLS server name configured for {} and reverse DNS lookup failed",
+            addr
+        )
+    })
+}
+
+/// Opens a connection to the reverse proxy, encrypted if TLS is configured
+async fn connect(to_addr: SocketAddr, tls_client: Option<&TlsClient>) -> Result<IoStream> {
+    let to_tcp_conn = TcpStream::connect(to_addr).await?;
+    let tls_client = match tls_client {
+        Some(tls_client) => tls_client,
+        None => return Ok(IoStream::from(to_tcp_conn)),
+    };
+
+    let name = server_name(to_addr.ip(), &tls_client.server_names)?;
+    let dnsname = DNSNameRef::try_from_ascii_str(&name)
+        .chain_err(|| format!("\"{}\" is not a valid TLS server name", name))?;
+    // Taken for each connection so reloaded certificates are picked up
+    let connector = TlsConnector::from(tls_client.config.get());
+    let tls_conn = connector
+        .connect(dnsname, to_tcp_conn)
+        .await
+        .chain_err(|| format!("TLS handshake with {} failed", name))?;
+    Ok(IoStream::from(TlsStream::from(tls_conn)))
+}
+
+/// Proxies data both ways between a client and the connection opened for it, compressing data sent
+/// by the client if `compress` is set
+fn spawn_proxy(
+    from_conn: IoStream,
+    from_addr: SocketAddr,
+    to_conn: IoStream,
+    to_addr: SocketAddr,
+    compress: Option<CompressionSettings>,
+) {
+    let (client_read, client_write) = split::<IoStream>(from_conn);
+    let (server_read, server_write) = split::<IoStream>(to_conn);
+
+    tokio::spawn(async move {
+        proxy_conn(
+            client_read,
+            server_write,
+            compress.map(Direction::Compress),
+            None,
+            &format!("{} -> {}", from_addr, to_addr),
+        )
+        .await;
+    });
+    tokio::spawn(async move {
+        proxy_conn(
+            server_read,
+            client_write,
+            compress.map(|_| Direction::Decompress),
+            None,
+            &format!("{} -> {}", to_addr, from_addr),
+        )
+        .await;
+    });
+}
+
+/// Proxies an intercepted connection that was headed for `dest_addr`. Connections that start with
+/// a TLS ClientHello are passed through to `dest_addr` untouched, since they are already encrypted
+/// and wouldn't compress. Everything else goes to the reverse proxy at the destination address.
+async fn proxy_intercepted(
+    from_conn: TcpStream,
+    from_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    compression: &[CompressionSettings],
+    tls_client: Option<&TlsClient>,
+) {
+    let is_tls = sniff::sniff(
+        &from_conn,
+        sniff::CLIENT_HELLO_PREFIX_LEN,
+        sniff::is_tls_client_hello,
+    )
+    .await;
+    match is_tls {
+        Ok(Some(true)) => {
+            println!(
+                "TLS ClientHello received from {}, passing the connection through to {}",
+                from_addr, dest_addr
+            );
+            match TcpStream::connect(dest_addr).await {
+                Ok(to_conn) => spawn_proxy(
+                    IoStream::from(from_conn),
+                    from_addr,
+                    IoStream::from(to_conn),
+                    dest_addr,
+                    None,
+                ),
+                Err(e) => eprintln!("failed to connect to {}: {}", dest_addr, e),
+            }
+            return;
+        }
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("error reading from {}: {}", from_addr, e.display_chain());
+            return;
+        }
+    }
+
+    let to_addr = SocketAddr::new(dest_addr.ip(), reverse_proxy::HTTPS_PORT);
+    let mut to_conn = match connect(to_addr, tls_client).await {
+        Ok(to_conn) => to_conn,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", to_addr, e.display_chain());
+            return;
+        }
+    };
+
+    println!("connection opened to {}", to_addr);
+
+    let compress = match negotiation::offer(&mut to_conn, compression).await {
+        Ok(compress) => compress,
+        Err(e) => {
+            eprintln!("compression negotiation with {} failed: {}", to_addr, e);
+            return;
+        }
+    };
+    spawn_proxy(
+        IoStream::from(from_conn),
+        from_addr,
+        to_conn,
+        to_addr,
+        compress,
+    );
+}
+
+/// `compression` lists the compression settings to offer to the reverse proxy, in order of
+/// preference. Compression is disabled if it is empty. Connections to the reverse proxy are
+/// encrypted if `tls` is set.
+///
 /// Note: this function allows for a custom TcpListener to be provided. Most users will either want
 /// to call run() or run_async() which sets the IP_TRANSPARENT option for the socket. This function
 /// is primarily useful for testing without the IP_TRANSPARENT option.
 pub async fn forward_proxy(
     listen_socket: TcpListener,
-    compress: bool,
-    encrypt: bool,
-    root_certs_path: Option<&Path>,
+    compression: Vec<CompressionSettings>,
+    tls: Option<ClientTlsSettings>,
 ) -> Result<()> {
-    let mut tls_config = ClientConfig::new();
-
-    if encrypt {
-        let mut root_file = BufReader::new(File::open(
-            root_certs_path.ok_or("Must provide a root cert path if encrypt is set.")?,
-        )?);
-        tls_config
-            .root_store
-            .add_pem_file(&mut root_file)
-            .map_err(|_| "Couldn't parse root cert file.")?;
-        if tls_config.root_store.is_empty() {
-            bail!("No root certs added to store.")
+    let tls_client = match tls {
+        Some(settings) => {
+            let config = Arc::new(ReloadableConfig::new(tls::client_config(&settings)?));
+            let server_names = settings.server_names.clone();
+            tokio::spawn(tls::log_session_stats(settings.session_stats.clone()));
+            tokio::spawn(tls::watch(
+                settings,
+                Arc::clone(&config),
+                tls::RELOAD_POLL_INTERVAL,
+            ));
+            Some(Arc::new(TlsClient {
+                config,
+                server_names,
+            }))
         }
-    }
+        None => None,
+    };
 
-    let tls_config_ref = Arc::new(tls_config);
     loop {
         let (from_conn, from_addr) = listen_socket
             .accept()
             .await
-            .chain_err(|| format!("error accepting connection"))?;
+            .chain_err(|| "error accepting connection")?;
         println!("connection received from {}", from_addr);
 
         match socket::getsockname(from_conn.as_raw_fd()) {
             Ok(socket::SockAddr::Inet(inet_addr)) => {
-                println!("connection destined to {}", inet_addr);
-
-                let to_addr = SocketAddr::new(inet_addr.ip().to_std(), reverse_proxy::HTTPS_PORT);
-
-                let to_tcp_conn = TcpStream::connect(to_addr).await?;
-                let to_conn = match encrypt {
-                    false => IoStream::from(to_tcp_conn),
-                    true => {
-                        let string_dnsname = lookup_addr(&inet_addr.ip().to_std())?;
-                        let dnsname = DNSNameRef::try_from_ascii_str(&string_dnsname)?;
-                        let connector = TlsConnector::from(Arc::clone(&tls_config_ref));
-                        IoStream::from(TlsStream::from(
-                            connector.connect(dnsname, to_tcp_conn).await?,
-                        ))
-                    }
-                };
-
-                println!("connection opened to {}", to_addr);
-                let (client_read, client_write) = split::<IoStream>(IoStream::from(from_conn));
-                let (server_read, server_write) = split::<IoStream>(to_conn);
+                let dest_addr = inet_addr.to_std();
+                println!("connection destined to {}", dest_addr);
 
+                let compression = compression.clone();
+                let tls_client = tls_client.clone();
+                // Waits on the client and the reverse proxy, so it happens here rather than in the
+                // accept loop
                 tokio::spawn(async move {
-                    proxy_conn(
-                        client_read,
-                        server_write,
-                        if compress {
-                            Some(Direction::Compress)
-                        } else {
-                            None
-                        },
-                    )
-                    .await;
-                });
-                tokio::spawn(async move {
-                    proxy_conn(
-                        server_read,
-                        client_write,
-                        if compress {
-                            Some(Direction::Decompress)
-                        } else {
-                            None
-                        },
+                    proxy_intercepted(
+                        from_conn,
+                        from_addr,
+                        dest_addr,
+                        &compression,
+                        tls_client.as_deref(),
                     )
-                    .await;
+                    .await
                 });
             }
             _ => eprintln!("Failed to get destination address"),
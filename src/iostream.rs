@@ -5,6 +5,7 @@ use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
 
 /// Wrapper for asynchronous network IO streams
+#[allow(clippy::large_enum_variant)]
 pub enum IoStream {
     TcpStream(TcpStream),
     TlsStream(TlsStream<TcpStream>),
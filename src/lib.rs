@@ -1,9 +1,17 @@
+pub mod backends;
+pub mod client_identity;
 pub mod compression;
 pub mod forward_proxy;
 mod iostream;
+pub mod negotiation;
 mod proxy_common;
 pub mod reverse_proxy;
+mod sniff;
+#[cfg(test)]
+mod test_util;
+pub mod tls;
 
+#[allow(unexpected_cfgs)]
 pub mod errors {
     error_chain::error_chain! {
         foreign_links {
@@ -26,26 +26,33 @@ use error_chain::bail;
 use error_chain::ChainedError;
 use rust_tls_proxy::errors::*;
 
+use rust_tls_proxy::backends::Backends;
+use rust_tls_proxy::client_identity::IdentityForwarding;
+use rust_tls_proxy::compression::{
+    ChecksumKind, CompressionSettings, Mode, Scheme, CHECKSUM_NAMES, DEFAULT_BROTLI_QUALITY,
+    MAX_BROTLI_QUALITY, MODE_NAMES, SCHEME_NAMES,
+};
+use rust_tls_proxy::tls::{
+    self, CertKeyPaths, CertsSettings, ClientAuth, ClientTlsSettings, PinMode, ServerTlsSettings,
+    SubjectAltName, TlsPolicy, TLS_POLICY_NAMES, TLS_VERSION_NAMES,
+};
 use rust_tls_proxy::{forward_proxy, reverse_proxy};
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 enum ServerSettings {
     Forward {
         addr: SocketAddr,
-        root_cert: PathBuf,
-        compress: bool,
-        encrypt: bool,
+        compression: Vec<CompressionSettings>,
+        tls: Option<ClientTlsSettings>,
     },
     Reverse {
         addr: SocketAddr,
-        server_ips: Vec<SocketAddr>,
-        cert_chain: PathBuf,
-        key: PathBuf,
-        compress: bool,
-        encrypt: bool,
+        backends: Backends,
+        compression: Vec<CompressionSettings>,
+        tls: Option<ServerTlsSettings>,
     },
 }
 
@@ -63,6 +70,282 @@ const REVERSE_PORT_HELP: &str = const_format::formatcp!(
     reverse_proxy::HTTPS_PORT
 );
 
+const SESSION_CACHE_SIZE_HELP: &str = const_format::formatcp!(
+    "Number of TLS sessions kept for resumption when using encryption, default {}. 0 disables \
+     resumption by session ID.",
+    tls::DEFAULT_SESSION_CACHE_SIZE
+);
+
+const CERT_DAYS_HELP: &str = const_format::formatcp!(
+    "Number of days the certificates are valid for, default {}.",
+    tls::DEFAULT_CERT_VALIDITY_DAYS
+);
+
+const BROTLI_QUALITY_HELP: &str = const_format::formatcp!(
+    "Brotli quality level from 0 to {}, default {}. Same as --compression-level brotli=<quality>.",
+    MAX_BROTLI_QUALITY,
+    DEFAULT_BROTLI_QUALITY
+);
+
+/// Parses an optional numeric argument
+fn parse_arg<T: std::str::FromStr>(sub_m: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match sub_m.value_of(name) {
+        Some(value) => Ok(Some(value.parse().map_err(|_| {
+            Error::from(format!("error parsing {} \"{}\"", name, value))
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the compression level for the scheme from the compression-level list, whose entries
+/// are either scheme=level or a bare level used by every scheme without its own entry.
+/// --brotli-quality takes precedence over both for brotli.
+fn compression_level(sub_m: &ArgMatches, scheme: Scheme) -> Result<Option<u32>> {
+    let parse_level = |value: &str| {
+        value
+            .parse::<u32>()
+            .map_err(|_| Error::from(format!("error parsing compression-level \"{}\"", value)))
+    };
+
+    let mut default_level = None;
+    let mut scheme_level = None;
+    for value in sub_m.values_of("compression-level").into_iter().flatten() {
+        match value.split_once('=') {
+            Some((name, level)) => {
+                let level = parse_level(level)?;
+                if name.parse::<Scheme>()? == scheme {
+                    scheme_level = Some(level);
+                }
+            }
+            None => default_level = Some(parse_level(value)?),
+        }
+    }
+    if scheme == Scheme::Brotli {
+        if let Some(quality) = parse_arg(sub_m, "brotli-quality")? {
+            return Ok(Some(quality));
+        }
+    }
+    Ok(scheme_level.or(default_level))
+}
+
+/// Returns the configured compression settings in order of preference, empty if compression is
+/// disabled
+fn compression_settings(sub_m: &ArgMatches) -> Result<Vec<CompressionSettings>> {
+    if !sub_m.is_present("compress") {
+        return Ok(vec![]);
+    }
+
+    let min_payload_size = parse_arg(sub_m, "min-compress-size")?.unwrap_or(0);
+    let mode = sub_m
+        .value_of("compression-mode")
+        .unwrap_or("independent")
+        .parse::<Mode>()?;
+    let checksum = match sub_m.value_of("checksum") {
+        Some(name) => Some(name.parse::<ChecksumKind>()?),
+        None => None,
+    };
+
+    sub_m
+        .values_of("compression-scheme")
+        .into_iter()
+        .flatten()
+        .map(|name| {
+            let scheme = name.parse::<Scheme>()?;
+            let mut settings = CompressionSettings::new(scheme);
+            settings.level = compression_level(sub_m, scheme)?;
+            settings.mode = mode;
+            settings.min_payload_size = min_payload_size;
+            settings.checksum = checksum;
+            settings.validate()?;
+            Ok(settings)
+        })
+        .collect()
+}
+
+/// Returns the TLS policy preset, with any lists given explicitly replacing the preset's own
+fn tls_policy(sub_m: &ArgMatches) -> Result<TlsPolicy> {
+    let mut policy: TlsPolicy = sub_m
+        .value_of("tls-policy")
+        .unwrap_or("compatible")
+        .parse()?;
+    if let Some(versions) = sub_m.values_of("tls-versions") {
+        policy.set_versions(versions)?;
+    }
+    if let Some(cipher_suites) = sub_m.values_of("cipher-suites") {
+        policy.set_cipher_suites(cipher_suites)?;
+    }
+    policy.validate().chain_err(|| "invalid TLS policy")?;
+    Ok(policy)
+}
+
+/// Returns the forward proxy's TLS settings, None if encryption is disabled
+fn client_tls_settings(sub_m: &ArgMatches) -> Result<Option<ClientTlsSettings>> {
+    if !sub_m.is_present("encrypt") {
+        return Ok(None);
+    }
+
+    let mut settings =
+        ClientTlsSettings::new(sub_m.value_of("root-cert").unwrap_or("certs/ca_cert.pem"));
+    if let Some(pins) = sub_m.values_of("pin") {
+        settings.pins = pins.collect::<Vec<_>>().join(",").parse()?;
+    }
+    if sub_m.is_present("pins-only") {
+        settings.pin_mode = PinMode::InsteadOfCa;
+        // Pinned reverse proxies don't need a CA, so the default root certs are only loaded if asked
+        settings.root_certs = sub_m.value_of("root-cert").map(PathBuf::from);
+    }
+    // clap makes sure the cert and key are given together
+    if let (Some(cert), Some(key)) = (sub_m.value_of("client-cert"), sub_m.value_of("client-key")) {
+        settings.client_cert = Some(CertKeyPaths::new(cert, key));
+    }
+    if let Some(server_names) = sub_m.values_of("server-name") {
+        settings.server_names = server_names.collect::<Vec<_>>().join(",").parse()?;
+    }
+    settings.alpn_protocols = alpn_protocols(sub_m);
+    if let Some(size) = parse_arg(sub_m, "session-cache-size")? {
+        settings.session_cache_size = size;
+    }
+    settings.session_tickets = !sub_m.is_present("no-session-tickets");
+    settings.policy = tls_policy(sub_m)?;
+    Ok(Some(settings))
+}
+
+fn parse_socket_addr(addr: &str) -> Result<SocketAddr> {
+    addr.parse()
+        .chain_err(|| format!("error parsing socket address \"{}\"", addr))
+}
+
+/// Returns the reverse proxy's backend servers, including the ones for ALPN protocols
+fn backends(sub_m: &ArgMatches) -> Result<Backends> {
+    let mut backends = match sub_m.values_of("SERVERS") {
+        Some(addrs) => Backends::new(addrs.map(parse_socket_addr).collect::<Result<_>>()?),
+        None => bail!("no server addreses"),
+    };
+    for route in sub_m.values_of("alpn-backend").into_iter().flatten() {
+        match route.split_once('=') {
+            Some((protocol, addr)) => {
+                backends.add_alpn_route(protocol.as_bytes(), vec![parse_socket_addr(addr)?])
+            }
+            None => bail!("expected protocol=ip:port, got \"{}\"", route),
+        }
+    }
+    if let Some(path) = sub_m.value_of("backend-tls-config") {
+        for (addr, settings) in tls::load_upstream_tls_config(Path::new(path))? {
+            backends
+                .set_upstream_tls(addr, settings)
+                .chain_err(|| format!("invalid backend TLS config {}", path))?;
+        }
+    }
+    for backend in sub_m.values_of("client-identity").into_iter().flatten() {
+        let (mode, addr) = match backend.split_once('=') {
+            Some(backend) => backend,
+            None => bail!("expected mode=ip:port, got \"{}\"", backend),
+        };
+        let mut forwarding = IdentityForwarding::from_mode(mode)?;
+        match &mut forwarding {
+            IdentityForwarding::ProxyProtocol(types) => {
+                for (field, tlv_type) in identity_fields(sub_m, "identity-tlvs")? {
+                    let tlv_type = match tlv_type.strip_prefix("0x") {
+                        Some(hex) => u8::from_str_radix(hex, 16),
+                        None => tlv_type.parse(),
+                    }
+                    .chain_err(|| format!("invalid TLV type \"{}\"", tlv_type))?;
+                    types.set(field, tlv_type)?;
+                }
+            }
+            IdentityForwarding::HttpHeaders(names) => {
+                for (field, name) in identity_fields(sub_m, "identity-headers")? {
+                    names.set(field, name)?;
+                }
+            }
+        }
+        backends.set_identity_forwarding(parse_socket_addr(addr)?, forwarding)?;
+    }
+    Ok(backends)
+}
+
+/// Returns the field=value pairs listed with --identity-headers or --identity-tlvs
+fn identity_fields<'a>(sub_m: &'a ArgMatches, name: &str) -> Result<Vec<(&'a str, &'a str)>> {
+    sub_m
+        .values_of(name)
+        .into_iter()
+        .flatten()
+        .map(|field| match field.split_once('=') {
+            Some(field) => Ok(field),
+            None => bail!("expected field=value, got \"{}\"", field),
+        })
+        .collect()
+}
+
+/// Returns the ALPN protocols listed with --alpn
+fn alpn_protocols(sub_m: &ArgMatches) -> Vec<Vec<u8>> {
+    sub_m
+        .values_of("alpn")
+        .into_iter()
+        .flatten()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect()
+}
+
+/// Returns the reverse proxy's TLS settings, None if encryption is disabled
+fn server_tls_settings(sub_m: &ArgMatches) -> Result<Option<ServerTlsSettings>> {
+    if !sub_m.is_present("encrypt") {
+        return Ok(None);
+    }
+
+    let mut cert = CertKeyPaths::new(
+        sub_m.value_of("cert-chain").unwrap_or("certs/cert.pem"),
+        sub_m.value_of("key").unwrap_or("certs/key.pem"),
+    );
+    cert.ocsp = sub_m.value_of("ocsp").map(PathBuf::from);
+    let mut settings = ServerTlsSettings::new(cert);
+    if let Some(sni_config) = sub_m.value_of("sni-config") {
+        settings.sni_certs = tls::load_sni_config(Path::new(sni_config))?;
+    }
+    if let Some(client_ca) = sub_m.value_of("client-ca") {
+        settings.client_auth = match sub_m.value_of("client-auth") {
+            Some("optional") => ClientAuth::Optional(client_ca.into()),
+            _ => ClientAuth::Required(client_ca.into()),
+        };
+    }
+    settings.alpn_protocols = alpn_protocols(sub_m);
+    if let Some(size) = parse_arg(sub_m, "session-cache-size")? {
+        settings.session_cache_size = size;
+    }
+    settings.session_tickets = !sub_m.is_present("no-session-tickets");
+    settings.policy = tls_policy(sub_m)?;
+    Ok(Some(settings))
+}
+
+/// Parses DNS names and IP addresses for a certificate
+fn subject_alt_names(sub_m: &ArgMatches, name: &str) -> Result<Vec<SubjectAltName>> {
+    sub_m
+        .values_of(name)
+        .into_iter()
+        .flatten()
+        .map(str::parse)
+        .collect()
+}
+
+/// Creates the certificates for a lab and prints where they were written
+fn write_certs(sub_m: &ArgMatches) -> Result<()> {
+    let mut settings = CertsSettings::new(sub_m.value_of("out-dir").unwrap_or("certs"));
+    if let Some(ca_name) = sub_m.value_of("ca-name") {
+        settings.ca_name = ca_name.to_string();
+    }
+    settings.server_names = subject_alt_names(sub_m, "server-names")?;
+    settings.client_names = subject_alt_names(sub_m, "client-names")?;
+    if let Some(days) = parse_arg(sub_m, "days")? {
+        settings.validity_days = days;
+    }
+    settings.overwrite = sub_m.is_present("force");
+
+    for path in tls::write_certs(&settings)? {
+        println!("wrote {}", path.display());
+    }
+    Ok(())
+}
+
 fn run() -> Result<()> {
     let m = App::new(APP_NAME)
         .about(ABOUT_STR)
@@ -83,12 +366,185 @@ fn run() -> Result<()> {
                         .default_value("certs/ca_cert.pem")
                         .help("Path to root certs to trust when using encryption."),
                 )
+                .arg(
+                    Arg::with_name("client-cert")
+                        .long("client-cert")
+                        .takes_value(true)
+                        .requires("client-key")
+                        .help(
+                            "Path to a certificate chain to present to reverse proxies that \
+                             request a client certificate when using encryption.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("client-key")
+                        .long("client-key")
+                        .takes_value(true)
+                        .requires("client-cert")
+                        .help("Path to the private key for --client-cert."),
+                )
+                .arg(
+                    Arg::with_name("server-name")
+                        .long("server-name")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help(
+                            "Name expected in the reverse proxy's certificate for destinations in \
+                             a network, as network=name, e.g. 10.0.0.0/24=proxy.lab. Can be \
+                             repeated or comma separated. Reverse DNS is used for other \
+                             destinations.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("pin")
+                        .long("pin")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help(
+                            "SHA-256 hash of the public key a reverse proxy must present, as \
+                             name=sha256//base64 for its server name. Can be repeated or comma \
+                             separated, and a name can have several pins.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("pins-only")
+                        .long("pins-only")
+                        .requires("pin")
+                        .help(
+                            "Trust pinned reverse proxies on their pin alone instead of also \
+                             verifying them against --root-cert. Other reverse proxies are still \
+                             verified against --root-cert if it is given.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("alpn")
+                        .long("alpn")
+                        .takes_value(true)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help(
+                            "Comma separated ALPN protocols to offer reverse proxies when using \
+                             encryption, in order of preference, e.g. h2,http/1.1.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("session-cache-size")
+                        .long("session-cache-size")
+                        .takes_value(true)
+                        .help(SESSION_CACHE_SIZE_HELP),
+                )
+                .arg(
+                    Arg::with_name("no-session-tickets")
+                        .long("no-session-tickets")
+                        .help(
+                            "Don't resume TLS sessions with session tickets. TLS 1.3 sessions \
+                             are then not resumed.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("tls-policy")
+                        .long("tls-policy")
+                        .takes_value(true)
+                        .possible_values(TLS_POLICY_NAMES)
+                        .default_value("compatible")
+                        .help(
+                            "TLS versions and cipher suites allowed between the proxies when \
+                             using encryption: modern allows TLS 1.3 only, compatible also allows \
+                             TLS 1.2. Both proxies need a policy in common.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("tls-versions")
+                        .long("tls-versions")
+                        .takes_value(true)
+                        .possible_values(TLS_VERSION_NAMES)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help("Comma separated TLS versions to allow instead of the policy's."),
+                )
+                .arg(
+                    Arg::with_name("cipher-suites")
+                        .long("cipher-suites")
+                        .takes_value(true)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help(
+                            "Comma separated IANA names of the cipher suites to allow instead of \
+                             the policy's, in order of preference, e.g. \
+                             TLS_AES_256_GCM_SHA384,TLS_AES_128_GCM_SHA256.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("compress")
                         .short("c")
                         .long("compress")
                         .help("enable compression"),
                 )
+                .arg(
+                    Arg::with_name("compression-scheme")
+                        .long("compression-scheme")
+                        .takes_value(true)
+                        .possible_values(SCHEME_NAMES)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .default_value("deflate")
+                        .help(
+                            "Comma separated compression schemes to negotiate when compression \
+                             is enabled, in order of preference.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("compression-level")
+                        .long("compression-level")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help(
+                            "Comma separated compression levels as scheme=level, for example \
+                             zstd=15,deflate=6. A bare level applies to every scheme without its \
+                             own entry. The ranges are 0-9 for deflate, 1-22 for zstd and 0-11 \
+                             for brotli. Defaults to each scheme's own default.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("brotli-quality")
+                        .long("brotli-quality")
+                        .takes_value(true)
+                        .help(BROTLI_QUALITY_HELP),
+                )
+                .arg(
+                    Arg::with_name("checksum")
+                        .long("checksum")
+                        .takes_value(true)
+                        .possible_values(CHECKSUM_NAMES)
+                        .help(
+                            "Add a checksum of the uncompressed data to each compressed frame so \
+                             corrupted frames are detected. Disabled by default.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("min-compress-size")
+                        .long("min-compress-size")
+                        .takes_value(true)
+                        .help(
+                            "Reads smaller than this many bytes are sent without being \
+                             compressed, default 0.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("compression-mode")
+                        .long("compression-mode")
+                        .takes_value(true)
+                        .possible_values(MODE_NAMES)
+                        .default_value("independent")
+                        .help(
+                            "Compress each read independently, or as one stream per connection \
+                             that keeps its dictionary across reads. lz4 always uses independent \
+                             frames.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("encrypt")
                         .short("e")
@@ -122,23 +578,296 @@ fn run() -> Result<()> {
                         .default_value("certs/key.pem")
                         .help("Path to private key to use for encryption."),
                 )
+                .arg(Arg::with_name("ocsp").long("ocsp").takes_value(true).help(
+                    "Path to a DER OCSP response for --cert-chain to staple in handshakes. \
+                             It is reloaded when the file changes, and a warning is logged when it \
+                             gets within a day of its nextUpdate time.",
+                ))
+                .arg(
+                    Arg::with_name("sni-config")
+                        .long("sni-config")
+                        .takes_value(true)
+                        .help(
+                            "Path to a JSON file mapping host names requested with SNI to the \
+                             cert chain and key to present for them. Other connections get \
+                             --cert-chain and --key.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("alpn")
+                        .long("alpn")
+                        .takes_value(true)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help(
+                            "Comma separated ALPN protocols to accept from forward proxies when \
+                             using encryption, in order of preference, e.g. h2,http/1.1.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("alpn-backend")
+                        .long("alpn-backend")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "Forward connections that negotiated an ALPN protocol to a different \
+                             server, as protocol=ip:port, e.g. h2=10.0.0.5:8443. Can be repeated.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("client-identity")
+                        .long("client-identity")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "Pass the subject, SANs and SHA-256 fingerprint of forward proxies' \
+                             client certs to a backend server, as mode=ip:port. proxy-v2 sends a \
+                             PROXY protocol v2 header with TLVs, http adds headers to each \
+                             HTTP/1 request. Can be repeated.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("identity-headers")
+                        .long("identity-headers")
+                        .takes_value(true)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help(
+                            "Header names for --client-identity http backends, as field=name \
+                             with fields subject, sans and fingerprint. Defaults to \
+                             X-Client-Cert-Subject, X-Client-Cert-SAN and \
+                             X-Client-Cert-Fingerprint.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("identity-tlvs")
+                        .long("identity-tlvs")
+                        .takes_value(true)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help(
+                            "TLV types for --client-identity proxy-v2 backends, as field=type \
+                             with fields subject, sans and fingerprint and types from 0xe0 to \
+                             0xef. Defaults to 0xe0, 0xe1 and 0xe2.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("backend-tls-config")
+                        .long("backend-tls-config")
+                        .takes_value(true)
+                        .help(
+                            "Path to a JSON file mapping backend servers (ip:port) to the server \
+                             name, root certs and optional client cert to connect to them over \
+                             TLS with. Other backends are connected to over plain TCP.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("client-ca")
+                        .long("client-ca")
+                        .takes_value(true)
+                        .help(
+                            "Path to CA certs used to verify forward proxy client certificates \
+                             when using encryption. Client certificates aren't requested if not \
+                             set.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("client-auth")
+                        .long("client-auth")
+                        .takes_value(true)
+                        .possible_values(&["required", "optional"])
+                        .requires("client-ca")
+                        .help(
+                            "Whether forward proxies must present a client certificate signed by \
+                             --client-ca, or may connect without one. Default required.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("session-cache-size")
+                        .long("session-cache-size")
+                        .takes_value(true)
+                        .help(SESSION_CACHE_SIZE_HELP),
+                )
+                .arg(
+                    Arg::with_name("no-session-tickets")
+                        .long("no-session-tickets")
+                        .help(
+                            "Don't issue TLS session tickets. Sessions are then only resumed \
+                             from the session cache. Resumption hits and misses are logged on \
+                             SIGUSR1.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("tls-policy")
+                        .long("tls-policy")
+                        .takes_value(true)
+                        .possible_values(TLS_POLICY_NAMES)
+                        .default_value("compatible")
+                        .help(
+                            "TLS versions and cipher suites allowed between the proxies when \
+                             using encryption: modern allows TLS 1.3 only, compatible also allows \
+                             TLS 1.2. Both proxies need a policy in common.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("tls-versions")
+                        .long("tls-versions")
+                        .takes_value(true)
+                        .possible_values(TLS_VERSION_NAMES)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help("Comma separated TLS versions to allow instead of the policy's."),
+                )
+                .arg(
+                    Arg::with_name("cipher-suites")
+                        .long("cipher-suites")
+                        .takes_value(true)
+                        .multiple(true)
+                        .require_delimiter(true)
+                        .help(
+                            "Comma separated IANA names of the cipher suites to allow instead of \
+                             the policy's, in order of preference, e.g. \
+                             TLS_AES_256_GCM_SHA384,TLS_AES_128_GCM_SHA256.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("compress")
                         .short("c")
                         .long("compress")
                         .help("enable compression"),
                 )
+                .arg(
+                    Arg::with_name("compression-scheme")
+                        .long("compression-scheme")
+                        .takes_value(true)
+                        .possible_values(SCHEME_NAMES)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .default_value("deflate")
+                        .help(
+                            "Comma separated compression schemes to negotiate when compression \
+                             is enabled, in order of preference.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("compression-level")
+                        .long("compression-level")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help(
+                            "Comma separated compression levels as scheme=level, for example \
+                             zstd=15,deflate=6. A bare level applies to every scheme without its \
+                             own entry. The ranges are 0-9 for deflate, 1-22 for zstd and 0-11 \
+                             for brotli. Defaults to each scheme's own default.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("brotli-quality")
+                        .long("brotli-quality")
+                        .takes_value(true)
+                        .help(BROTLI_QUALITY_HELP),
+                )
+                .arg(
+                    Arg::with_name("checksum")
+                        .long("checksum")
+                        .takes_value(true)
+                        .possible_values(CHECKSUM_NAMES)
+                        .help(
+                            "Add a checksum of the uncompressed data to each compressed frame so \
+                             corrupted frames are detected. Disabled by default.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("min-compress-size")
+                        .long("min-compress-size")
+                        .takes_value(true)
+                        .help(
+                            "Reads smaller than this many bytes are sent without being \
+                             compressed, default 0.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("compression-mode")
+                        .long("compression-mode")
+                        .takes_value(true)
+                        .possible_values(MODE_NAMES)
+                        .default_value("independent")
+                        .help(
+                            "Compress each read independently, or as one stream per connection \
+                             that keeps its dictionary across reads. lz4 always uses independent \
+                             frames.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("encrypt")
                         .short("e")
                         .long("encrypt")
                         .help("enable encryption"),
                 ),
+            SubCommand::with_name("certs")
+                .about(
+                    "create a CA and certificates for both proxies, as PEM files that the \
+                     default --root-cert, --cert-chain, --key, --client-ca, --client-cert and \
+                     --client-key paths point to",
+                )
+                .arg(
+                    Arg::with_name("out-dir")
+                        .long("out-dir")
+                        .default_value("certs")
+                        .help("Directory to write the PEM files to."),
+                )
+                .arg(
+                    Arg::with_name("ca-name")
+                        .long("ca-name")
+                        .takes_value(true)
+                        .help("Common name of the CA, default \"Rust TLS Proxy CA\"."),
+                )
+                .arg(
+                    Arg::with_name("server-names")
+                        .long("server-names")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .default_value("localhost")
+                        .help(
+                            "Comma separated DNS names and IP addresses the reverse proxy \
+                             certificate is valid for, e.g. proxy.lab,10.0.0.5. The first is also \
+                             its common name.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("client-names")
+                        .long("client-names")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .default_value("forward-proxy")
+                        .help(
+                            "Comma separated DNS names and IP addresses in the forward proxy's \
+                             client certificate. The first is also its common name.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("days")
+                        .long("days")
+                        .takes_value(true)
+                        .help(CERT_DAYS_HELP),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Replace existing files, including the CA and its key."),
+                ),
         ])
         .get_matches_safe()
         .chain_err(|| "error parsing arguments")?;
 
     let server = match m.subcommand() {
+        ("certs", Some(sub_m)) => return write_certs(sub_m),
+
         ("forward", Some(sub_m)) => ServerSettings::Forward {
             addr: {
                 let port = match sub_m.value_of("port") {
@@ -150,11 +879,8 @@ fn run() -> Result<()> {
 
                 SocketAddr::from((IpAddr::from([0, 0, 0, 0]), port))
             },
-            root_cert: [sub_m.value_of("root-cert").unwrap_or("certs/ca_cert.pem")]
-                .iter()
-                .collect(),
-            compress: sub_m.is_present("compress"),
-            encrypt: sub_m.is_present("encrypt"),
+            compression: compression_settings(sub_m)?,
+            tls: client_tls_settings(sub_m)?,
         },
 
         ("reverse", Some(sub_m)) => ServerSettings::Reverse {
@@ -169,59 +895,36 @@ fn run() -> Result<()> {
                 SocketAddr::from((IpAddr::from([0, 0, 0, 0]), port))
             },
 
-            server_ips: match sub_m.values_of("SERVERS") {
-                Some(addrs) => addrs
-                    .map(|a| {
-                        a.parse::<SocketAddr>()
-                            .chain_err(|| format!("error parsing socket address \"{}\"", a))
-                    })
-                    .collect::<Result<_>>()?,
-                None => bail!("no server addreses"),
-            },
-            cert_chain: [sub_m.value_of("cert-chain").unwrap_or("certs/cert.pem")]
-                .iter()
-                .collect(),
-            key: [sub_m.value_of("key").unwrap_or("certs/key.pem")]
-                .iter()
-                .collect(),
-            compress: sub_m.is_present("compress"),
-            encrypt: sub_m.is_present("encrypt"),
+            backends: backends(sub_m)?,
+            compression: compression_settings(sub_m)?,
+            tls: server_tls_settings(sub_m)?,
         },
 
         _ => bail!("unknown subcommand"),
     };
 
-    return match server {
+    match server {
         ServerSettings::Forward {
             addr,
-            compress,
-            encrypt,
-            root_cert,
-        } => forward_proxy::run(addr, compress, encrypt, Some(&root_cert))
-            .chain_err(|| "error in forward_proxy::run()"),
+            compression,
+            tls,
+        } => {
+            forward_proxy::run(addr, compression, tls).chain_err(|| "error in forward_proxy::run()")
+        }
 
         ServerSettings::Reverse {
             addr,
-            server_ips,
-            compress,
-            encrypt,
-            cert_chain,
-            key,
-        } => reverse_proxy::run(
-            addr,
-            server_ips,
-            compress,
-            encrypt,
-            Some(&cert_chain),
-            Some(&key),
-        )
-        .chain_err(|| "error in reverse_proxy::run()"),
-    };
+            backends,
+            compression,
+            tls,
+        } => reverse_proxy::run(addr, backends, compression, tls)
+            .chain_err(|| "error in reverse_proxy::run()"),
+    }
 }
 
 fn main() {
     if let Err(e) = run() {
-        eprintln!("{}", e.display_chain().to_string());
+        eprintln!("{}", e.display_chain());
         std::process::exit(1);
     }
 }
@@ -0,0 +1,364 @@
+// In-band negotiation of the compression scheme used between the forward and reverse proxies.
+//
+// When compression is enabled, the forward proxy starts each connection by sending an offer listing
+// the schemes it supports in order of preference:
+//      magic (4 bytes) | OFFER_TYPE (1 byte) | count (1 byte) | schemes (count bytes)
+// and waits for the reverse proxy to select one of them, or none:
+//      magic (4 bytes) | SELECT_TYPE (1 byte) | scheme (1 byte, NO_COMPRESSION for none)
+//
+// The reverse proxy always checks whether a connection starts with an offer. Connections that
+// don't are proxied without compression, so a forward proxy with compression disabled doesn't send
+// anything and works with any reverse proxy.
+
+use crate::compression::{CompressionSettings, Scheme};
+use crate::errors::*;
+use error_chain::bail;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Magic value that starts every negotiation message. The first two bytes match the compression
+/// header magic value, but 0xc0 is never a valid compression header version.
+pub const NEGOTIATION_MAGIC_VALUE: u32 = 0xbeef_c0de;
+const OFFER_TYPE: u8 = 1;
+const SELECT_TYPE: u8 = 2;
+/// Scheme value used to select no compression. The null method value from IETF RFC 3749 (0) is
+/// already taken by Scheme::Stored, so this uses the last private use value, which no scheme uses.
+const NO_COMPRESSION: u8 = 0xff;
+/// Size of the magic value and message type
+const PREAMBLE_SIZE: usize = 5;
+
+/// How long the reverse proxy waits for the start of an offer before treating the connection as one
+/// that doesn't negotiate. Bounds the delay for protocols where the server speaks first. The forward
+/// proxy gives up on a connection if no selection arrives within the same time.
+pub const NEGOTIATION_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn offer_to_bytes(schemes: &[Scheme]) -> Result<Vec<u8>> {
+    if schemes.len() > u8::MAX as usize {
+        bail!("Too many compression schemes to offer: {}", schemes.len());
+    }
+
+    let mut bytes = NEGOTIATION_MAGIC_VALUE.to_be_bytes().to_vec();
+    bytes.push(OFFER_TYPE);
+    bytes.push(schemes.len() as u8);
+    for scheme in schemes {
+        bytes.extend(
+            scheme
+                .to_bytes()
+                .ok_or("Could not convert scheme to bytes")?,
+        );
+    }
+    Ok(bytes)
+}
+
+fn selection_to_bytes(scheme: Option<Scheme>) -> Result<Vec<u8>> {
+    let mut bytes = NEGOTIATION_MAGIC_VALUE.to_be_bytes().to_vec();
+    bytes.push(SELECT_TYPE);
+    match scheme {
+        Some(scheme) => bytes.extend(
+            scheme
+                .to_bytes()
+                .ok_or("Could not convert scheme to bytes")?,
+        ),
+        None => bytes.push(NO_COMPRESSION),
+    }
+    Ok(bytes)
+}
+
+/// Returns true if the buffer matches the start of a message of the given type
+fn is_message_prefix(buf: &[u8], message_type: u8) -> bool {
+    let mut preamble = NEGOTIATION_MAGIC_VALUE.to_be_bytes().to_vec();
+    preamble.push(message_type);
+    let len = buf.len().min(preamble.len());
+    buf[..len] == preamble[..len]
+}
+
+/// Returns whether the data starts with a compression offer, None if more data is needed to tell
+pub fn is_offer(data: &[u8]) -> Option<bool> {
+    if !is_message_prefix(data, OFFER_TYPE) {
+        Some(false)
+    } else if data.len() >= PREAMBLE_SIZE {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// Offers the configured compression schemes to the reverse proxy and returns the settings for the
+/// scheme it selects. Nothing is sent if no schemes are configured.
+pub async fn offer<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    compression: &[CompressionSettings],
+) -> Result<Option<CompressionSettings>> {
+    if compression.is_empty() {
+        return Ok(None);
+    }
+
+    let schemes: Vec<Scheme> = compression.iter().map(|settings| settings.scheme).collect();
+    conn.write_all(&offer_to_bytes(&schemes)?)
+        .await
+        .chain_err(|| "error sending compression offer")?;
+
+    let mut selection = [0; PREAMBLE_SIZE + 1];
+    match tokio::time::timeout(NEGOTIATION_TIMEOUT, conn.read_exact(&mut selection)).await {
+        Ok(result) => result.chain_err(|| "error receiving compression selection")?,
+        Err(_) => bail!("Timed out waiting for a compression selection from the reverse proxy"),
+    };
+    if !is_message_prefix(&selection, SELECT_TYPE) {
+        bail!("Did not receive a compression selection from the reverse proxy");
+    }
+
+    let scheme_byte = selection[PREAMBLE_SIZE];
+    if scheme_byte == NO_COMPRESSION {
+        return Ok(None);
+    }
+    match compression
+        .iter()
+        .find(|settings| settings.scheme.to_bytes() == Some(vec![scheme_byte]))
+    {
+        Some(settings) => Ok(Some(*settings)),
+        None => bail!(
+            "Reverse proxy selected compression scheme {} which was not offered",
+            scheme_byte
+        ),
+    }
+}
+
+/// Outcome of checking a connection for a compression offer
+#[derive(PartialEq, Debug)]
+pub struct Accepted {
+    /// Settings for the selected scheme, None if the connection won't be compressed
+    pub compression: Option<CompressionSettings>,
+    /// Data read while looking for an offer that turned out to be part of the proxied stream. It
+    /// should be forwarded before anything else read from the connection.
+    pub initial_data: Vec<u8>,
+}
+
+/// Checks whether the connection starts with a compression offer, and if so replies with the first
+/// offered scheme that is also configured here.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    compression: &[CompressionSettings],
+) -> Result<Accepted> {
+    let not_negotiated = |initial_data: Vec<u8>| Accepted {
+        compression: None,
+        initial_data,
+    };
+
+    // Only read as many bytes as the next part of the offer needs, so no data following the offer
+    // is consumed
+    let mut buf = Vec::new();
+    let mut needed = PREAMBLE_SIZE + 1;
+    while buf.len() < needed {
+        let mut chunk = vec![0; needed - buf.len()];
+        let n = match tokio::time::timeout(NEGOTIATION_TIMEOUT, conn.read(&mut chunk)).await {
+            Ok(result) => result.chain_err(|| "error reading compression offer")?,
+            Err(_) => return Ok(not_negotiated(buf)),
+        };
+        if n == 0 {
+            return Ok(not_negotiated(buf));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        if !is_message_prefix(&buf, OFFER_TYPE) {
+            return Ok(not_negotiated(buf));
+        }
+        if buf.len() > PREAMBLE_SIZE {
+            needed = PREAMBLE_SIZE + 1 + buf[PREAMBLE_SIZE] as usize;
+        }
+    }
+
+    let offered = &buf[PREAMBLE_SIZE + 1..];
+    // Offered schemes we don't know about are skipped
+    let selected = offered
+        .iter()
+        .filter_map(|byte| Scheme::from_bytes(&[*byte]))
+        .find_map(|scheme| {
+            compression
+                .iter()
+                .find(|settings| settings.scheme == scheme)
+                .copied()
+        });
+
+    conn.write_all(&selection_to_bytes(
+        selected.map(|settings| settings.scheme),
+    )?)
+    .await
+    .chain_err(|| "error sending compression selection")?;
+
+    Ok(Accepted {
+        compression: selected,
+        initial_data: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{CompressionSettings, Scheme};
+    use crate::negotiation::{
+        accept, is_offer, offer, offer_to_bytes, selection_to_bytes, Accepted, NO_COMPRESSION,
+    };
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn negotiate_first_common_scheme() {
+        let (mut forward, mut reverse) = duplex(64);
+        let forward_settings = [
+            CompressionSettings::new(Scheme::Zstd),
+            CompressionSettings::new(Scheme::Deflate),
+        ];
+        let mut brotli = CompressionSettings::new(Scheme::Brotli);
+        brotli.level = Some(9);
+        let reverse_settings = [
+            CompressionSettings::new(Scheme::Deflate),
+            CompressionSettings::new(Scheme::Zstd),
+            brotli,
+        ];
+
+        let (offered, accepted) = tokio::join!(
+            offer(&mut forward, &forward_settings),
+            accept(&mut reverse, &reverse_settings)
+        );
+
+        assert_eq!(
+            offered.unwrap(),
+            Some(CompressionSettings::new(Scheme::Zstd))
+        );
+        assert_eq!(
+            accepted.unwrap(),
+            Accepted {
+                compression: Some(CompressionSettings::new(Scheme::Zstd)),
+                initial_data: Vec::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn negotiate_no_common_scheme() {
+        let (mut forward, mut reverse) = duplex(64);
+        let forward_settings = [CompressionSettings::new(Scheme::Lz4)];
+        let reverse_settings = [CompressionSettings::new(Scheme::Deflate)];
+
+        let (offered, accepted) = tokio::join!(
+            offer(&mut forward, &forward_settings),
+            accept(&mut reverse, &reverse_settings)
+        );
+
+        assert_eq!(offered.unwrap(), None);
+        assert_eq!(accepted.unwrap().compression, None);
+    }
+
+    #[tokio::test]
+    async fn negotiate_reverse_compression_disabled() {
+        let (mut forward, mut reverse) = duplex(64);
+        let forward_settings = [CompressionSettings::new(Scheme::Deflate)];
+
+        let (offered, accepted) = tokio::join!(
+            offer(&mut forward, &forward_settings),
+            accept(&mut reverse, &[])
+        );
+
+        assert_eq!(offered.unwrap(), None);
+        assert_eq!(accepted.unwrap().compression, None);
+    }
+
+    #[tokio::test]
+    async fn accept_without_offer_returns_data() {
+        let (mut client, mut reverse) = duplex(64);
+        let message = b"GET / HTTP/1.1\r\n\r\n";
+        client.write_all(message).await.unwrap();
+
+        let accepted = accept(&mut reverse, &[CompressionSettings::new(Scheme::Deflate)])
+            .await
+            .unwrap();
+
+        assert_eq!(accepted.compression, None);
+        // Only the first bytes are read before deciding the connection isn't negotiating
+        assert!(message.starts_with(&accepted.initial_data));
+        assert!(!accepted.initial_data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn accept_times_out_without_data() {
+        let (_client, mut reverse) = duplex(64);
+
+        let accepted = accept(&mut reverse, &[CompressionSettings::new(Scheme::Deflate)])
+            .await
+            .unwrap();
+
+        assert_eq!(accepted.compression, None);
+        assert!(accepted.initial_data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn accept_does_not_consume_data_after_offer() {
+        let (mut forward, mut reverse) = duplex(64);
+        let offer_bytes = offer_to_bytes(&[Scheme::Deflate]).unwrap();
+        // Send the offer one byte at a time followed by stream data
+        for byte in offer_bytes {
+            forward.write_all(&[byte]).await.unwrap();
+        }
+        forward.write_all(b"after").await.unwrap();
+
+        let accepted = accept(&mut reverse, &[CompressionSettings::new(Scheme::Deflate)])
+            .await
+            .unwrap();
+        let mut remaining = [0; 5];
+        reverse.read_exact(&mut remaining).await.unwrap();
+
+        assert_eq!(
+            accepted.compression,
+            Some(CompressionSettings::new(Scheme::Deflate))
+        );
+        assert_eq!(&remaining, b"after");
+    }
+
+    #[tokio::test]
+    async fn offer_times_out_without_selection() {
+        let (mut forward, _reverse) = duplex(64);
+
+        assert!(
+            offer(&mut forward, &[CompressionSettings::new(Scheme::Deflate)])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn offer_nothing_when_disabled() {
+        let (mut forward, mut reverse) = duplex(64);
+
+        assert_eq!(offer(&mut forward, &[]).await.unwrap(), None);
+        drop(forward);
+
+        let mut received = Vec::new();
+        reverse.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn no_compression_is_not_a_scheme() {
+        assert_eq!(Scheme::from_bytes(&[NO_COMPRESSION]), None);
+        for scheme in [
+            Scheme::Stored,
+            Scheme::Deflate,
+            Scheme::Zstd,
+            Scheme::Brotli,
+            Scheme::Lz4,
+        ] {
+            assert_ne!(
+                selection_to_bytes(Some(scheme)).unwrap(),
+                selection_to_bytes(None).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn recognize_offer() {
+        let offer = offer_to_bytes(&[Scheme::Zstd]).unwrap();
+        assert_eq!(is_offer(&offer), Some(true));
+        assert_eq!(is_offer(&offer[..5]), Some(true));
+        assert_eq!(is_offer(&offer[..3]), None);
+        assert_eq!(is_offer(b"GET / HTTP/1.1"), Some(false));
+        assert_eq!(is_offer(&[0xbe, 0xef, 0x01]), Some(false));
+    }
+}
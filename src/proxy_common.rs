@@ -1,51 +1,100 @@
-use crate::compression::{split_frames, Compressor, Decompressor, Direction};
+use crate::client_identity::HeaderInjector;
+use crate::compression::{
+    ChecksumMismatch, CompressionSettings, Compressor, Direction, FrameReassembler, Mode,
+    StreamCompressor, StreamDecompressor,
+};
 use crate::iostream::IoStream;
+use std::borrow::Cow;
 use std::io::Write;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 
+/// Proxies data from the read connection to the write connection until either is closed or an error
+/// occurs. If `headers` is set, the data is HTTP requests that get the client's identity headers
+/// once decompressed. `description` identifies the connection in log messages.
 pub async fn proxy_conn(
     mut read_conn: ReadHalf<IoStream>,
     mut write_conn: WriteHalf<IoStream>,
     compress_direction: Option<Direction>,
+    mut headers: Option<HeaderInjector>,
+    description: &str,
 ) {
     let mut buf = vec![0; 1024];
+    // Compression frames can be split across reads, so hold on to partial frames until they are
+    // complete
+    let mut reassembler = FrameReassembler::new();
+    // Streaming frames are decompressed with the same decoder for the whole connection
+    let mut decompressor = StreamDecompressor::new();
+    // Schemes that don't support streaming fall back to independent frames
+    let mut stream_compressor = match compress_direction {
+        Some(Direction::Compress(settings))
+            if settings.mode == Mode::Streaming && settings.scheme.supports_streaming() =>
+        {
+            match StreamCompressor::new(settings) {
+                Ok(compressor) => Some(compressor),
+                Err(e) => {
+                    eprintln!("{}: compression error: {}", description, e);
+                    let _ = write_conn.shutdown().await;
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
 
     loop {
         // proxy from the read connection to the write connection
         match read_conn.read(&mut buf).await {
             Ok(0) => {
-                println!("Read connection closed");
+                println!("{}: read connection closed", description);
+                if let Some(Direction::Decompress) = compress_direction {
+                    match reassembler.finish().and_then(|frame| match frame {
+                        Some(frame) => decompressor.decompress_frame(&frame),
+                        None => Ok(vec![]),
+                    }) {
+                        Ok(decomp_buf) => {
+                            if let Some(decomp_buf) =
+                                inject_headers(&mut headers, &decomp_buf, description)
+                            {
+                                if write_conn.write_all(&decomp_buf).await.is_err() {
+                                    eprintln!("{}: error sending to write connection", description);
+                                }
+                            }
+                        }
+                        Err(e) => log_decompression_error(description, &e),
+                    }
+                }
                 break;
             }
             Ok(n) => {
-                // TODO: cleanup match statements
                 let comp_buf = match compress_direction {
                     Some(Direction::Decompress) => {
-                        // TODO: add graceful error handling
-                        let decomp_frames = split_frames(&buf[..n]);
-                        decomp_frames
-                            .iter()
-                            .flat_map(|frame| {
-                                let mut decomp = Decompressor::new(Vec::new());
-                                decomp.write_all(frame).expect("Decompression error");
-                                decomp.finish().expect("Decompression error")
-                            })
-                            .collect()
-                    }
-                    Some(Direction::Compress) => {
-                        let compressed_buf = Vec::new();
-                        let mut comp = Compressor::new(compressed_buf);
-                        match comp.write_all(&buf[..n]) {
-                            Err(e) => {
-                                eprintln!("Compression error: {}", e.to_string());
-                                break;
+                        reassembler.push(&buf[..n]);
+                        let mut decomp_buf = Vec::new();
+                        if let Err(e) = decompress_available(
+                            &mut reassembler,
+                            &mut decompressor,
+                            &mut decomp_buf,
+                        ) {
+                            // Frames before the bad one were intact, so still forward them
+                            if let Some(decomp_buf) =
+                                inject_headers(&mut headers, &decomp_buf, description)
+                            {
+                                let _ = write_conn.write_all(&decomp_buf).await;
                             }
-                            _ => (),
+                            log_decompression_error(description, &e);
+                            break;
+                        }
+                        decomp_buf
+                    }
+                    Some(Direction::Compress(settings)) => {
+                        let compressed = match stream_compressor.as_mut() {
+                            Some(compressor) => compressor.compress_frame(&buf[..n]),
+                            None => compress_frame(settings, &buf[..n]),
                         };
-                        match comp.finish() {
+                        match compressed {
                             Ok(comp_buf) => comp_buf,
                             Err(e) => {
-                                eprintln!("Compression error: {}", e.to_string());
+                                eprintln!("{}: compression error: {}", description, e);
                                 break;
                             }
                         }
@@ -57,14 +106,18 @@ pub async fn proxy_conn(
                     Some(_) => &comp_buf,
                     None => &buf[..n],
                 };
+                let write_buffer = match inject_headers(&mut headers, write_buffer, description) {
+                    Some(write_buffer) => write_buffer,
+                    None => break,
+                };
 
-                if let Err(_) = write_conn.write_all(write_buffer).await {
-                    eprintln!("Error sending to write connection");
+                if write_conn.write_all(&write_buffer).await.is_err() {
+                    eprintln!("{}: error sending to write connection", description);
                     break;
                 }
             }
-            Err(_) => {
-                eprintln!("Socket error");
+            Err(e) => {
+                eprintln!("{}: socket error: {}", description, e);
                 break;
             }
         }
@@ -73,13 +126,67 @@ pub async fn proxy_conn(
     let _ = write_conn.shutdown().await;
 }
 
+/// Passes data sent to an HTTP backend through the header injector, if there is one. Returns None
+/// and logs the error if the data isn't HTTP requests, which closes the connection.
+fn inject_headers<'a>(
+    headers: &mut Option<HeaderInjector>,
+    data: &'a [u8],
+    description: &str,
+) -> Option<Cow<'a, [u8]>> {
+    match headers {
+        Some(headers) => match headers.push(data) {
+            Ok(data) => Some(Cow::Owned(data)),
+            Err(e) => {
+                eprintln!("{}: can't add identity headers: {}", description, e);
+                None
+            }
+        },
+        None => Some(Cow::Borrowed(data)),
+    }
+}
+
+/// Logs an error that closed the connection while decompressing. Checksum mismatches mean the data
+/// was corrupted somewhere between the proxies, so they are reported separately from other errors.
+fn log_decompression_error(description: &str, error: &std::io::Error) {
+    match ChecksumMismatch::from_io_error(error) {
+        Some(mismatch) => eprintln!(
+            "{}: corrupted compression frame, closing connection: {}",
+            description, mismatch
+        ),
+        None => eprintln!("{}: decompression error: {}", description, error),
+    }
+}
+
+/// Compresses the data into an independent frame
+fn compress_frame(settings: CompressionSettings, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut comp = Compressor::with_settings(Vec::new(), settings)?;
+    comp.write_all(data)?;
+    comp.finish()
+}
+
+/// Decompresses every complete frame currently held by the reassembler into decomp_buf. On error,
+/// decomp_buf holds the output of the frames before the one that failed.
+fn decompress_available(
+    reassembler: &mut FrameReassembler,
+    decompressor: &mut StreamDecompressor,
+    decomp_buf: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    while let Some(frame) = reassembler.next_frame()? {
+        decomp_buf.extend(decompressor.decompress_frame(&frame)?);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::compression::{split_frames, Compressor, Decompressor, Direction};
+    use crate::compression::{
+        split_frames, ChecksumKind, CompressionSettings, Compressor, Decompressor, Direction, Mode,
+        Scheme,
+    };
     use crate::iostream::IoStream;
     use crate::proxy_common::proxy_conn;
+    use crate::test_util::incompressible;
     use std::io::Write;
-    use tokio;
     use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
 
@@ -109,7 +216,14 @@ mod tests {
         let (_, out_send_write) = split::<IoStream>(IoStream::from(out_send_conn));
 
         tokio::spawn(async move {
-            proxy_conn(in_recv_read, out_send_write, compress_direction).await;
+            proxy_conn(
+                in_recv_read,
+                out_send_write,
+                compress_direction,
+                None,
+                "test",
+            )
+            .await;
         });
 
         TestProxy {
@@ -125,7 +239,7 @@ mod tests {
 
         let mut test_proxy = setup_proxy(None).await;
 
-        test_proxy.reader.write_all(&message).await.unwrap();
+        test_proxy.reader.write_all(message).await.unwrap();
         test_proxy.reader.shutdown().await.unwrap();
         test_proxy.writer.read_to_end(&mut received).await.unwrap();
 
@@ -137,19 +251,42 @@ mod tests {
         let message = "Hello world! This is message should be proxied and compressed.".as_bytes();
         let mut received = Vec::new();
 
-        let mut test_proxy = setup_proxy(Some(Direction::Compress)).await;
+        let mut test_proxy = setup_proxy(Some(Direction::Compress(CompressionSettings::new(
+            Scheme::Deflate,
+        ))))
+        .await;
 
-        test_proxy.reader.write_all(&message).await.unwrap();
+        test_proxy.reader.write_all(message).await.unwrap();
         test_proxy.reader.shutdown().await.unwrap();
         test_proxy.writer.read_to_end(&mut received).await.unwrap();
 
         let expected_message = Vec::new();
         let mut ref_compressor = Compressor::new(expected_message);
-        ref_compressor.write_all(&message).unwrap();
+        ref_compressor.write_all(message).unwrap();
 
         assert_eq!(received, ref_compressor.finish().unwrap());
     }
 
+    #[tokio::test]
+    async fn proxy_zstd_compressed_content() {
+        let message = "Hello world! This is message should be proxied and compressed.".as_bytes();
+        let mut received = Vec::new();
+
+        let mut test_proxy = setup_proxy(Some(Direction::Compress(CompressionSettings::new(
+            Scheme::Zstd,
+        ))))
+        .await;
+
+        test_proxy.reader.write_all(message).await.unwrap();
+        test_proxy.reader.shutdown().await.unwrap();
+        test_proxy.writer.read_to_end(&mut received).await.unwrap();
+
+        let mut ref_decompressor = Decompressor::new(Vec::new());
+        ref_decompressor.write_all(&received).unwrap();
+
+        assert_eq!(ref_decompressor.finish().unwrap(), message);
+    }
+
     #[tokio::test]
     async fn proxy_large_compressed_content() {
         // ~2kB message
@@ -162,13 +299,16 @@ Duis quis neque sit amet turpis ullamcorper pretium a et turpis. In ultrices ero
 Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum imperdiet tortor ex a ante. Mauris a tortor nec sapien volutpat porttitor. Praesent purus erat, viverra sed rhoncus eget, sodales ac felis. Integer scelerisque leo gravida.".as_bytes();
         let mut received = Vec::new();
 
-        let mut test_proxy = setup_proxy(Some(Direction::Compress)).await;
+        let mut test_proxy = setup_proxy(Some(Direction::Compress(CompressionSettings::new(
+            Scheme::Deflate,
+        ))))
+        .await;
 
-        test_proxy.reader.write_all(&message).await.unwrap();
+        test_proxy.reader.write_all(message).await.unwrap();
         test_proxy.reader.shutdown().await.unwrap();
         test_proxy.writer.read_to_end(&mut received).await.unwrap();
 
-        let compression_frames = split_frames(&received);
+        let (compression_frames, _) = split_frames(&received).unwrap();
         // The forward proxy should receive the data as 2 different messages, each will be
         // compressed separately.
         // TODO: should make this more maintainable by not hardcoding it to expect 2 chunks but
@@ -192,7 +332,7 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
         let message = "Hello world! This is message should be proxied and decompressed.".as_bytes();
 
         let mut ref_compressor = Compressor::new(Vec::new());
-        ref_compressor.write_all(&message).unwrap();
+        ref_compressor.write_all(message).unwrap();
         let compressed_message = ref_compressor.finish().unwrap();
 
         let mut received = Vec::new();
@@ -210,6 +350,34 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
         assert_eq!(received, message);
     }
 
+    #[tokio::test]
+    async fn proxy_decompressed_mixed_schemes() {
+        let messages = [
+            "Hello world! This message was compressed with lz4.".as_bytes(),
+            "Hello world! This message was compressed with deflate.".as_bytes(),
+        ];
+        let schemes = [Scheme::Lz4, Scheme::Deflate];
+
+        let mut received = Vec::new();
+
+        let mut test_proxy = setup_proxy(Some(Direction::Decompress)).await;
+
+        for (message, scheme) in messages.iter().zip(schemes.iter()) {
+            let mut ref_compressor = Compressor::with_scheme(Vec::new(), *scheme).unwrap();
+            ref_compressor.write_all(message).unwrap();
+            let compressed_message = ref_compressor.finish().unwrap();
+            test_proxy
+                .reader
+                .write_all(&compressed_message)
+                .await
+                .unwrap();
+        }
+        test_proxy.reader.shutdown().await.unwrap();
+        test_proxy.writer.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, messages.concat());
+    }
+
     #[tokio::test]
     async fn proxy_large_decompressed_content() {
         // ~2kB message
@@ -241,4 +409,173 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
 
         assert_eq!(received, message);
     }
+
+    #[tokio::test]
+    async fn proxy_decompressed_frame_larger_than_read_buffer() {
+        let message = incompressible(5000);
+
+        let mut ref_compressor = Compressor::new(Vec::new());
+        ref_compressor.write_all(&message).unwrap();
+        let compressed_message = ref_compressor.finish().unwrap();
+        assert!(compressed_message.len() > 1024);
+
+        let mut received = Vec::new();
+
+        let mut test_proxy = setup_proxy(Some(Direction::Decompress)).await;
+
+        test_proxy
+            .reader
+            .write_all(&compressed_message)
+            .await
+            .unwrap();
+        test_proxy.reader.shutdown().await.unwrap();
+        test_proxy.writer.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn proxy_decompressed_content_in_small_chunks() {
+        let message = incompressible(3000);
+        let compressed_messages: Vec<u8> = message
+            .chunks(700)
+            .flat_map(|chunk| {
+                let mut ref_compressor = Compressor::new(Vec::new());
+                ref_compressor.write_all(chunk).unwrap();
+                ref_compressor.finish().unwrap()
+            })
+            .collect();
+
+        let mut received = Vec::new();
+
+        let mut test_proxy = setup_proxy(Some(Direction::Decompress)).await;
+        test_proxy.reader.set_nodelay(true).unwrap();
+
+        // Use chunk sizes that split both headers and payloads across writes
+        for chunk in compressed_messages.chunks(3) {
+            test_proxy.reader.write_all(chunk).await.unwrap();
+            test_proxy.reader.flush().await.unwrap();
+        }
+        test_proxy.reader.shutdown().await.unwrap();
+        test_proxy.writer.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn proxy_incompressible_content_as_stored_frames() {
+        let message = incompressible(1000);
+        let mut received = Vec::new();
+
+        let mut test_proxy = setup_proxy(Some(Direction::Compress(CompressionSettings::new(
+            Scheme::Deflate,
+        ))))
+        .await;
+
+        test_proxy.reader.write_all(&message).await.unwrap();
+        test_proxy.reader.shutdown().await.unwrap();
+        test_proxy.writer.read_to_end(&mut received).await.unwrap();
+
+        let (compression_frames, _) = split_frames(&received).unwrap();
+        let decompressed_data: Vec<u8> = compression_frames
+            .iter()
+            .flat_map(|frame| {
+                let mut ref_decompressor = Decompressor::new(Vec::new());
+                ref_decompressor.write_all(frame).unwrap();
+                ref_decompressor.finish().unwrap()
+            })
+            .collect();
+
+        assert_eq!(decompressed_data, message);
+        // Each frame only adds its 10 byte header to the proxied data
+        assert_eq!(
+            received.len() - message.len(),
+            10 * compression_frames.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_streaming_compressed_content() {
+        let messages = [
+            "GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".as_bytes(),
+            "GET /style.css HTTP/1.1\r\nHost: example.com\r\n\r\n".as_bytes(),
+        ];
+        let mut settings = CompressionSettings::new(Scheme::Deflate);
+        settings.mode = Mode::Streaming;
+
+        let mut compressing_proxy = setup_proxy(Some(Direction::Compress(settings))).await;
+        let mut decompressing_proxy = setup_proxy(Some(Direction::Decompress)).await;
+        compressing_proxy.reader.set_nodelay(true).unwrap();
+
+        let mut compressed = Vec::new();
+        for message in messages.iter() {
+            compressing_proxy.reader.write_all(message).await.unwrap();
+            // Each frame can be decompressed as soon as it arrives, without waiting for the
+            // stream to end
+            let mut frame = vec![0; 1024];
+            let n = compressing_proxy.writer.read(&mut frame).await.unwrap();
+            decompressing_proxy
+                .reader
+                .write_all(&frame[..n])
+                .await
+                .unwrap();
+            let mut received = vec![0; message.len()];
+            decompressing_proxy
+                .writer
+                .read_exact(&mut received)
+                .await
+                .unwrap();
+            assert_eq!(&received, message);
+            compressed.extend_from_slice(&frame[..n]);
+        }
+
+        let (frames, remainder) = split_frames(&compressed).unwrap();
+        assert_eq!(frames.len(), messages.len());
+        assert!(remainder.is_empty());
+    }
+
+    #[tokio::test]
+    async fn proxy_closes_on_invalid_frame() {
+        let mut received = Vec::new();
+
+        let mut test_proxy = setup_proxy(Some(Direction::Decompress)).await;
+
+        test_proxy
+            .reader
+            .write_all(b"not a compression frame")
+            .await
+            .unwrap();
+        test_proxy.writer.read_to_end(&mut received).await.unwrap();
+
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn proxy_closes_on_checksum_mismatch() {
+        let messages = [incompressible(100), incompressible(200)];
+        let mut settings = CompressionSettings::new(Scheme::Deflate);
+        settings.checksum = Some(ChecksumKind::Crc32);
+        let mut frames: Vec<Vec<u8>> = messages
+            .iter()
+            .map(|message| {
+                let mut ref_compressor = Compressor::with_settings(Vec::new(), settings).unwrap();
+                ref_compressor.write_all(message).unwrap();
+                ref_compressor.finish().unwrap()
+            })
+            .collect();
+        // The data doesn't compress, so the frame is stored and this corrupts the payload without
+        // breaking decompression
+        let last = frames[1].len() - 1;
+        frames[1][last] ^= 0x01;
+
+        let mut received = Vec::new();
+
+        let mut test_proxy = setup_proxy(Some(Direction::Decompress)).await;
+
+        // The connection is closed without waiting for the sender to finish
+        test_proxy.reader.write_all(&frames.concat()).await.unwrap();
+        test_proxy.writer.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, messages[0]);
+    }
 }
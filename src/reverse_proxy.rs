@@ -1,45 +1,309 @@
-use crate::compression::Direction;
+use crate::backends::Backends;
+use crate::client_identity::{self, ClientTls, HeaderInjector, IdentityForwarding};
+use crate::compression::{CompressionSettings, Direction};
 use crate::errors::*;
 use crate::iostream::IoStream;
+use crate::negotiation::{self, Accepted};
 use crate::proxy_common::proxy_conn;
-use error_chain::bail;
-use std::fs::File;
-use std::io::BufReader;
+use crate::sniff::{self, Protocol};
+use crate::tls::{self, ClientAuth, ReloadableConfig, ServerTlsSettings};
+use error_chain::ChainedError;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::Arc;
-use tokio::io::split;
+use std::sync::{Arc, Mutex};
+use tokio::io::{split, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::rustls::internal::pemfile;
-use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
-use tokio_rustls::{TlsAcceptor, TlsStream};
+use tokio_rustls::rustls::{ClientConfig, ServerConfig, Session};
+use tokio_rustls::webpki::DNSName;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
 
 pub const HTTPS_PORT: u16 = 9443;
 
 pub fn run(
     local_addr: SocketAddr,
-    server_ips: Vec<SocketAddr>,
-    compress: bool,
-    encrypt: bool,
-    cert_path: Option<&Path>,
-    key_path: Option<&Path>,
+    backends: impl Into<Backends>,
+    compression: Vec<CompressionSettings>,
+    tls: Option<ServerTlsSettings>,
 ) -> Result<()> {
     let rt = tokio::runtime::Runtime::new().chain_err(|| "failed to create tokio runtime")?;
 
-    rt.block_on(run_async(
-        local_addr, server_ips, compress, encrypt, cert_path, key_path,
-    ))
+    rt.block_on(run_async(local_addr, backends, compression, tls))
 }
 
+/// TLS state shared by the connections from forward proxies
+struct TlsServer {
+    config: Arc<ReloadableConfig<ServerConfig>>,
+    /// Connections that aren't TLS are refused when client certificates are required, so they can't
+    /// get around client authentication
+    require_tls: bool,
+}
+
+/// TLS state for connections to a backend that only accepts TLS
+struct Upstream {
+    server_name: DNSName,
+    config: Arc<ReloadableConfig<ClientConfig>>,
+}
+
+/// Opens a connection to a backend server, encrypted if it has upstream TLS settings. `preamble`
+/// is sent before anything else, including the TLS handshake, as PROXY protocol headers are.
+async fn connect_backend(
+    to_addr: SocketAddr,
+    preamble: &[u8],
+    upstream: Option<&Upstream>,
+) -> Result<IoStream> {
+    let mut to_tcp_conn = TcpStream::connect(to_addr).await?;
+    to_tcp_conn.write_all(preamble).await?;
+    let upstream = match upstream {
+        Some(upstream) => upstream,
+        None => return Ok(IoStream::from(to_tcp_conn)),
+    };
+
+    // Taken for each connection so reloaded certificates are picked up
+    let connector = TlsConnector::from(upstream.config.get());
+    let tls_conn = connector
+        .connect(upstream.server_name.as_ref(), to_tcp_conn)
+        .await
+        .chain_err(|| format!("TLS handshake with {} failed", to_addr))?;
+    Ok(IoStream::from(TlsStream::from(tls_conn)))
+}
+
+/// Proxies a connection to a backend server. What the connection starts with decides how it is
+/// handled: TLS handshakes are accepted if TLS is configured, compression offers are negotiated,
+/// and anything else is forwarded as it is.
+async fn proxy_accepted(
+    from_tcp_conn: TcpStream,
+    from_addr: SocketAddr,
+    backends: &Mutex<Backends>,
+    upstreams: &HashMap<SocketAddr, Upstream>,
+    compression: &[CompressionSettings],
+    tls_server: Option<&TlsServer>,
+) {
+    let protocol =
+        match sniff::sniff(&from_tcp_conn, sniff::PROTOCOL_PREFIX_LEN, sniff::classify).await {
+            // Includes clients that wait for the server to speak first
+            Ok(protocol) => protocol.unwrap_or(Protocol::Plaintext),
+            Err(e) => {
+                eprintln!("error reading from {}: {}", from_addr, e.display_chain());
+                return;
+            }
+        };
+    println!("{} connection from {}", protocol, from_addr);
+    // The address the client connected to, for PROXY protocol headers
+    let local_addr = match from_tcp_conn.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(e) => {
+            eprintln!("error reading local address of {}: {}", from_addr, e);
+            return;
+        }
+    };
+
+    // Protocol the client selected with ALPN, used to pick the backend
+    let mut alpn_protocol: Option<Vec<u8>> = None;
+    // Passed on to backends that want the client's identity
+    let mut client_tls: Option<ClientTls> = None;
+    let (mut from_conn, negotiate) = match (protocol, tls_server) {
+        // Taken for each connection so reloaded certificates are picked up
+        (Protocol::Tls, Some(tls_server)) => {
+            match TlsAcceptor::from(tls_server.config.get())
+                .accept(from_tcp_conn)
+                .await
+            {
+                Ok(tls_conn) => {
+                    let session = tls_conn.get_ref().1;
+                    client_tls = match ClientTls::from_session(session) {
+                        Ok(client_tls) => Some(client_tls),
+                        Err(e) => {
+                            eprintln!("{}: {}", from_addr, e.display_chain());
+                            return;
+                        }
+                    };
+                    if let Some(identity) =
+                        client_tls.as_ref().and_then(|tls| tls.identity.as_ref())
+                    {
+                        println!("client certificate {} from {}", identity.subject, from_addr);
+                    }
+                    alpn_protocol = session.get_alpn_protocol().map(Vec::from);
+                    if let Some(protocol) = &alpn_protocol {
+                        println!(
+                            "negotiated ALPN protocol {} with {}",
+                            String::from_utf8_lossy(protocol),
+                            from_addr
+                        );
+                    }
+                    // Forward proxies send their compression offer once the handshake is done
+                    (IoStream::from(TlsStream::from(tls_conn)), true)
+                }
+                // Includes forward proxies that don't present a valid client certificate
+                Err(e) => {
+                    eprintln!("TLS handshake with {} failed: {}", from_addr, e);
+                    return;
+                }
+            }
+        }
+        (_, Some(tls_server)) if tls_server.require_tls => {
+            eprintln!(
+                "refusing {} connection from {}, client certificates are required",
+                protocol, from_addr
+            );
+            return;
+        }
+        (Protocol::Compression, _) => (IoStream::from(from_tcp_conn), true),
+        // TLS is forwarded as it is when it isn't configured here, e.g. for a TLS backend
+        (Protocol::Tls, None) | (Protocol::Plaintext, _) => (IoStream::from(from_tcp_conn), false),
+    };
+
+    let accepted = if negotiate {
+        match negotiation::accept(&mut from_conn, compression).await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("compression negotiation with {} failed: {}", from_addr, e);
+                return;
+            }
+        }
+    } else {
+        Accepted {
+            compression: None,
+            initial_data: Vec::new(),
+        }
+    };
+
+    let selected = {
+        let mut backends = backends.lock().unwrap_or_else(|e| e.into_inner());
+        backends.select(alpn_protocol.as_deref()).map(|to_addr| {
+            let forwarding = backends.identity_forwarding(to_addr).cloned();
+            (to_addr, forwarding)
+        })
+    };
+    let (to_addr, forwarding) = match selected {
+        Some(selected) => selected,
+        None => {
+            eprintln!(
+                "no backend server to forward the connection from {} to",
+                from_addr
+            );
+            return;
+        }
+    };
+
+    // TLS forwarded as it is is already encrypted for the backend, wrapping it in another TLS
+    // connection would only stop the backend from understanding it
+    let passthrough = protocol == Protocol::Tls && tls_server.is_none();
+    let upstream = match passthrough {
+        true => None,
+        false => upstreams.get(&to_addr),
+    };
+
+    let mut preamble = Vec::new();
+    let mut headers = None;
+    match &forwarding {
+        Some(IdentityForwarding::ProxyProtocol(types)) => {
+            match client_identity::proxy_v2_header(
+                from_addr,
+                local_addr,
+                client_tls.as_ref(),
+                types,
+            ) {
+                Ok(header) => preamble = header,
+                Err(e) => {
+                    eprintln!("{}: {}", from_addr, e.display_chain());
+                    return;
+                }
+            }
+        }
+        // Requests can't be read in TLS forwarded as it is, and there is no identity to add
+        Some(IdentityForwarding::HttpHeaders(names)) if !passthrough => {
+            let identity = client_tls.as_ref().and_then(|tls| tls.identity.as_ref());
+            headers = Some(HeaderInjector::new(names, identity));
+        }
+        _ => (),
+    }
+    let initial_data = match headers.as_mut() {
+        Some(headers) => match headers.push(&accepted.initial_data) {
+            Ok(initial_data) => initial_data,
+            Err(e) => {
+                eprintln!("{}: can't add identity headers: {}", from_addr, e);
+                return;
+            }
+        },
+        None => accepted.initial_data,
+    };
+
+    let to_conn = match connect_backend(to_addr, &preamble, upstream).await {
+        Ok(to_conn) => to_conn,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", to_addr, e.display_chain());
+            return;
+        }
+    };
+    println!("connection opened to {}", to_addr);
+
+    let (client_read, client_write) = split::<IoStream>(from_conn);
+    let (server_read, mut server_write) = split::<IoStream>(to_conn);
+
+    // Forward anything read while checking for a compression offer
+    if server_write.write_all(&initial_data).await.is_err() {
+        eprintln!("Error sending to write connection");
+        return;
+    }
+
+    let compress = accepted.compression;
+    tokio::spawn(async move {
+        proxy_conn(
+            client_read,
+            server_write,
+            compress.map(|_| Direction::Decompress),
+            headers,
+            &format!("{} -> {}", from_addr, to_addr),
+        )
+        .await;
+    });
+    proxy_conn(
+        server_read,
+        client_write,
+        compress.map(Direction::Compress),
+        None,
+        &format!("{} -> {}", to_addr, from_addr),
+    )
+    .await;
+}
+
+/// `backends` are the servers that connections are forwarded to, a Vec<SocketAddr> or Backends
+/// with routes for ALPN protocols. Backends with upstream TLS settings are connected to over TLS,
+/// so connections decrypted here are encrypted again for them.
+///
+/// `compression` lists the compression settings that can be selected when a forward proxy offers
+/// compression. Compression is disabled if it is empty. If `tls` is set, connections that start
+/// with a TLS handshake are decrypted, and other connections are still accepted unless client
+/// certificates are required.
 pub async fn run_async(
     local_addr: SocketAddr,
-    server_ips: Vec<SocketAddr>,
-    compress: bool,
-    encrypt: bool,
-    cert_path: Option<&Path>,
-    key_path: Option<&Path>,
+    backends: impl Into<Backends>,
+    compression: Vec<CompressionSettings>,
+    tls: Option<ServerTlsSettings>,
 ) -> Result<()> {
-    let mut server_carousel = server_ips.iter().cycle();
+    let backends: Backends = backends.into();
+
+    let mut upstreams = HashMap::new();
+    for (&addr, settings) in backends.upstream_tls() {
+        let config = tls::client_config(&settings.client)
+            .chain_err(|| format!("invalid upstream TLS settings for {}", addr))?;
+        let config = Arc::new(ReloadableConfig::new(config));
+        tokio::spawn(tls::watch(
+            settings.client.clone(),
+            Arc::clone(&config),
+            tls::RELOAD_POLL_INTERVAL,
+        ));
+        let server_name = settings.dns_name()?;
+        upstreams.insert(
+            addr,
+            Upstream {
+                server_name,
+                config,
+            },
+        );
+    }
+    let upstreams = Arc::new(upstreams);
+    let backends = Arc::new(Mutex::new(backends));
 
     println!("opening listener socket on {}", local_addr);
 
@@ -47,81 +311,52 @@ pub async fn run_async(
         .await
         .chain_err(|| format!("error opening listener socket on {}", local_addr))?;
 
-    let mut tls_config = ServerConfig::new(NoClientAuth::new());
-    if encrypt {
-        let certs = pemfile::certs(&mut BufReader::new(File::open(
-            cert_path.ok_or("Must provide a cert path if encrypt is set")?,
-        )?))
-        .map_err(|_| "Could not load certs")?;
-        if certs.is_empty() {
-            bail!("Did not read any certs from file")
+    let tls_server = match tls {
+        Some(settings) => {
+            let config = Arc::new(ReloadableConfig::new(tls::server_config(&settings)?));
+            let require_tls = matches!(settings.client_auth, ClientAuth::Required(_));
+            tokio::spawn(tls::log_session_stats(settings.session_stats.clone()));
+            tokio::spawn(tls::watch_ocsp_expiry(
+                settings.clone(),
+                Arc::clone(&config),
+                tls::OCSP_CHECK_INTERVAL,
+            ));
+            tokio::spawn(tls::watch(
+                settings,
+                Arc::clone(&config),
+                tls::RELOAD_POLL_INTERVAL,
+            ));
+            Some(Arc::new(TlsServer {
+                config,
+                require_tls,
+            }))
         }
-
-        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(
-            key_path.ok_or("Must provide a key path if encrypt is set")?,
-        )?))
-        .map_err(|_| "Could not load key")?;
-        let key = match keys.len() {
-            1 => keys.remove(0),
-            0 => bail!("Did not read any keys from file."),
-            _ => bail!("Read multiple keys from file. Only one private key should be provided."),
-        };
-
-        tls_config.set_single_cert(certs, key)?;
-    }
-    let tls_config_acceptor = TlsAcceptor::from(Arc::new(tls_config));
+        None => None,
+    };
 
     loop {
         let (from_tcp_conn, from_addr) = listen_socket
             .accept()
             .await
-            .chain_err(|| format!("error accepting connection"))?;
+            .chain_err(|| "error accepting connection")?;
         println!("connection received from {}", from_addr);
 
-        let from_conn = match encrypt {
-            false => IoStream::from(from_tcp_conn),
-            true => IoStream::from(TlsStream::from(
-                tls_config_acceptor.clone().accept(from_tcp_conn).await?,
-            )),
-        };
-
-        let to_addr = server_carousel
-            .next()
-            .chain_err(|| "server carousel failed to provide server addr")?
-            .clone();
-
-        if let Ok(to_conn) = TcpStream::connect(to_addr).await {
-            println!("connection opened to {}", to_addr);
-
-            let (client_read, client_write) = split::<IoStream>(from_conn);
-            let (server_read, server_write) = split::<IoStream>(IoStream::from(to_conn));
-
-            tokio::spawn(async move {
-                proxy_conn(
-                    client_read,
-                    server_write,
-                    if compress {
-                        Some(Direction::Decompress)
-                    } else {
-                        None
-                    },
-                )
-                .await;
-            });
-            tokio::spawn(async move {
-                proxy_conn(
-                    server_read,
-                    client_write,
-                    if compress {
-                        Some(Direction::Compress)
-                    } else {
-                        None
-                    },
-                )
-                .await;
-            });
-        } else {
-            eprintln!("failed to connect to {}", to_addr);
-        }
+        let backends = Arc::clone(&backends);
+        let upstreams = Arc::clone(&upstreams);
+        let compression = compression.clone();
+        let tls_server = tls_server.clone();
+        // Sniffing, the handshake and negotiation can wait on the client, so they happen here
+        // rather than in the accept loop
+        tokio::spawn(async move {
+            proxy_accepted(
+                from_tcp_conn,
+                from_addr,
+                &backends,
+                &upstreams,
+                &compression,
+                tls_server.as_deref(),
+            )
+            .await
+        });
     }
 }
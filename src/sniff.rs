@@ -0,0 +1,196 @@
+// Detection of what a connection carries from the first bytes the peer sends, without consuming
+// them, so the connection can still be handed to whichever pipeline handles it.
+
+use crate::errors::*;
+use crate::negotiation;
+use std::fmt;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// How long to wait for the peer to send enough data to tell what the connection carries. Bounds
+/// the delay for protocols where the server speaks first.
+pub const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait before peeking again when the peer has only sent part of what is needed
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+/// TLS record content type of handshake messages
+const HANDSHAKE_CONTENT_TYPE: u8 = 0x16;
+/// Handshake message type of a ClientHello
+const CLIENT_HELLO_TYPE: u8 = 0x01;
+/// Largest TLS record, a full plaintext fragment plus the most expansion allowed
+const MAX_RECORD_LEN: usize = (1 << 14) + 2048;
+/// Bytes needed to recognize a ClientHello: the record header and the handshake message type
+pub const CLIENT_HELLO_PREFIX_LEN: usize = 6;
+
+/// Returns whether the data starts with a TLS record carrying a ClientHello, None if more data is
+/// needed to tell
+pub fn is_tls_client_hello(data: &[u8]) -> Option<bool> {
+    match *data {
+        [content_type, ..] if content_type != HANDSHAKE_CONTENT_TYPE => Some(false),
+        // Major version 3 covers SSL 3.0 to TLS 1.3, whose ClientHello says TLS 1.0 or 1.2 here
+        [_, major, ..] if major != 3 => Some(false),
+        [_, _, minor, ..] if minor > 4 => Some(false),
+        [_, _, _, len_high, len_low, handshake_type, ..] => {
+            let record_len = u16::from_be_bytes([len_high, len_low]) as usize;
+            Some((1..=MAX_RECORD_LEN).contains(&record_len) && handshake_type == CLIENT_HELLO_TYPE)
+        }
+        _ => None,
+    }
+}
+
+/// What a connection accepted by the reverse proxy carries
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// A TLS handshake, from a forward proxy or a client of a TLS backend
+    Tls,
+    /// A compression offer from a forward proxy that doesn't use encryption
+    Compression,
+    /// Anything else, forwarded as it is
+    Plaintext,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tls => "TLS",
+            Protocol::Compression => "compressed",
+            Protocol::Plaintext => "plaintext",
+        })
+    }
+}
+
+/// Bytes needed to tell the protocols apart
+pub const PROTOCOL_PREFIX_LEN: usize = CLIENT_HELLO_PREFIX_LEN;
+
+/// Returns the protocol the data starts with, None if more data is needed to tell
+pub fn classify(data: &[u8]) -> Option<Protocol> {
+    if is_tls_client_hello(data)? {
+        return Some(Protocol::Tls);
+    }
+    if negotiation::is_offer(data)? {
+        return Some(Protocol::Compression);
+    }
+    Some(Protocol::Plaintext)
+}
+
+/// Peeks at the data the peer sends until `classify` can tell what the connection carries, looking
+/// at no more than `max_len` bytes. Returns None if the peer closes the connection, sends `max_len`
+/// bytes that can't be classified, or doesn't send enough within SNIFF_TIMEOUT.
+pub async fn sniff<T>(
+    conn: &TcpStream,
+    max_len: usize,
+    classify: impl Fn(&[u8]) -> Option<T>,
+) -> Result<Option<T>> {
+    let mut buf = vec![0; max_len];
+    let peek = async {
+        loop {
+            let n = conn
+                .peek(&mut buf)
+                .await
+                .chain_err(|| "error reading from connection")?;
+            if n == 0 {
+                return Ok(None);
+            }
+            if let Some(kind) = classify(&buf[..n]) {
+                return Ok(Some(kind));
+            }
+            if n == max_len {
+                return Ok(None);
+            }
+            // Peeking returns straight away while any data is waiting, so give the rest time to
+            // arrive
+            tokio::time::sleep(PEEK_INTERVAL).await;
+        }
+    };
+    match tokio::time::timeout(SNIFF_TIMEOUT, peek).await {
+        Ok(result) => result,
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::negotiation;
+    use crate::sniff::{classify, is_tls_client_hello, sniff, Protocol, CLIENT_HELLO_PREFIX_LEN};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Start of the ClientHello rustls sends
+    const CLIENT_HELLO: &[u8] = &[0x16, 0x03, 0x01, 0x00, 0xc4, 0x01, 0x00, 0x00, 0xc0];
+
+    #[test]
+    fn recognize_client_hello() {
+        assert_eq!(is_tls_client_hello(CLIENT_HELLO), Some(true));
+        assert_eq!(is_tls_client_hello(&CLIENT_HELLO[..5]), None);
+        assert_eq!(is_tls_client_hello(&[]), None);
+
+        assert_eq!(is_tls_client_hello(b"GET / HTTP/1.1\r\n"), Some(false));
+        assert_eq!(is_tls_client_hello(&[0x16, 0x04]), Some(false));
+        // A server's handshake record, and a ClientHello record with no length
+        assert_eq!(
+            is_tls_client_hello(&[0x16, 0x03, 0x03, 0x00, 0x7a, 0x02]),
+            Some(false)
+        );
+        assert_eq!(
+            is_tls_client_hello(&[0x16, 0x03, 0x01, 0x00, 0x00, 0x01]),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn classify_protocols() {
+        let offer = negotiation::NEGOTIATION_MAGIC_VALUE.to_be_bytes();
+        assert_eq!(classify(CLIENT_HELLO), Some(Protocol::Tls));
+        assert_eq!(
+            classify(&[offer[0], offer[1], offer[2], offer[3], 1]),
+            Some(Protocol::Compression)
+        );
+        assert_eq!(classify(b"GET / HTTP/1.1\r\n"), Some(Protocol::Plaintext));
+        assert_eq!(classify(b"G"), Some(Protocol::Plaintext));
+        assert_eq!(classify(&CLIENT_HELLO[..2]), None);
+        assert_eq!(classify(&offer[..2]), None);
+    }
+
+    /// Helper function that returns both ends of a TCP connection
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn sniff_does_not_consume() {
+        let (mut client, mut server) = connection().await;
+        // The record header arrives on its own
+        client.write_all(&CLIENT_HELLO[..3]).await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            client.write_all(&CLIENT_HELLO[3..]).await.unwrap();
+        });
+
+        let found = sniff(&server, CLIENT_HELLO_PREFIX_LEN, is_tls_client_hello).await;
+        assert_eq!(found.unwrap(), Some(true));
+
+        let mut data = [0; 9];
+        server.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data[..], CLIENT_HELLO);
+    }
+
+    #[tokio::test]
+    async fn sniff_gives_up() {
+        // The server speaks first
+        let (_client, server) = connection().await;
+        let found = sniff(&server, CLIENT_HELLO_PREFIX_LEN, is_tls_client_hello).await;
+        assert_eq!(found.unwrap(), None);
+
+        // The client closes before sending enough
+        let (mut client, server) = connection().await;
+        client.write_all(&CLIENT_HELLO[..2]).await.unwrap();
+        drop(client);
+        let found = sniff(&server, CLIENT_HELLO_PREFIX_LEN, is_tls_client_hello).await;
+        assert_eq!(found.unwrap(), None);
+    }
+}
@@ -0,0 +1,15 @@
+//! Helpers shared by unit tests in several modules
+
+/// Generates data that doesn't compress well, so frames end up large
+pub fn incompressible(len: usize) -> Vec<u8> {
+    let mut state: u32 = 0x1234_5678;
+    (0..len)
+        .map(|_| {
+            // xorshift
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
@@ -0,0 +1,650 @@
+// TLS settings shared by the forward and reverse proxies, and helpers to turn them into rustls
+// configs.
+
+mod certgen;
+mod der;
+mod keys;
+mod ocsp;
+mod pins;
+mod policy;
+mod reload;
+mod server_names;
+mod sessions;
+mod sni;
+mod upstream;
+mod x509;
+
+use crate::errors::*;
+use error_chain::bail;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, ClientConfig,
+    NoClientAuth, PrivateKey, ResolvesClientCert, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::webpki;
+
+pub type CertsSettings = certgen::CertsSettings;
+pub type IpNetwork = server_names::IpNetwork;
+pub type PinMode = pins::PinMode;
+pub type ReloadableConfig<C> = reload::ReloadableConfig<C>;
+pub type ServerNameTable = server_names::ServerNameTable;
+pub type ServerPins = pins::ServerPins;
+pub type SessionStats = sessions::SessionStats;
+pub type SniCertResolver = sni::SniCertResolver;
+pub type SubjectAltName = certgen::SubjectAltName;
+pub type TlsPolicy = policy::TlsPolicy;
+pub const TLS_POLICY_NAMES: &[&str] = policy::PRESET_NAMES;
+pub const TLS_VERSION_NAMES: &[&str] = policy::VERSION_NAMES;
+pub type UpstreamTlsSettings = upstream::UpstreamTlsSettings;
+
+/// How often TLS files are checked for changes
+pub const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often stapled OCSP responses are checked for their nextUpdate time
+pub const OCSP_CHECK_INTERVAL: Duration = ocsp::CHECK_INTERVAL;
+
+/// Number of days certificates made by write_certs() are valid for unless configured otherwise
+pub const DEFAULT_CERT_VALIDITY_DAYS: u32 = certgen::DEFAULT_VALIDITY_DAYS;
+
+/// Number of sessions each proxy keeps for resumption unless configured otherwise
+pub const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
+/// Reloads the config whenever the process receives SIGHUP or one of the source files changes, see
+/// reload::watch()
+pub async fn watch<S: ConfigSource>(
+    source: S,
+    config: Arc<ReloadableConfig<S::Config>>,
+    poll_interval: Duration,
+) {
+    reload::watch(source, config, poll_interval).await
+}
+
+/// Logs the session resumption counts whenever the process receives SIGUSR1, see
+/// sessions::log_stats()
+pub async fn log_session_stats(stats: SessionStats) {
+    sessions::log_stats(stats).await
+}
+
+/// Logs a warning whenever an OCSP response stapled by the reverse proxy gets close to its
+/// nextUpdate time, and rebuilds the config without it once that time has passed, see
+/// ocsp::watch_expiry()
+pub async fn watch_ocsp_expiry(
+    settings: ServerTlsSettings,
+    config: Arc<ReloadableConfig<ServerConfig>>,
+    check_interval: Duration,
+) {
+    if std::iter::once(&settings.cert)
+        .chain(settings.sni_certs.values())
+        .any(|paths| paths.ocsp.is_some())
+    {
+        ocsp::watch_expiry(settings, config, check_interval).await
+    }
+}
+
+/// Creates a CA and certificates for both proxies, and writes them as PEM files, see
+/// certgen::write_certs()
+pub fn write_certs(settings: &CertsSettings) -> Result<Vec<PathBuf>> {
+    certgen::write_certs(settings)
+}
+
+/// Reads a JSON file mapping host names to the certificate chain and key to present for them, see
+/// sni::load_sni_config()
+pub fn load_sni_config(path: &Path) -> Result<HashMap<String, CertKeyPaths>> {
+    sni::load_sni_config(path)
+}
+
+/// Reads a JSON file mapping backend addresses to the TLS settings the reverse proxy connects to
+/// them with, see upstream::load_upstream_tls_config()
+pub fn load_upstream_tls_config(path: &Path) -> Result<HashMap<SocketAddr, UpstreamTlsSettings>> {
+    upstream::load_upstream_tls_config(path)
+}
+
+/// Returns the subject of a DER certificate, formatted like `CN=proxy.lab, O=Example`, see
+/// x509::subject()
+pub fn cert_subject(cert: &[u8]) -> Result<String> {
+    x509::subject(cert)
+}
+
+/// Returns the subject alternative names of a DER certificate, formatted like `DNS:proxy.lab`, see
+/// x509::subject_alt_names()
+pub fn cert_subject_alt_names(cert: &[u8]) -> Result<Vec<String>> {
+    x509::subject_alt_names(cert)
+}
+
+/// Paths to a PEM certificate chain and its private key
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct CertKeyPaths {
+    pub cert_chain: PathBuf,
+    pub key: PathBuf,
+    /// DER OCSP response for the first certificate in the chain, stapled in handshakes
+    #[serde(default)]
+    pub ocsp: Option<PathBuf>,
+}
+
+impl CertKeyPaths {
+    pub fn new(cert_chain: impl Into<PathBuf>, key: impl Into<PathBuf>) -> CertKeyPaths {
+        CertKeyPaths {
+            cert_chain: cert_chain.into(),
+            key: key.into(),
+            ocsp: None,
+        }
+    }
+}
+
+/// TLS settings for the forward proxy's connections to reverse proxies
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientTlsSettings {
+    /// PEM file with the root certificates used to verify reverse proxies. Can only be left out
+    /// if every reverse proxy is trusted by its pins alone.
+    pub root_certs: Option<PathBuf>,
+    /// Client certificate presented to reverse proxies that ask for one
+    pub client_cert: Option<CertKeyPaths>,
+    /// Names expected in the certificates of reverse proxies at each destination address. Reverse
+    /// DNS is used for destinations that aren't in the table.
+    pub server_names: ServerNameTable,
+    /// Public keys that reverse proxies with each server name must present
+    pub pins: ServerPins,
+    /// Whether pinned reverse proxies are also verified against `root_certs`
+    pub pin_mode: PinMode,
+    /// ALPN protocols offered to reverse proxies, in order of preference
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Number of sessions kept to resume with reverse proxies, one per server name. Sessions
+    /// aren't resumed if it is 0.
+    pub session_cache_size: usize,
+    /// Whether session tickets are used to resume sessions. TLS 1.3 sessions are only resumed with
+    /// tickets, so without them only TLS 1.2 sessions are resumed, by session ID.
+    pub session_tickets: bool,
+    /// Counts of offered sessions, shared by every config built from these settings. Reloading
+    /// the config starts with an empty cache, but keeps the counts.
+    pub session_stats: SessionStats,
+    /// TLS versions and cipher suites offered to reverse proxies
+    pub policy: TlsPolicy,
+}
+
+impl ClientTlsSettings {
+    /// Creates settings that verify reverse proxies against the root certificates, without a
+    /// client certificate or pins
+    pub fn new(root_certs: impl Into<PathBuf>) -> ClientTlsSettings {
+        ClientTlsSettings {
+            root_certs: Some(root_certs.into()),
+            client_cert: None,
+            server_names: ServerNameTable::new(),
+            pins: ServerPins::new(),
+            pin_mode: PinMode::WithCa,
+            alpn_protocols: Vec::new(),
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+            session_tickets: true,
+            session_stats: SessionStats::new(),
+            policy: TlsPolicy::default(),
+        }
+    }
+}
+
+/// Whether the reverse proxy asks forward proxies for a client certificate
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientAuth {
+    /// Client certificates aren't requested
+    None,
+    /// Client certificates are verified against the CA bundle when presented, but connections
+    /// without one are also accepted
+    Optional(PathBuf),
+    /// Connections must present a client certificate signed by a CA in the bundle
+    Required(PathBuf),
+}
+
+/// TLS settings for connections accepted by the reverse proxy
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerTlsSettings {
+    /// Certificate chain and key presented to forward proxies that don't ask for a host name in
+    /// `sni_certs`
+    pub cert: CertKeyPaths,
+    /// Certificate chains and keys presented to forward proxies that ask for a specific host name
+    /// with SNI. Keys are host names or wildcards such as `*.example.com`.
+    pub sni_certs: HashMap<String, CertKeyPaths>,
+    pub client_auth: ClientAuth,
+    /// ALPN protocols accepted from forward proxies, in order of preference
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Number of sessions kept for forward proxies to resume by session ID. Sessions aren't kept
+    /// if it is 0.
+    pub session_cache_size: usize,
+    /// Whether session tickets are issued, letting forward proxies resume without the session
+    /// being kept here. Without them, TLS 1.3 tickets only refer to sessions in the cache.
+    pub session_tickets: bool,
+    /// Counts of resumed sessions, shared by every config built from these settings. Reloading
+    /// the config starts with empty caches and new ticket keys, but keeps the counts.
+    pub session_stats: SessionStats,
+    /// TLS versions and cipher suites accepted from forward proxies
+    pub policy: TlsPolicy,
+}
+
+impl ServerTlsSettings {
+    /// Creates settings that present the certificate without requesting client certificates
+    pub fn new(cert: CertKeyPaths) -> ServerTlsSettings {
+        ServerTlsSettings {
+            cert,
+            sni_certs: HashMap::new(),
+            client_auth: ClientAuth::None,
+            alpn_protocols: Vec::new(),
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+            session_tickets: true,
+            session_stats: SessionStats::new(),
+            policy: TlsPolicy::default(),
+        }
+    }
+}
+
+/// Settings that a TLS config is built from
+pub trait ConfigSource: Send + Sync + 'static {
+    type Config: Send + Sync + 'static;
+
+    /// Files the config is read from, checked for changes to know when to reload it
+    fn files(&self) -> Vec<PathBuf>;
+
+    fn build(&self) -> Result<Self::Config>;
+}
+
+impl ConfigSource for ClientTlsSettings {
+    type Config = ClientConfig;
+
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self.root_certs.iter().cloned().collect();
+        if let Some(client_cert) = &self.client_cert {
+            files.push(client_cert.cert_chain.clone());
+            files.push(client_cert.key.clone());
+        }
+        files
+    }
+
+    fn build(&self) -> Result<ClientConfig> {
+        client_config(self)
+    }
+}
+
+impl ConfigSource for ServerTlsSettings {
+    type Config = ServerConfig;
+
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for paths in std::iter::once(&self.cert).chain(self.sni_certs.values()) {
+            files.push(paths.cert_chain.clone());
+            files.push(paths.key.clone());
+            files.extend(paths.ocsp.clone());
+        }
+        match &self.client_auth {
+            ClientAuth::None => (),
+            ClientAuth::Optional(ca_path) | ClientAuth::Required(ca_path) => {
+                files.push(ca_path.clone())
+            }
+        }
+        files
+    }
+
+    fn build(&self) -> Result<ServerConfig> {
+        server_config(self)
+    }
+}
+
+/// Reads every certificate from a PEM file. Returns an error if there are none.
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut BufReader::new(
+        File::open(path).chain_err(|| format!("error opening {}", path.display()))?,
+    ))
+    .map_err(|_| format!("Could not load certs from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("Did not read any certs from {}", path.display())
+    }
+    Ok(certs)
+}
+
+/// Reads a single private key from a file, see keys::load_private_key() for the supported formats
+pub fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    keys::load_private_key(path)
+}
+
+/// Reads a certificate chain and its private key, ready to be presented in a handshake, along with
+/// its OCSP response if there is one. Returns an error if the key doesn't belong to the first
+/// certificate in the chain, or the OCSP response can't be read or isn't for it. A response that
+/// clients would reject, e.g. one saying the certificate is revoked, is left out, see
+/// ocsp::load_staple().
+pub fn certified_key(paths: &CertKeyPaths) -> Result<CertifiedKey> {
+    let certs = load_certs(&paths.cert_chain)?;
+    let key = sign::any_supported_type(&load_private_key(&paths.key)?)
+        .map_err(|_| format!("unsupported private key type in {}", paths.key.display()))?;
+    let mut certified_key = CertifiedKey::new(certs, Arc::new(key));
+    if let Some(ocsp) = &paths.ocsp {
+        certified_key.ocsp = ocsp::load_staple(ocsp, &certified_key.cert)?;
+    }
+
+    check_key_matches_cert(&certified_key).chain_err(|| {
+        format!(
+            "private key {} doesn't match certificate {}",
+            paths.key.display(),
+            paths.cert_chain.display()
+        )
+    })?;
+    Ok(certified_key)
+}
+
+/// Webpki algorithm that verifies signatures made with a rustls signature scheme
+fn verification_algorithm(scheme: SignatureScheme) -> Option<&'static webpki::SignatureAlgorithm> {
+    match scheme {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => Some(&webpki::ECDSA_P256_SHA256),
+        SignatureScheme::ECDSA_NISTP384_SHA384 => Some(&webpki::ECDSA_P384_SHA384),
+        SignatureScheme::ED25519 => Some(&webpki::ED25519),
+        SignatureScheme::RSA_PKCS1_SHA256 => Some(&webpki::RSA_PKCS1_2048_8192_SHA256),
+        _ => None,
+    }
+}
+
+/// rustls doesn't check that a key belongs to its certificate, and a mismatch only shows up as
+/// failed handshakes. Sign a message with the key and verify it with the certificate instead.
+fn check_key_matches_cert(certified_key: &CertifiedKey) -> Result<()> {
+    let signer = certified_key
+        .key
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .ok_or("unsupported private key type")?;
+    let algorithm =
+        verification_algorithm(signer.get_scheme()).ok_or("unsupported signature scheme")?;
+
+    let message = b"rust_tls_proxy key check";
+    let signature = signer.sign(message)?;
+    let end_entity_cert = certified_key
+        .end_entity_cert()
+        .map_err(|_| "empty certificate chain")?;
+    webpki::EndEntityCert::from(end_entity_cert.as_ref())
+        .and_then(|cert| cert.verify_signature(algorithm, message, &signature))
+        .map_err(|e| format!("signature check failed: {:?}", e))?;
+    Ok(())
+}
+
+/// Presents the same client certificate to every server that asks for one
+struct ClientCertResolver(CertifiedKey);
+
+impl ResolvesClientCert for ClientCertResolver {
+    fn resolve(&self, _: &[&[u8]], _: &[SignatureScheme]) -> Option<CertifiedKey> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Reads a bundle of trusted root certificates from a PEM file. Returns an error if there are
+/// none.
+pub fn load_root_store(path: &Path) -> Result<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+    root_store
+        .add_pem_file(&mut BufReader::new(
+            File::open(path).chain_err(|| format!("error opening {}", path.display()))?,
+        ))
+        .map_err(|_| format!("Couldn't parse root cert file {}", path.display()))?;
+    if root_store.is_empty() {
+        bail!("No root certs added to store from {}", path.display())
+    }
+    Ok(root_store)
+}
+
+/// Builds the config used by the forward proxy to connect to reverse proxies
+pub fn client_config(settings: &ClientTlsSettings) -> Result<ClientConfig> {
+    settings.policy.validate()?;
+    let mut config = ClientConfig::new();
+    settings.policy.apply_to_client(&mut config);
+    match (&settings.root_certs, settings.pin_mode) {
+        (Some(root_certs), _) => config.root_store = load_root_store(root_certs)?,
+        (None, PinMode::InsteadOfCa) if !settings.pins.is_empty() => (),
+        (None, _) => bail!("root certs are needed to verify reverse proxies that aren't pinned"),
+    }
+    if !settings.pins.is_empty() {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(pins::PinningVerifier::new(
+                settings.pins.clone(),
+                settings.pin_mode,
+            )));
+    }
+
+    if let Some(client_cert) = &settings.client_cert {
+        config.client_auth_cert_resolver =
+            Arc::new(ClientCertResolver(certified_key(client_cert)?));
+    }
+    config.set_protocols(&settings.alpn_protocols);
+
+    config.set_persistence(sessions::client_session_storage(
+        settings.session_cache_size,
+        &settings.session_stats,
+    ));
+    config.enable_tickets = settings.session_tickets;
+
+    Ok(config)
+}
+
+/// Builds the config used by the reverse proxy to accept connections from forward proxies
+pub fn server_config(settings: &ServerTlsSettings) -> Result<ServerConfig> {
+    settings.policy.validate()?;
+    let mut config = match &settings.client_auth {
+        ClientAuth::None => ServerConfig::new(NoClientAuth::new()),
+        ClientAuth::Optional(ca_path) => ServerConfig::new(
+            AllowAnyAnonymousOrAuthenticatedClient::new(load_root_store(ca_path)?),
+        ),
+        ClientAuth::Required(ca_path) => {
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(load_root_store(ca_path)?))
+        }
+    };
+
+    settings.policy.apply_to_server(&mut config);
+
+    // A key that none of the allowed cipher suites can sign with would fail every handshake
+    let policy_certified_key = |paths: &CertKeyPaths| -> Result<CertifiedKey> {
+        let key = certified_key(paths)?;
+        settings
+            .policy
+            .check_key(&key)
+            .chain_err(|| format!("can't present {}", paths.cert_chain.display()))?;
+        Ok(key)
+    };
+    let mut resolver = SniCertResolver::new(policy_certified_key(&settings.cert)?);
+    for (name, paths) in &settings.sni_certs {
+        resolver.add(name, policy_certified_key(paths)?)?;
+    }
+    config.cert_resolver = Arc::new(resolver);
+    config.set_protocols(&settings.alpn_protocols);
+
+    config.set_persistence(sessions::session_storage(
+        settings.session_cache_size,
+        &settings.session_stats,
+    ));
+    config.ticketer = sessions::ticketer(settings.session_tickets, &settings.session_stats);
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::{
+        certified_key, client_config, load_certs, load_private_key, load_root_store, server_config,
+        CertKeyPaths, ClientAuth, ClientTlsSettings, ConfigSource, PinMode, ServerPins,
+        ServerTlsSettings, TlsPolicy,
+    };
+    use std::path::Path;
+    use tokio_rustls::rustls::ProtocolVersion;
+
+    fn server_cert() -> CertKeyPaths {
+        CertKeyPaths::new("tests/certs/cert.pem", "tests/certs/key.pem")
+    }
+
+    #[test]
+    fn load_test_certs() {
+        assert_eq!(
+            load_certs(Path::new("tests/certs/cert.pem")).unwrap().len(),
+            1
+        );
+        assert!(load_private_key(Path::new("tests/certs/client_key.pem")).is_ok());
+        assert!(!load_root_store(Path::new("tests/certs/ca_cert.pem"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn load_errors_name_the_file() {
+        let error = load_private_key(Path::new("tests/certs/cert.pem")).unwrap_err();
+        assert!(error.to_string().contains("tests/certs/cert.pem"));
+
+        let error = load_certs(Path::new("tests/certs/missing.pem")).unwrap_err();
+        assert!(error.to_string().contains("tests/certs/missing.pem"));
+    }
+
+    #[test]
+    fn certified_key_rejects_mismatched_key() {
+        assert!(certified_key(&server_cert()).is_ok());
+        assert!(certified_key(&CertKeyPaths::new(
+            "tests/certs/cert.pem",
+            "tests/certs/keys/rsa_pkcs1.der"
+        ))
+        .is_ok());
+        assert!(certified_key(&CertKeyPaths::new(
+            "tests/certs/client_cert.pem",
+            "tests/certs/client_key.pem"
+        ))
+        .is_ok());
+
+        let result = certified_key(&CertKeyPaths::new(
+            "tests/certs/cert.pem",
+            "tests/certs/client_key.pem",
+        ));
+        match result {
+            Err(error) => assert!(error.to_string().contains("doesn't match")),
+            Ok(_) => panic!("mismatched key was accepted"),
+        }
+    }
+
+    #[test]
+    fn client_config_with_client_cert() {
+        let mut settings = ClientTlsSettings::new("tests/certs/ca_cert.pem");
+        assert!(client_config(&settings).is_ok());
+
+        settings.client_cert = Some(CertKeyPaths::new(
+            "tests/certs/client_cert.pem",
+            "tests/certs/client_key.pem",
+        ));
+        assert!(client_config(&settings).is_ok());
+
+        settings.alpn_protocols = vec![b"h2".to_vec()];
+        assert_eq!(
+            client_config(&settings).unwrap().alpn_protocols,
+            vec![b"h2".to_vec()]
+        );
+
+        // A certificate file with no key in it
+        settings.client_cert = Some(CertKeyPaths::new(
+            "tests/certs/client_cert.pem",
+            "tests/certs/client_cert.pem",
+        ));
+        assert!(client_config(&settings).is_err());
+    }
+
+    #[test]
+    fn client_config_pins() {
+        let pins: ServerPins = "localhost=sha256//NojY2KjJdGYzj5le4ItRwkFL3l2aceXpyyB1lNXDIzQ="
+            .parse()
+            .unwrap();
+        let mut settings = ClientTlsSettings::new("tests/certs/ca_cert.pem");
+        settings.pins = pins.clone();
+        assert!(client_config(&settings).is_ok());
+
+        // Unpinned reverse proxies couldn't be verified without root certs
+        settings.root_certs = None;
+        assert!(client_config(&settings).is_err());
+        settings.pin_mode = PinMode::InsteadOfCa;
+        assert!(client_config(&settings).is_ok());
+        settings.pins = ServerPins::new();
+        assert!(client_config(&settings).is_err());
+    }
+
+    #[test]
+    fn server_config_client_auth_modes() {
+        let mut settings = ServerTlsSettings::new(server_cert());
+        assert!(server_config(&settings).is_ok());
+
+        settings.client_auth = ClientAuth::Optional("tests/certs/client_ca_cert.pem".into());
+        assert!(server_config(&settings).is_ok());
+
+        settings.client_auth = ClientAuth::Required("tests/certs/client_ca_cert.pem".into());
+        assert!(server_config(&settings).is_ok());
+
+        settings.client_auth = ClientAuth::Required("tests/certs/missing.pem".into());
+        assert!(server_config(&settings).is_err());
+    }
+
+    #[test]
+    fn configs_follow_policy() {
+        let mut client_settings = ClientTlsSettings::new("tests/certs/ca_cert.pem");
+        client_settings.policy = TlsPolicy::modern();
+        let config = client_config(&client_settings).unwrap();
+        assert_eq!(config.versions, vec![ProtocolVersion::TLSv1_3]);
+        assert_eq!(config.ciphersuites.len(), 3);
+
+        let mut server_settings = ServerTlsSettings::new(server_cert());
+        server_settings.policy = TlsPolicy::modern();
+        assert_eq!(
+            server_config(&server_settings).unwrap().versions,
+            vec![ProtocolVersion::TLSv1_3]
+        );
+
+        // The test certificate has an RSA key
+        server_settings.policy = TlsPolicy::compatible();
+        server_settings.policy.set_versions(vec!["1.2"]).unwrap();
+        server_settings
+            .policy
+            .set_cipher_suites(vec!["TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384"])
+            .unwrap();
+        assert!(server_config(&server_settings).is_err());
+
+        client_settings.policy.set_versions(vec!["1.2"]).unwrap();
+        assert!(client_config(&client_settings).is_err());
+    }
+
+    #[test]
+    fn staple_ocsp_response() {
+        let mut cert = server_cert();
+        cert.ocsp = Some("tests/certs/ocsp/cert_good.der".into());
+        // The chain needs the issuer to check the response against
+        assert!(certified_key(&cert).is_err());
+
+        let mut cert = CertKeyPaths::new("tests/certs/cert_chain.pem", "tests/certs/key.pem");
+        assert_eq!(certified_key(&cert).unwrap().ocsp, None);
+
+        cert.ocsp = Some("tests/certs/ocsp/cert_good.der".into());
+        let staple = certified_key(&cert).unwrap().ocsp.unwrap();
+        assert_eq!(
+            staple,
+            std::fs::read("tests/certs/ocsp/cert_good.der").unwrap()
+        );
+
+        // The response file is watched for changes
+        let settings = ServerTlsSettings::new(cert.clone());
+        assert!(settings
+            .files()
+            .contains(&"tests/certs/ocsp/cert_good.der".into()));
+
+        // The certificate is still presented, without the response
+        cert.ocsp = Some("tests/certs/ocsp/cert_revoked.der".into());
+        assert_eq!(certified_key(&cert).unwrap().ocsp, None);
+        cert.ocsp = Some("tests/certs/ocsp/missing.der".into());
+        assert!(certified_key(&cert).is_err());
+    }
+}
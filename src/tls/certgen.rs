@@ -0,0 +1,533 @@
+// Creation of a CA and the certificates the proxies present to each other, for labs and tests.
+// Keys are ECDSA P-256, which both TLS 1.2 and TLS 1.3 can use.
+
+use crate::errors::*;
+use crate::tls::{der, keys};
+use error_chain::bail;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::webpki::DNSNameRef;
+
+/// DER encodings of the OIDs used in the certificates
+const ECDSA_WITH_SHA256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const COMMON_NAME_OID: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const KEY_USAGE_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x0f];
+const SUBJECT_ALT_NAME_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+const BASIC_CONSTRAINTS_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x13];
+const EXTENDED_KEY_USAGE_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x25];
+const SERVER_AUTH_OID: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+const CLIENT_AUTH_OID: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02];
+
+/// Context specific tags used in certificates
+const EXPLICIT_0: u8 = 0xa0;
+const EXPLICIT_3: u8 = 0xa3;
+const DNS_NAME: u8 = 0x82;
+const IP_ADDRESS: u8 = 0x87;
+const UTF8_STRING: u8 = 0x0c;
+
+/// How long before they are created certificates become valid, so they can be used straight away
+/// on hosts whose clocks are a little behind
+const BACKDATE: Duration = Duration::from_secs(60 * 60);
+
+/// Number of days certificates are valid for unless configured otherwise
+pub const DEFAULT_VALIDITY_DAYS: u32 = 365;
+
+/// A name a certificate is valid for
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Ip(IpAddr),
+}
+
+impl SubjectAltName {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            SubjectAltName::Dns(name) => der::encode(DNS_NAME, name.as_bytes()),
+            SubjectAltName::Ip(IpAddr::V4(ip)) => der::encode(IP_ADDRESS, &ip.octets()),
+            SubjectAltName::Ip(IpAddr::V6(ip)) => der::encode(IP_ADDRESS, &ip.octets()),
+        }
+    }
+}
+
+impl std::str::FromStr for SubjectAltName {
+    type Err = Error;
+
+    /// Parses an IP address, or a DNS name that can start with a `*.` wildcard
+    fn from_str(name: &str) -> Result<SubjectAltName> {
+        if let Ok(ip) = name.parse() {
+            return Ok(SubjectAltName::Ip(ip));
+        }
+        DNSNameRef::try_from_ascii_str(name.strip_prefix("*.").unwrap_or(name))
+            .map_err(|_| format!("\"{}\" is neither a DNS name nor an IP address", name))?;
+        Ok(SubjectAltName::Dns(name.to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for SubjectAltName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubjectAltName::Dns(name) => f.write_str(name),
+            SubjectAltName::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// What a leaf certificate can be used for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeafUsage {
+    /// Presented by reverse proxies
+    Server,
+    /// Presented by forward proxies to reverse proxies that require client certificates
+    Client,
+}
+
+/// A DER certificate and its PKCS#8 private key
+pub struct GeneratedCert {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+/// Formats DER data as a PEM block
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+impl GeneratedCert {
+    pub fn cert_pem(&self) -> String {
+        pem("CERTIFICATE", &self.cert)
+    }
+
+    pub fn key_pem(&self) -> String {
+        pem("PRIVATE KEY", &self.key)
+    }
+}
+
+/// Encodes a Name with just a common name
+fn common_name(name: &str) -> Vec<u8> {
+    let value = der::encode(UTF8_STRING, name.as_bytes());
+    let attribute = der::encode(der::SEQUENCE, &[COMMON_NAME_OID, &value].concat());
+    der::encode(der::SEQUENCE, &der::encode(der::SET, &attribute))
+}
+
+/// Encodes an extension with its DER value
+fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let mut contents = oid.to_vec();
+    if critical {
+        contents.extend(der::encode(der::BOOLEAN, &[0xff]));
+    }
+    contents.extend(der::encode(der::OCTET_STRING, value));
+    der::encode(der::SEQUENCE, &contents)
+}
+
+/// Encodes a P-256 public key as a SubjectPublicKeyInfo
+fn subject_public_key_info(key_pair: &EcdsaKeyPair) -> Vec<u8> {
+    let algorithm = der::encode(
+        der::SEQUENCE,
+        &[keys::EC_PUBLIC_KEY_OID, keys::P256_OID].concat(),
+    );
+    let public_key = der::encode_bit_string(key_pair.public_key().as_ref());
+    der::encode(der::SEQUENCE, &[algorithm, public_key].concat())
+}
+
+/// Returns a random positive serial number, as the contents of an INTEGER
+fn serial_number(rng: &SystemRandom) -> Result<Vec<u8>> {
+    let mut serial = vec![0; 16];
+    rng.fill(&mut serial)
+        .map_err(|_| "error generating a serial number")?;
+    // Clearing the top bit keeps it positive, and a non-zero first byte keeps the encoding minimal
+    serial[0] = (serial[0] & 0x7f).max(1);
+    Ok(serial)
+}
+
+fn generate_key(rng: &SystemRandom) -> Result<(Vec<u8>, EcdsaKeyPair)> {
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, rng)
+        .map_err(|_| "error generating a private key")?;
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+        .map_err(|e| format!("generated private key is unusable: {}", e))?;
+    Ok((pkcs8.as_ref().to_vec(), key_pair))
+}
+
+/// The parts of a certificate that differ between the CA and leaves
+struct CertFields<'a> {
+    subject: &'a str,
+    issuer: &'a str,
+    extensions: Vec<Vec<u8>>,
+    validity: Duration,
+}
+
+/// Builds a certificate for the subject's key, signed by the issuer's key
+fn sign_certificate(
+    fields: CertFields,
+    subject_key: &EcdsaKeyPair,
+    issuer_key: &EcdsaKeyPair,
+    rng: &SystemRandom,
+) -> Result<Vec<u8>> {
+    let version = der::encode(EXPLICIT_0, &der::encode(der::INTEGER, &[2]));
+    let serial_number = der::encode(der::INTEGER, &serial_number(rng)?);
+    let signature_algorithm = der::encode(der::SEQUENCE, ECDSA_WITH_SHA256_OID);
+    let not_before = SystemTime::now() - BACKDATE;
+    let validity = der::encode(
+        der::SEQUENCE,
+        &[
+            der::encode_time(not_before),
+            der::encode_time(not_before + BACKDATE + fields.validity),
+        ]
+        .concat(),
+    );
+    let extensions = der::encode(
+        EXPLICIT_3,
+        &der::encode(der::SEQUENCE, &fields.extensions.concat()),
+    );
+
+    let tbs_certificate = der::encode(
+        der::SEQUENCE,
+        &[
+            version,
+            serial_number,
+            signature_algorithm.clone(),
+            common_name(fields.issuer),
+            validity,
+            common_name(fields.subject),
+            subject_public_key_info(subject_key),
+            extensions,
+        ]
+        .concat(),
+    );
+    let signature = issuer_key
+        .sign(rng, &tbs_certificate)
+        .map_err(|_| "error signing certificate")?;
+    Ok(der::encode(
+        der::SEQUENCE,
+        &[
+            tbs_certificate,
+            signature_algorithm,
+            der::encode_bit_string(signature.as_ref()),
+        ]
+        .concat(),
+    ))
+}
+
+/// A CA that issues the proxies' certificates. It can only sign leaf certificates, not other CAs.
+pub struct CertificateAuthority {
+    name: String,
+    cert: GeneratedCert,
+    key_pair: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl CertificateAuthority {
+    /// Creates a CA with a new key and a self-signed certificate with the common name
+    pub fn new(name: &str, validity: Duration) -> Result<CertificateAuthority> {
+        let rng = SystemRandom::new();
+        let (key, key_pair) = generate_key(&rng)?;
+        let fields = CertFields {
+            subject: name,
+            issuer: name,
+            extensions: vec![
+                // CA with a path length of 0
+                extension(
+                    BASIC_CONSTRAINTS_OID,
+                    true,
+                    &der::encode(
+                        der::SEQUENCE,
+                        &[
+                            der::encode(der::BOOLEAN, &[0xff]),
+                            der::encode(der::INTEGER, &[0]),
+                        ]
+                        .concat(),
+                    ),
+                ),
+                // keyCertSign and cRLSign
+                extension(KEY_USAGE_OID, true, &[der::BIT_STRING, 0x02, 0x01, 0x06]),
+            ],
+            validity,
+        };
+        let cert = sign_certificate(fields, &key_pair, &key_pair, &rng)?;
+        Ok(CertificateAuthority {
+            name: name.to_string(),
+            cert: GeneratedCert { cert, key },
+            key_pair,
+            rng,
+        })
+    }
+
+    pub fn cert(&self) -> &GeneratedCert {
+        &self.cert
+    }
+
+    /// Issues a certificate with a new key that is valid for the names. The first name is also
+    /// used as the common name.
+    pub fn issue(
+        &self,
+        names: &[SubjectAltName],
+        usage: LeafUsage,
+        validity: Duration,
+    ) -> Result<GeneratedCert> {
+        let subject = match names.first() {
+            Some(name) => name.to_string(),
+            None => bail!("certificates need at least one name"),
+        };
+        let alt_names: Vec<u8> = names.iter().flat_map(SubjectAltName::encode).collect();
+        let usage_oid = match usage {
+            LeafUsage::Server => SERVER_AUTH_OID,
+            LeafUsage::Client => CLIENT_AUTH_OID,
+        };
+
+        let (key, key_pair) = generate_key(&self.rng)?;
+        let fields = CertFields {
+            subject: &subject,
+            issuer: &self.name,
+            extensions: vec![
+                // Not a CA
+                extension(
+                    BASIC_CONSTRAINTS_OID,
+                    true,
+                    &der::encode(der::SEQUENCE, &[]),
+                ),
+                // digitalSignature
+                extension(KEY_USAGE_OID, true, &[der::BIT_STRING, 0x02, 0x07, 0x80]),
+                extension(
+                    EXTENDED_KEY_USAGE_OID,
+                    false,
+                    &der::encode(der::SEQUENCE, usage_oid),
+                ),
+                extension(
+                    SUBJECT_ALT_NAME_OID,
+                    false,
+                    &der::encode(der::SEQUENCE, &alt_names),
+                ),
+            ],
+            validity,
+        };
+        let cert = sign_certificate(fields, &key_pair, &self.key_pair, &self.rng)?;
+        Ok(GeneratedCert { cert, key })
+    }
+}
+
+/// What the certs subcommand creates
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertsSettings {
+    /// Directory the PEM files are written to
+    pub out_dir: PathBuf,
+    /// Common name of the CA
+    pub ca_name: String,
+    /// Names the reverse proxy certificate is valid for
+    pub server_names: Vec<SubjectAltName>,
+    /// Names in the forward proxy's client certificate
+    pub client_names: Vec<SubjectAltName>,
+    pub validity_days: u32,
+    /// Whether existing files are replaced
+    pub overwrite: bool,
+}
+
+impl CertsSettings {
+    /// Creates settings for a reverse proxy reached as localhost, valid for DEFAULT_VALIDITY_DAYS
+    pub fn new(out_dir: impl Into<PathBuf>) -> CertsSettings {
+        CertsSettings {
+            out_dir: out_dir.into(),
+            ca_name: "Rust TLS Proxy CA".to_string(),
+            server_names: vec![SubjectAltName::Dns("localhost".to_string())],
+            client_names: vec![SubjectAltName::Dns("forward-proxy".to_string())],
+            validity_days: DEFAULT_VALIDITY_DAYS,
+            overwrite: false,
+        }
+    }
+}
+
+/// Names of the files written by write_certs(), the same names the proxies' flags default to
+pub const CA_CERT_FILE: &str = "ca_cert.pem";
+pub const CA_KEY_FILE: &str = "ca_key.pem";
+pub const CERT_FILE: &str = "cert.pem";
+pub const KEY_FILE: &str = "key.pem";
+pub const CLIENT_CERT_FILE: &str = "client_cert.pem";
+pub const CLIENT_KEY_FILE: &str = "client_key.pem";
+
+/// Writes a file, readable only by its owner if it holds a private key
+fn write_file(path: &Path, contents: &str, private: bool, overwrite: bool) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options
+        .write(true)
+        .mode(if private { 0o600 } else { 0o644 });
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .chain_err(|| format!("error writing {}", path.display()))
+}
+
+/// Creates a CA, a reverse proxy certificate and a forward proxy client certificate, and writes
+/// them and their keys as PEM files. Returns the paths written. Existing files are only replaced
+/// if `overwrite` is set, so a CA that certificates were already issued from isn't lost.
+pub fn write_certs(settings: &CertsSettings) -> Result<Vec<PathBuf>> {
+    let validity = Duration::from_secs(u64::from(settings.validity_days) * 24 * 60 * 60);
+    let ca = CertificateAuthority::new(&settings.ca_name, validity)?;
+    let server = ca.issue(&settings.server_names, LeafUsage::Server, validity)?;
+    let client = ca.issue(&settings.client_names, LeafUsage::Client, validity)?;
+
+    let files = [
+        (CA_CERT_FILE, ca.cert().cert_pem(), false),
+        (CA_KEY_FILE, ca.cert().key_pem(), true),
+        (CERT_FILE, server.cert_pem(), false),
+        (KEY_FILE, server.key_pem(), true),
+        (CLIENT_CERT_FILE, client.cert_pem(), false),
+        (CLIENT_KEY_FILE, client.key_pem(), true),
+    ];
+    let paths: Vec<PathBuf> = files
+        .iter()
+        .map(|(name, _, _)| settings.out_dir.join(name))
+        .collect();
+    if !settings.overwrite {
+        if let Some(existing) = paths.iter().find(|path| path.exists()) {
+            bail!(
+                "{} already exists, pass --force to replace it",
+                existing.display()
+            );
+        }
+    }
+
+    std::fs::create_dir_all(&settings.out_dir)
+        .chain_err(|| format!("error creating {}", settings.out_dir.display()))?;
+    for (path, (_, contents, private)) in paths.iter().zip(&files) {
+        write_file(path, contents, *private, settings.overwrite)?;
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::certgen::{
+        write_certs, CertificateAuthority, CertsSettings, LeafUsage, SubjectAltName,
+    };
+    use crate::tls::{load_certs, load_private_key, x509, CertKeyPaths};
+    use std::time::{Duration, SystemTime};
+    use tokio_rustls::webpki::{self, DNSNameRef};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    static ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[&webpki::ECDSA_P256_SHA256];
+
+    fn names(names: &[&str]) -> Vec<SubjectAltName> {
+        names.iter().map(|name| name.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_names() {
+        assert_eq!(
+            names(&["proxy.lab", "*.Example.com", "10.0.0.1", "::1"]),
+            vec![
+                SubjectAltName::Dns("proxy.lab".to_string()),
+                SubjectAltName::Dns("*.example.com".to_string()),
+                SubjectAltName::Ip("10.0.0.1".parse().unwrap()),
+                SubjectAltName::Ip("::1".parse().unwrap()),
+            ]
+        );
+        assert!("not a name".parse::<SubjectAltName>().is_err());
+        assert!("".parse::<SubjectAltName>().is_err());
+    }
+
+    #[test]
+    fn issued_certs_verify() {
+        let ca = CertificateAuthority::new("Test Lab CA", DAY).unwrap();
+        let server = ca
+            .issue(&names(&["proxy.lab", "10.0.0.1"]), LeafUsage::Server, DAY)
+            .unwrap();
+        let client = ca
+            .issue(&names(&["forward.lab"]), LeafUsage::Client, DAY)
+            .unwrap();
+        assert_eq!(x509::subject(&ca.cert().cert).unwrap(), "CN=Test Lab CA");
+        assert_eq!(x509::subject(&server.cert).unwrap(), "CN=proxy.lab");
+        assert_eq!(
+            x509::subject_alt_names(&server.cert).unwrap(),
+            vec!["DNS:proxy.lab".to_string(), "IP:10.0.0.1".to_string()]
+        );
+
+        let anchors =
+            [webpki::trust_anchor_util::cert_der_as_trust_anchor(&ca.cert().cert).unwrap()];
+        let anchors = webpki::TLSServerTrustAnchors(&anchors);
+        let now = webpki::Time::try_from(SystemTime::now()).unwrap();
+
+        let server_cert = webpki::EndEntityCert::from(&server.cert).unwrap();
+        server_cert
+            .verify_is_valid_tls_server_cert(ALGORITHMS, &anchors, &[], now)
+            .unwrap();
+        let name = DNSNameRef::try_from_ascii_str("proxy.lab").unwrap();
+        assert!(server_cert.verify_is_valid_for_dns_name(name).is_ok());
+        let other_name = DNSNameRef::try_from_ascii_str("other.lab").unwrap();
+        assert!(server_cert
+            .verify_is_valid_for_dns_name(other_name)
+            .is_err());
+
+        // The extended key usage keeps each certificate to its own role
+        let client_anchors = webpki::TLSClientTrustAnchors(anchors.0);
+        let client_cert = webpki::EndEntityCert::from(&client.cert).unwrap();
+        client_cert
+            .verify_is_valid_tls_client_cert(ALGORITHMS, &client_anchors, &[], now)
+            .unwrap();
+        assert!(client_cert
+            .verify_is_valid_tls_server_cert(ALGORITHMS, &anchors, &[], now)
+            .is_err());
+
+        // A CA with a path length of 0 can't vouch for other CAs' certificates
+        let other_ca = CertificateAuthority::new("Other CA", DAY).unwrap();
+        let other_ca_cert = webpki::EndEntityCert::from(&other_ca.cert().cert).unwrap();
+        assert!(other_ca_cert
+            .verify_is_valid_tls_server_cert(ALGORITHMS, &anchors, &[], now)
+            .is_err());
+
+        assert!(ca.issue(&[], LeafUsage::Server, DAY).is_err());
+    }
+
+    #[test]
+    fn write_pem_files() {
+        let dir =
+            std::env::temp_dir().join(format!("rust_tls_proxy_certgen_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let settings = CertsSettings::new(&dir);
+        let paths = write_certs(&settings).unwrap();
+        assert_eq!(paths.len(), 6);
+
+        // The files load the same way as the proxies' flags load them
+        let server = CertKeyPaths::new(dir.join("cert.pem"), dir.join("key.pem"));
+        assert!(crate::tls::certified_key(&server).is_ok());
+        let client = CertKeyPaths::new(dir.join("client_cert.pem"), dir.join("client_key.pem"));
+        assert!(crate::tls::certified_key(&client).is_ok());
+        assert!(load_certs(&dir.join("ca_cert.pem")).is_ok());
+        assert!(load_private_key(&dir.join("ca_key.pem")).is_ok());
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.join("key.pem"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // An existing CA isn't replaced by accident
+        let ca_cert = std::fs::read(dir.join("ca_cert.pem")).unwrap();
+        assert!(write_certs(&settings).is_err());
+        assert_eq!(std::fs::read(dir.join("ca_cert.pem")).unwrap(), ca_cert);
+
+        let mut settings = settings;
+        settings.overwrite = true;
+        assert!(write_certs(&settings).is_ok());
+        assert_ne!(std::fs::read(dir.join("ca_cert.pem")).unwrap(), ca_cert);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
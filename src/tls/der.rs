@@ -0,0 +1,320 @@
+// Just enough DER to read the parts of certificates and OCSP responses the proxies need, and to
+// write keys and certificates.
+
+use crate::errors::*;
+use error_chain::bail;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const BOOLEAN: u8 = 0x01;
+pub const INTEGER: u8 = 0x02;
+pub const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+pub const OID: u8 = 0x06;
+pub const ENUMERATED: u8 = 0x0a;
+pub const UTC_TIME: u8 = 0x17;
+pub const GENERALIZED_TIME: u8 = 0x18;
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+
+/// Encodes a DER tag and length followed by the contents
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        encoded.push(0x80 | len_bytes.len() as u8);
+        encoded.extend(len_bytes);
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+/// A DER value read from the front of some data
+#[derive(Debug, PartialEq)]
+pub struct Value<'a> {
+    pub tag: u8,
+    pub contents: &'a [u8],
+    /// The tag, length and contents
+    pub encoded: &'a [u8],
+}
+
+/// Reads the value at the front of the data, and returns it with the data after it. Only single
+/// byte tags are supported.
+pub fn read(data: &[u8]) -> Result<(Value<'_>, &[u8])> {
+    let (tag, first_len) = match data {
+        [tag, first_len, ..] => (*tag, *first_len),
+        _ => bail!("DER value is truncated"),
+    };
+    let (header_len, len) = if first_len < 0x80 {
+        (2, first_len as usize)
+    } else {
+        let len_bytes = (first_len & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > std::mem::size_of::<usize>() {
+            bail!("unsupported DER length");
+        }
+        let len = data
+            .get(2..2 + len_bytes)
+            .ok_or("DER length is truncated")?
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize);
+        (2 + len_bytes, len)
+    };
+    let end = header_len
+        .checked_add(len)
+        .filter(|end| *end <= data.len())
+        .ok_or("DER value is truncated")?;
+
+    let value = Value {
+        tag,
+        contents: &data[header_len..end],
+        encoded: &data[..end],
+    };
+    Ok((value, &data[end..]))
+}
+
+/// Reads the value at the front of the data, which must have the tag
+pub fn read_tagged(data: &[u8], tag: u8) -> Result<(Value<'_>, &[u8])> {
+    let (value, rest) = read(data)?;
+    if value.tag != tag {
+        bail!("expected DER tag {:#04x}, got {:#04x}", tag, value.tag);
+    }
+    Ok((value, rest))
+}
+
+/// Reads every value in the contents of a SEQUENCE or SET
+pub fn read_all(mut data: &[u8]) -> Result<Vec<Value<'_>>> {
+    let mut values = Vec::new();
+    while !data.is_empty() {
+        let (value, rest) = read(data)?;
+        values.push(value);
+        data = rest;
+    }
+    Ok(values)
+}
+
+/// Formats the contents of an OID in dotted decimal, e.g. 2.5.4.3
+pub fn oid_to_string(contents: &[u8]) -> Result<String> {
+    let mut arcs = Vec::new();
+    let mut arc: u64 = 0;
+    for byte in contents {
+        if arc > u64::MAX >> 7 {
+            bail!("OID component is too large");
+        }
+        arc = arc << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    if contents.last().is_none_or(|byte| byte & 0x80 != 0) {
+        bail!("OID is truncated");
+    }
+
+    // The first component encodes the first two arcs
+    let (first, second) = match arcs[0] {
+        first @ 0..=79 => (first / 40, first % 40),
+        first => (2, first - 80),
+    };
+    let mut dotted = format!("{}.{}", first, second);
+    for arc in &arcs[1..] {
+        dotted.push_str(&format!(".{}", arc));
+    }
+    Ok(dotted)
+}
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Counts from March so the leap day is at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date in the proleptic Gregorian calendar of a number of days from 1970-01-01, the inverse of
+/// days_from_civil()
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Encodes a time the way certificates must: UTCTime up to 2049 and GeneralizedTime from 2050,
+/// to the second
+pub fn encode_time(time: SystemTime) -> Vec<u8> {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let second_of_day = seconds.rem_euclid(86_400);
+    let time_of_day = format!(
+        "{:02}{:02}{:02}Z",
+        second_of_day / 3_600,
+        second_of_day / 60 % 60,
+        second_of_day % 60
+    );
+    if year < 2050 {
+        let text = format!("{:02}{:02}{:02}{}", year % 100, month, day, time_of_day);
+        encode(UTC_TIME, text.as_bytes())
+    } else {
+        let text = format!("{:04}{:02}{:02}{}", year, month, day, time_of_day);
+        encode(GENERALIZED_TIME, text.as_bytes())
+    }
+}
+
+/// Encodes a BIT STRING with no unused bits
+pub fn encode_bit_string(bytes: &[u8]) -> Vec<u8> {
+    encode(BIT_STRING, &[&[0][..], bytes].concat())
+}
+
+/// Reads a UTCTime or GeneralizedTime in UTC without fractional seconds, the forms certificates
+/// and OCSP responses use, e.g. `491231235959Z` or `20491231235959Z`
+pub fn read_time(value: &Value<'_>) -> Result<SystemTime> {
+    let text = std::str::from_utf8(value.contents).map_err(|_| "invalid DER time")?;
+    let digits = match (value.tag, text.strip_suffix('Z')) {
+        (UTC_TIME, Some(digits)) if digits.len() == 12 => {
+            // Two digit years from 50 are in the 1900s
+            let century = if digits[..2] >= *"50" { "19" } else { "20" };
+            format!("{}{}", century, digits)
+        }
+        (GENERALIZED_TIME, Some(digits)) if digits.len() == 14 => digits.to_string(),
+        _ => bail!("unsupported DER time \"{}\"", text),
+    };
+    if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        bail!("invalid DER time \"{}\"", text);
+    }
+    let field = |range: std::ops::Range<usize>| digits[range].parse::<i64>().unwrap();
+    let (month, day) = (field(4..6), field(6..8));
+    let (hour, minute, second) = (field(8..10), field(10..12), field(12..14));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        bail!("invalid DER time \"{}\"", text);
+    }
+
+    let seconds =
+        days_from_civil(field(0..4), month, day) * 86_400 + hour * 3_600 + minute * 60 + second;
+    match u64::try_from(seconds) {
+        Ok(seconds) => Ok(UNIX_EPOCH + Duration::from_secs(seconds)),
+        Err(_) => bail!("DER time \"{}\" is before 1970", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::der::{
+        encode, encode_time, oid_to_string, read, read_all, read_tagged, read_time,
+        GENERALIZED_TIME, SEQUENCE, UTC_TIME,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn encode_lengths() {
+        assert_eq!(encode(0x04, &[1, 2]), vec![0x04, 0x02, 1, 2]);
+        assert_eq!(&encode(0x04, &[0; 0x80])[..3], &[0x04, 0x81, 0x80]);
+        assert_eq!(&encode(0x04, &[0; 0x1234])[..4], &[0x04, 0x82, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn read_values() {
+        let long = encode(0x04, &[7; 0x1234]);
+        let data = [&encode(SEQUENCE, &[0x02, 0x01, 0x05])[..], &long].concat();
+
+        let (value, rest) = read_tagged(&data, SEQUENCE).unwrap();
+        assert_eq!(value.contents, &[0x02, 0x01, 0x05]);
+        assert_eq!(value.encoded, &data[..5]);
+        assert_eq!(read_all(value.contents).unwrap()[0].contents, &[0x05]);
+
+        let (value, rest) = read(rest).unwrap();
+        assert_eq!(value.contents.len(), 0x1234);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn read_errors() {
+        assert!(read(&[]).is_err());
+        assert!(read(&[0x04, 0x03, 1, 2]).is_err());
+        assert!(read(&[0x04, 0x82, 0x01]).is_err());
+        assert!(read(&[0x04, 0x80]).is_err());
+        assert!(read_tagged(&[0x04, 0x00], SEQUENCE).is_err());
+    }
+
+    #[test]
+    fn oids() {
+        assert_eq!(oid_to_string(&[0x55, 0x04, 0x03]).unwrap(), "2.5.4.3");
+        assert_eq!(
+            oid_to_string(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01]).unwrap(),
+            "1.2.840.10045.2.1"
+        );
+        assert!(oid_to_string(&[0x2a, 0x86]).is_err());
+        assert!(oid_to_string(&[]).is_err());
+    }
+
+    #[test]
+    fn times() {
+        let time = |tag, text: &str| {
+            let encoded = encode(tag, text.as_bytes());
+            read_time(&read(&encoded).unwrap().0)
+        };
+        assert_eq!(
+            time(GENERALIZED_TIME, "19700101000000Z").unwrap(),
+            UNIX_EPOCH
+        );
+        // 2026-10-18 07:18:35, and a leap day
+        assert_eq!(
+            time(GENERALIZED_TIME, "20261018071835Z").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_792_307_915)
+        );
+        assert_eq!(
+            time(UTC_TIME, "240229120000Z").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_709_208_000)
+        );
+        assert_eq!(time(UTC_TIME, "700101000000Z").unwrap(), UNIX_EPOCH);
+
+        assert!(time(GENERALIZED_TIME, "20261018071835").is_err());
+        assert!(time(GENERALIZED_TIME, "20261318071835Z").is_err());
+        assert!(time(UTC_TIME, "20261018071835Z").is_err());
+        assert!(time(SEQUENCE, "20261018071835Z").is_err());
+    }
+
+    #[test]
+    fn encode_times() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_208_000);
+        assert_eq!(encode_time(time), encode(UTC_TIME, b"240229120000Z"));
+        assert_eq!(
+            read_time(&read(&encode_time(time)).unwrap().0).unwrap(),
+            time
+        );
+
+        // 2050-01-01 00:00:01
+        let time = UNIX_EPOCH + Duration::from_secs(2_524_608_001);
+        assert_eq!(
+            encode_time(time),
+            encode(GENERALIZED_TIME, b"20500101000001Z")
+        );
+        assert_eq!(
+            read_time(&read(&encode_time(time)).unwrap().0).unwrap(),
+            time
+        );
+    }
+}
@@ -0,0 +1,507 @@
+// OCSP responses stapled to the reverse proxy's certificates. The responses are read from files
+// kept up to date by something else, e.g. a cron job running `openssl ocsp`. Their signatures
+// aren't checked here, that is up to the clients they are stapled for.
+
+use crate::errors::*;
+use crate::tls::reload::{self, ReloadableConfig};
+use crate::tls::{der, x509, ServerTlsSettings};
+use error_chain::bail;
+use ring::digest;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::{Certificate, ServerConfig};
+
+/// How long before a stapled response's nextUpdate time warnings start
+pub const EXPIRY_WARNING: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often stapled responses are checked for their nextUpdate time
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// OID of the basic OCSP response type, the only one in use
+const BASIC_RESPONSE_OID: &str = "1.3.6.1.5.5.7.48.1.1";
+
+/// OIDs of the hash algorithms accepted in certificate IDs. Responders use SHA-1 unless asked for
+/// something else.
+const SHA1_OID: &str = "1.3.14.3.2.26";
+const SHA256_OID: &str = "2.16.840.1.101.3.4.2.1";
+
+/// Context specific tags of the fields read from OCSP responses
+const EXPLICIT_0: u8 = 0xa0;
+const CERT_STATUS_GOOD: u8 = 0x80;
+const CERT_STATUS_REVOKED: u8 = 0xa1;
+const CERT_STATUS_UNKNOWN: u8 = 0x82;
+
+/// When the status in an OCSP response was known to be correct, and when newer status will be
+/// available
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Validity {
+    pub this_update: SystemTime,
+    /// Left out by responders that always have newer status available
+    pub next_update: Option<SystemTime>,
+}
+
+/// Status of a certificate in an OCSP response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Good(Validity),
+    Revoked,
+    /// The responder doesn't know the certificate
+    Unknown,
+}
+
+/// How close a response is to its nextUpdate time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    /// No nextUpdate time, or it is further away than EXPIRY_WARNING
+    NotSoon,
+    /// The nextUpdate time is this far away
+    Within(Duration),
+    /// The nextUpdate time was this long ago
+    Expired(Duration),
+}
+
+impl Validity {
+    pub fn expiry(&self, now: SystemTime) -> Expiry {
+        match self
+            .next_update
+            .map(|next_update| next_update.duration_since(now))
+        {
+            None => Expiry::NotSoon,
+            Some(Ok(left)) if left > EXPIRY_WARNING => Expiry::NotSoon,
+            Some(Ok(left)) => Expiry::Within(left),
+            Some(Err(e)) => Expiry::Expired(e.duration()),
+        }
+    }
+}
+
+fn response_status_name(status: &[u8]) -> String {
+    match status {
+        [1] => "malformedRequest".to_string(),
+        [2] => "internalError".to_string(),
+        [3] => "tryLater".to_string(),
+        [5] => "sigRequired".to_string(),
+        [6] => "unauthorized".to_string(),
+        _ => format!("{:?}", status),
+    }
+}
+
+/// Returns the SingleResponse values in a DER OCSPResponse
+fn single_responses(response: &[u8]) -> Result<Vec<der::Value<'_>>> {
+    let (response, _) = der::read_tagged(response, der::SEQUENCE)?;
+    let (status, rest) = der::read_tagged(response.contents, der::ENUMERATED)?;
+    if status.contents != [0] {
+        bail!(
+            "OCSP responder returned {} instead of a certificate status",
+            response_status_name(status.contents)
+        );
+    }
+
+    let (response_bytes, _) = der::read_tagged(rest, EXPLICIT_0)?;
+    let (response_bytes, _) = der::read_tagged(response_bytes.contents, der::SEQUENCE)?;
+    let (response_type, rest) = der::read_tagged(response_bytes.contents, der::OID)?;
+    let response_type = der::oid_to_string(response_type.contents)?;
+    if response_type != BASIC_RESPONSE_OID {
+        bail!("unsupported OCSP response type {}", response_type);
+    }
+    let (basic_response, _) = der::read_tagged(rest, der::OCTET_STRING)?;
+
+    let (basic_response, _) = der::read_tagged(basic_response.contents, der::SEQUENCE)?;
+    let (response_data, _) = der::read_tagged(basic_response.contents, der::SEQUENCE)?;
+    // The version, responder ID and time the response was produced are tagged differently, so
+    // the responses are the first SEQUENCE
+    let responses = der::read_all(response_data.contents)?
+        .into_iter()
+        .find(|field| field.tag == der::SEQUENCE)
+        .ok_or("OCSP response has no certificate status")?;
+    der::read_all(responses.contents)
+}
+
+/// Returns whether the fields of a certificate ID identify the DER certificate, by its serial number
+/// and the hashes of its issuer's name and public key
+fn cert_id_matches(cert_id: &[der::Value<'_>], cert: &[u8], issuer: &[u8]) -> Result<bool> {
+    let (hash_algorithm, name_hash, key_hash, serial) = match cert_id {
+        [hash_algorithm, name_hash, key_hash, serial]
+            if hash_algorithm.tag == der::SEQUENCE
+                && name_hash.tag == der::OCTET_STRING
+                && key_hash.tag == der::OCTET_STRING
+                && serial.tag == der::INTEGER =>
+        {
+            (hash_algorithm, name_hash, key_hash, serial)
+        }
+        _ => bail!("invalid certificate ID in OCSP response"),
+    };
+    if serial.contents != x509::serial_number(cert)? {
+        return Ok(false);
+    }
+
+    let (oid, _) = der::read_tagged(hash_algorithm.contents, der::OID)?;
+    let algorithm = match der::oid_to_string(oid.contents)?.as_str() {
+        SHA1_OID => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        SHA256_OID => &digest::SHA256,
+        oid => bail!("unsupported hash algorithm {} in OCSP certificate ID", oid),
+    };
+    let hash = |data| digest::digest(algorithm, data);
+    Ok(
+        name_hash.contents == hash(x509::subject_name(issuer)?).as_ref()
+            && key_hash.contents == hash(x509::public_key(issuer)?).as_ref(),
+    )
+}
+
+/// Reads the status of a DER certificate issued by `issuer` from a DER OCSP response. Returns an
+/// error if the response has no status for it.
+pub fn read_status(response: &[u8], cert: &[u8], issuer: &[u8]) -> Result<Status> {
+    for single_response in single_responses(response)? {
+        let fields = der::read_all(single_response.contents)?;
+        let (cert_id, cert_status, this_update) = match &fields[..] {
+            [cert_id, cert_status, this_update, ..] => (cert_id, cert_status, this_update),
+            _ => bail!("OCSP response is missing certificate status fields"),
+        };
+        // Another certificate with the same serial number from a different CA isn't a match
+        if !cert_id_matches(&der::read_all(cert_id.contents)?, cert, issuer)? {
+            continue;
+        }
+
+        match cert_status.tag {
+            CERT_STATUS_GOOD => (),
+            CERT_STATUS_REVOKED => return Ok(Status::Revoked),
+            CERT_STATUS_UNKNOWN => return Ok(Status::Unknown),
+            tag => bail!("unknown certificate status {:#04x} in OCSP response", tag),
+        }
+        let next_update = match fields.get(3) {
+            Some(field) if field.tag == EXPLICIT_0 => {
+                Some(der::read_time(&der::read(field.contents)?.0)?)
+            }
+            _ => None,
+        };
+        return Ok(Status::Good(Validity {
+            this_update: der::read_time(this_update)?,
+            next_update,
+        }));
+    }
+    bail!("OCSP response isn't for this certificate")
+}
+
+/// Formats a duration in hours and minutes for logs
+fn hours_and_minutes(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    format!("{}h{:02}m", minutes / 60, minutes % 60)
+}
+
+/// Reads the OCSP response to staple to the first certificate of a chain, and logs a warning if it
+/// is close to its nextUpdate time. The chain must hold the certificate's issuer next, to check the
+/// response is for it. Returns None and logs an error if the response says the certificate is
+/// revoked or unknown, or is past its nextUpdate time, since clients would reject the certificate
+/// or the response.
+pub fn load_staple(path: &Path, chain: &[Certificate]) -> Result<Option<Vec<u8>>> {
+    let (cert, issuer) = match chain {
+        [cert, issuer, ..] => (&cert.0, &issuer.0),
+        _ => bail!(
+            "certificate chain has no issuer certificate to check OCSP response {} against",
+            path.display()
+        ),
+    };
+    let response = std::fs::read(path).chain_err(|| format!("error opening {}", path.display()))?;
+    let status = read_status(&response, cert, issuer)
+        .chain_err(|| format!("invalid OCSP response {}", path.display()))?;
+
+    let validity = match status {
+        Status::Good(validity) => validity,
+        Status::Revoked => {
+            eprintln!(
+                "OCSP response {} says the certificate has been revoked, not stapling it",
+                path.display()
+            );
+            return Ok(None);
+        }
+        Status::Unknown => {
+            eprintln!(
+                "OCSP response {} says the responder doesn't know the certificate, not stapling it",
+                path.display()
+            );
+            return Ok(None);
+        }
+    };
+    match validity.expiry(SystemTime::now()) {
+        Expiry::NotSoon => Ok(Some(response)),
+        Expiry::Within(left) => {
+            eprintln!(
+                "OCSP response {} reaches its nextUpdate time in {}, it should be refreshed",
+                path.display(),
+                hours_and_minutes(left)
+            );
+            Ok(Some(response))
+        }
+        Expiry::Expired(ago) => {
+            eprintln!(
+                "OCSP response {} passed its nextUpdate time {} ago, not stapling it",
+                path.display(),
+                hours_and_minutes(ago)
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Checks the OCSP response of each certificate every `check_interval`, logging a warning for the
+/// ones close to or past their nextUpdate time. Responses are only reloaded when their files
+/// change, so when a stapled response passes its nextUpdate time the config is rebuilt without
+/// it, and whatever refreshes the files having stopped shows up in the logs. Runs until the task
+/// is dropped.
+pub async fn watch_expiry(
+    settings: ServerTlsSettings,
+    config: Arc<ReloadableConfig<ServerConfig>>,
+    check_interval: Duration,
+) {
+    let mut interval = tokio::time::interval(check_interval);
+    // The first tick completes straight away, and the responses were just checked when loading
+    interval.tick().await;
+    // Responses that couldn't be stapled at the last check
+    let mut unstapled = HashSet::new();
+    loop {
+        interval.tick().await;
+        let mut rebuild = false;
+        for paths in std::iter::once(&settings.cert).chain(settings.sni_certs.values()) {
+            let ocsp = match &paths.ocsp {
+                Some(ocsp) => ocsp,
+                None => continue,
+            };
+            let staple = crate::tls::load_certs(&paths.cert_chain)
+                .and_then(|certs| load_staple(ocsp, &certs));
+            match staple {
+                Ok(Some(_)) => {
+                    unstapled.remove(ocsp);
+                }
+                // Only a response that could be stapled until now needs the config rebuilt
+                Ok(None) => rebuild |= unstapled.insert(ocsp.clone()),
+                Err(e) => eprintln!(
+                    "error checking OCSP response {}: {}",
+                    ocsp.display(),
+                    error_chain::ChainedError::display_chain(&e)
+                ),
+            }
+        }
+
+        if rebuild {
+            match reload::reload(&settings, &config) {
+                Ok(()) => println!("OCSP response can no longer be stapled, reloaded TLS config"),
+                Err(e) => eprintln!(
+                    "OCSP response can no longer be stapled, but the TLS config was not \
+                     reloaded: {}",
+                    error_chain::ChainedError::display_chain(&e)
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::ocsp::{
+        load_staple, read_status, watch_expiry, Expiry, Status, Validity, EXPIRY_WARNING,
+    };
+    use crate::tls::{
+        der, load_certs, server_config, CertKeyPaths, ReloadableConfig, ServerTlsSettings,
+    };
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::{
+        Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier,
+        ServerConfig, TLSError,
+    };
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    /// Responses for tests/certs/cert.pem, issued by tests/certs/ca_cert.pem, made with
+    /// `openssl ocsp -index ... -ndays 36500`
+    const GOOD_RESPONSE: &str = "tests/certs/ocsp/cert_good.der";
+    const REVOKED_RESPONSE: &str = "tests/certs/ocsp/cert_revoked.der";
+
+    fn cert(path: &str) -> Vec<u8> {
+        load_certs(Path::new(path)).unwrap().remove(0).0
+    }
+
+    fn chain(paths: &[&str]) -> Vec<Certificate> {
+        paths.iter().map(|path| Certificate(cert(path))).collect()
+    }
+
+    #[test]
+    fn read_good_response() {
+        let response = std::fs::read(GOOD_RESPONSE).unwrap();
+        let cert = cert("tests/certs/cert.pem");
+        let issuer = self::cert("tests/certs/ca_cert.pem");
+        let validity = match read_status(&response, &cert, &issuer).unwrap() {
+            Status::Good(validity) => validity,
+            status => panic!("unexpected status {:?}", status),
+        };
+        // 2026-10-18 07:18:35 and 36500 days later
+        assert_eq!(
+            validity.this_update,
+            UNIX_EPOCH + Duration::from_secs(1_792_307_915)
+        );
+        assert_eq!(
+            validity.next_update,
+            Some(UNIX_EPOCH + Duration::from_secs(1_792_307_915 + 36_500 * 86_400))
+        );
+
+        let chain = chain(&["tests/certs/cert.pem", "tests/certs/ca_cert.pem"]);
+        assert_eq!(
+            load_staple(Path::new(GOOD_RESPONSE), &chain).unwrap(),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn reject_unusable_responses() {
+        let chain = chain(&["tests/certs/cert.pem", "tests/certs/ca_cert.pem"]);
+        // Revoked certificates are still presented, without the response
+        let response = std::fs::read(REVOKED_RESPONSE).unwrap();
+        assert_eq!(
+            read_status(&response, &chain[0].0, &chain[1].0).unwrap(),
+            Status::Revoked
+        );
+        assert_eq!(
+            load_staple(Path::new(REVOKED_RESPONSE), &chain).unwrap(),
+            None
+        );
+        // A response for another certificate
+        let sni_chain = self::chain(&["tests/certs/sni_cert.pem", "tests/certs/sni_ca_cert.pem"]);
+        assert!(load_staple(Path::new(GOOD_RESPONSE), &sni_chain).is_err());
+        // Not a response at all
+        assert!(load_staple(Path::new("tests/certs/keys/rsa_pkcs1.der"), &chain).is_err());
+        // No issuer to check the response against
+        assert!(load_staple(Path::new(GOOD_RESPONSE), &chain[..1]).is_err());
+        // unauthorized
+        let cert = &chain[0].0;
+        assert!(read_status(&[0x30, 0x03, 0x0a, 0x01, 0x06], cert, cert).is_err());
+    }
+
+    #[test]
+    fn reject_response_from_another_issuer() {
+        // Same serial number, but the certificate ID hashes another CA's name and key
+        let response = std::fs::read(GOOD_RESPONSE).unwrap();
+        let cert = cert("tests/certs/cert.pem");
+        let sni_ca = self::cert("tests/certs/sni_ca_cert.pem");
+        let e = read_status(&response, &cert, &sni_ca).unwrap_err();
+        assert_eq!(e.to_string(), "OCSP response isn't for this certificate");
+    }
+
+    #[test]
+    fn expiry() {
+        let this_update = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let next_update = this_update + 2 * EXPIRY_WARNING;
+        let validity = Validity {
+            this_update,
+            next_update: Some(next_update),
+        };
+        assert_eq!(validity.expiry(this_update), Expiry::NotSoon);
+        assert_eq!(
+            validity.expiry(next_update - Duration::from_secs(60)),
+            Expiry::Within(Duration::from_secs(60))
+        );
+        assert_eq!(
+            validity.expiry(next_update + Duration::from_secs(60)),
+            Expiry::Expired(Duration::from_secs(60))
+        );
+
+        let validity = Validity {
+            this_update,
+            next_update: None,
+        };
+        assert_eq!(validity.expiry(next_update), Expiry::NotSoon);
+    }
+
+    /// GeneralizedTime, which OCSP responses use for every time
+    fn generalized_time(time: SystemTime) -> Vec<u8> {
+        let encoded = der::encode_time(time);
+        match encoded[0] {
+            der::UTC_TIME => der::encode(der::GENERALIZED_TIME, &[b"20", &encoded[2..]].concat()),
+            _ => encoded,
+        }
+    }
+
+    /// Verifier that accepts any certificate and keeps the OCSP response stapled to it
+    struct StapleRecorder(Mutex<Vec<u8>>);
+
+    impl ServerCertVerifier for StapleRecorder {
+        fn verify_server_cert(
+            &self,
+            _roots: &RootCertStore,
+            _presented_certs: &[Certificate],
+            _dns_name: DNSNameRef,
+            ocsp_response: &[u8],
+        ) -> Result<ServerCertVerified, TLSError> {
+            *self.0.lock().unwrap() = ocsp_response.to_vec();
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    /// Helper function that makes a connection to a server with the current config, and returns
+    /// the OCSP response stapled in the handshake, empty if there was none
+    async fn stapled_response(config: &ReloadableConfig<ServerConfig>) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(config.get());
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(conn).await;
+        });
+
+        let recorder = Arc::new(StapleRecorder(Mutex::new(Vec::new())));
+        let mut client_config = ClientConfig::new();
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::clone(&recorder) as Arc<dyn ServerCertVerifier>);
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        TlsConnector::from(Arc::new(client_config))
+            .connect(name, conn)
+            .await
+            .unwrap();
+        let response = recorder.0.lock().unwrap().clone();
+        response
+    }
+
+    #[tokio::test]
+    async fn stop_stapling_expired_response() {
+        let dir =
+            std::env::temp_dir().join(format!("rust_tls_proxy_ocsp_expiry_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // The good response with its nextUpdate time moved to 2 seconds from now. Signatures
+        // aren't checked, and the time keeps its length, so the response stays readable.
+        let response = std::fs::read(GOOD_RESPONSE).unwrap();
+        let next_update = UNIX_EPOCH + Duration::from_secs(1_792_307_915 + 36_500 * 86_400);
+        let next_update = generalized_time(next_update);
+        let at = response
+            .windows(next_update.len())
+            .position(|window| window == &next_update[..])
+            .unwrap();
+        let mut response = response;
+        response.splice(
+            at..at + next_update.len(),
+            generalized_time(SystemTime::now() + Duration::from_secs(2)),
+        );
+        std::fs::write(dir.join("cert.ocsp"), &response).unwrap();
+
+        let mut cert = CertKeyPaths::new("tests/certs/cert_chain.pem", "tests/certs/key.pem");
+        cert.ocsp = Some(dir.join("cert.ocsp"));
+        let settings = ServerTlsSettings::new(cert);
+        let config = Arc::new(ReloadableConfig::new(server_config(&settings).unwrap()));
+        assert_eq!(stapled_response(&config).await, response);
+
+        let watcher = tokio::spawn(watch_expiry(
+            settings,
+            Arc::clone(&config),
+            Duration::from_millis(100),
+        ));
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        // The file didn't change, but the response is no longer stapled
+        assert!(stapled_response(&config).await.is_empty());
+
+        watcher.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
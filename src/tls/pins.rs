@@ -0,0 +1,274 @@
+use crate::errors::*;
+use crate::tls::x509;
+use error_chain::bail;
+use ring::digest;
+use tokio_rustls::rustls::{
+    Certificate, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError, WebPKIVerifier,
+};
+use tokio_rustls::webpki::{self, DNSNameRef};
+
+/// Prefix of pins written as text, the same format curl's --pinnedpubkey uses
+const PIN_PREFIX: &str = "sha256//";
+
+/// SHA-256 hash of a certificate's DER encoded SubjectPublicKeyInfo
+pub type SpkiHash = [u8; 32];
+
+/// Returns the pin of a DER certificate
+pub fn spki_hash(cert: &[u8]) -> Result<SpkiHash> {
+    let digest = digest::digest(&digest::SHA256, x509::subject_public_key_info(cert)?);
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest.as_ref());
+    Ok(hash)
+}
+
+/// Formats a pin the way it is configured, e.g. `sha256//NojY2KjJdGYzj5le4ItRwkFL3l2aceXpyyB1lNXDIzQ=`
+pub fn format_pin(hash: &SpkiHash) -> String {
+    format!("{}{}", PIN_PREFIX, base64::encode(hash))
+}
+
+fn parse_pin(pin: &str) -> Result<SpkiHash> {
+    let encoded = pin
+        .strip_prefix(PIN_PREFIX)
+        .ok_or_else(|| format!("pin \"{}\" should start with {}", pin, PIN_PREFIX))?;
+    let decoded =
+        base64::decode(encoded).chain_err(|| format!("invalid base64 in pin \"{}\"", pin))?;
+    if decoded.len() != 32 {
+        bail!(
+            "pin \"{}\" should be a 32 byte SHA-256 hash, got {} bytes",
+            pin,
+            decoded.len()
+        );
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&decoded);
+    Ok(hash)
+}
+
+/// Hashes of the public keys that the reverse proxy with each server name may present. Several pins
+/// can be given for the same name, e.g. the current key and the one that will replace it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerPins {
+    entries: Vec<(String, SpkiHash)>,
+}
+
+impl ServerPins {
+    pub fn new() -> ServerPins {
+        ServerPins::default()
+    }
+
+    /// Adds a pin written as `sha256//` followed by the base64 hash for a server name
+    pub fn add(&mut self, name: &str, pin: &str) -> Result<()> {
+        DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| format!("invalid TLS server name \"{}\"", name))?;
+        self.entries
+            .push((name.to_ascii_lowercase(), parse_pin(pin)?));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the pins for a server name, empty if it isn't pinned
+    pub fn lookup(&self, name: &str) -> Vec<&SpkiHash> {
+        self.entries
+            .iter()
+            .filter(|(pinned_name, _)| pinned_name.eq_ignore_ascii_case(name))
+            .map(|(_, hash)| hash)
+            .collect()
+    }
+}
+
+impl std::str::FromStr for ServerPins {
+    type Err = Error;
+
+    /// Parses comma separated `name=pin` entries, e.g.
+    /// `proxy.lab=sha256//NojY2KjJdGYzj5le4ItRwkFL3l2aceXpyyB1lNXDIzQ=`
+    fn from_str(entries: &str) -> Result<ServerPins> {
+        let mut pins = ServerPins::new();
+        for entry in entries.split(',').filter(|entry| !entry.trim().is_empty()) {
+            match entry.split_once('=') {
+                Some((name, pin)) => pins.add(name.trim(), pin.trim())?,
+                None => bail!("expected name=sha256//hash, got \"{}\"", entry),
+            }
+        }
+        Ok(pins)
+    }
+}
+
+/// How pins are combined with the CA validation of reverse proxy certificates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PinMode {
+    /// Certificates of pinned reverse proxies must be signed by a trusted root and match a pin
+    WithCa,
+    /// Certificates of pinned reverse proxies only have to match a pin and be valid for the server
+    /// name. Reverse proxies without pins are still verified against the trusted roots.
+    InsteadOfCa,
+}
+
+/// Checks the key of each reverse proxy that has pins. Certificates of reverse proxies without pins
+/// are verified as usual.
+pub struct PinningVerifier {
+    pins: ServerPins,
+    mode: PinMode,
+}
+
+impl PinningVerifier {
+    pub fn new(pins: ServerPins, mode: PinMode) -> PinningVerifier {
+        PinningVerifier { pins, mode }
+    }
+}
+
+/// Formats a presented chain for logging, one certificate per line with its subject and pin
+fn describe_chain(presented_certs: &[Certificate]) -> String {
+    presented_certs
+        .iter()
+        .enumerate()
+        .map(|(i, cert)| {
+            let subject = x509::subject(&cert.0).unwrap_or_else(|e| e.to_string());
+            let pin = spki_hash(&cert.0)
+                .map(|hash| format_pin(&hash))
+                .unwrap_or_else(|e| e.to_string());
+            format!("  {}: {} {}", i, subject, pin)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        let name: &str = dns_name.into();
+        let pins = self.pins.lookup(name);
+        if pins.is_empty() || self.mode == PinMode::WithCa {
+            WebPKIVerifier::new().verify_server_cert(
+                roots,
+                presented_certs,
+                dns_name,
+                ocsp_response,
+            )?;
+        }
+        if pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let end_entity_cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        if self.mode == PinMode::InsteadOfCa {
+            webpki::EndEntityCert::from(&end_entity_cert.0)
+                .and_then(|cert| cert.verify_is_valid_for_dns_name(dns_name))
+                .map_err(TLSError::WebPKIError)?;
+        }
+
+        // Only the reverse proxy's own key is pinned, other certificates in the chain aren't
+        // verified without a CA
+        match spki_hash(&end_entity_cert.0) {
+            Ok(hash) if pins.contains(&&hash) => Ok(ServerCertVerified::assertion()),
+            _ => {
+                eprintln!(
+                    "certificate presented by {} doesn't match its pins, presented chain:\n{}",
+                    name,
+                    describe_chain(presented_certs)
+                );
+                Err(TLSError::General(format!(
+                    "certificate presented by {} doesn't match its pins",
+                    name
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::pins::{format_pin, spki_hash, PinMode, PinningVerifier, ServerPins};
+    use crate::tls::{load_certs, load_root_store};
+    use std::path::Path;
+    use tokio_rustls::rustls::{Certificate, RootCertStore, ServerCertVerifier};
+    use tokio_rustls::webpki::DNSNameRef;
+
+    /// Pin of tests/certs/cert.pem, from
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+    const CERT_PIN: &str = "sha256//NojY2KjJdGYzj5le4ItRwkFL3l2aceXpyyB1lNXDIzQ=";
+    const SNI_CERT_PIN: &str = "sha256//oAG3vdIU1LRd8cAKXYn9uJpevJmcLcQ9YGlbXnfqlVc=";
+
+    fn certs(path: &str) -> Vec<Certificate> {
+        load_certs(Path::new(path)).unwrap()
+    }
+
+    fn verify(
+        verifier: &PinningVerifier,
+        roots: &RootCertStore,
+        certs: &[Certificate],
+        name: &str,
+    ) -> bool {
+        let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+        verifier.verify_server_cert(roots, certs, name, &[]).is_ok()
+    }
+
+    #[test]
+    fn pins_match_openssl() {
+        let cert = &certs("tests/certs/cert.pem")[0];
+        assert_eq!(format_pin(&spki_hash(&cert.0).unwrap()), CERT_PIN);
+    }
+
+    #[test]
+    fn pin_parse_errors() {
+        assert!("localhost".parse::<ServerPins>().is_err());
+        assert!("localhost=NojY2KjJdGYzj5le4ItRwkFL3l2aceXpyyB1lNXDIzQ="
+            .parse::<ServerPins>()
+            .is_err());
+        assert!("localhost=sha256//AAAA".parse::<ServerPins>().is_err());
+        assert!(
+            "not a name=sha256//NojY2KjJdGYzj5le4ItRwkFL3l2aceXpyyB1lNXDIzQ="
+                .parse::<ServerPins>()
+                .is_err()
+        );
+
+        let pins: ServerPins = format!("LocalHost={},localhost={}", CERT_PIN, SNI_CERT_PIN)
+            .parse()
+            .unwrap();
+        assert_eq!(pins.lookup("localhost").len(), 2);
+        assert!(pins.lookup("example.test").is_empty());
+    }
+
+    #[test]
+    fn pins_with_ca() {
+        let roots = load_root_store(Path::new("tests/certs/ca_cert.pem")).unwrap();
+        let cert = certs("tests/certs/cert.pem");
+        let sni_cert = certs("tests/certs/sni_cert.pem");
+
+        let pins = format!("localhost={},example.test={}", CERT_PIN, SNI_CERT_PIN);
+        let verifier = PinningVerifier::new(pins.parse().unwrap(), PinMode::WithCa);
+        assert!(verify(&verifier, &roots, &cert, "localhost"));
+        // Matches its pin, but isn't signed by a trusted root
+        assert!(!verify(&verifier, &roots, &sni_cert, "example.test"));
+
+        let pins = format!("localhost={}", SNI_CERT_PIN);
+        let verifier = PinningVerifier::new(pins.parse().unwrap(), PinMode::WithCa);
+        assert!(!verify(&verifier, &roots, &cert, "localhost"));
+    }
+
+    #[test]
+    fn pins_instead_of_ca() {
+        let roots = RootCertStore::empty();
+        let cert = certs("tests/certs/cert.pem");
+        let sni_cert = certs("tests/certs/sni_cert.pem");
+
+        let pins = format!("localhost={},example.test={}", CERT_PIN, SNI_CERT_PIN);
+        let verifier = PinningVerifier::new(pins.parse().unwrap(), PinMode::InsteadOfCa);
+        assert!(verify(&verifier, &roots, &cert, "localhost"));
+        assert!(verify(&verifier, &roots, &sni_cert, "example.test"));
+        // The right key, but a certificate for another name
+        assert!(!verify(&verifier, &roots, &sni_cert, "localhost"));
+        // Unpinned names still need a trusted root
+        assert!(!verify(&verifier, &roots, &cert, "other.test"));
+        assert!(!verify(&verifier, &roots, &[], "localhost"));
+    }
+}
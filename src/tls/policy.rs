@@ -0,0 +1,327 @@
+// Protocol versions and cipher suites allowed on the hop between the proxies, shared by the forward
+// proxy's client config and the reverse proxy's server config. Key exchange groups aren't part of
+// the policy since rustls 0.19 has no setting to restrict them, it always uses x25519, secp384r1
+// and secp256r1.
+
+use crate::errors::*;
+use error_chain::bail;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{
+    ciphersuite, ClientConfig, ProtocolVersion, ServerConfig, SupportedCipherSuite,
+};
+
+/// Names used to select a policy preset, e.g. on the command line
+pub const PRESET_NAMES: &[&str] = &["modern", "compatible"];
+
+/// Names used to select protocol versions
+pub const VERSION_NAMES: &[&str] = &["1.2", "1.3"];
+
+/// Cipher suites rustls supports, by their IANA names, in the order rustls prefers them
+static CIPHER_SUITES: &[(&str, &SupportedCipherSuite)] = &[
+    (
+        "TLS_CHACHA20_POLY1305_SHA256",
+        &ciphersuite::TLS13_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        "TLS_AES_256_GCM_SHA384",
+        &ciphersuite::TLS13_AES_256_GCM_SHA384,
+    ),
+    (
+        "TLS_AES_128_GCM_SHA256",
+        &ciphersuite::TLS13_AES_128_GCM_SHA256,
+    ),
+    (
+        "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        &ciphersuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        &ciphersuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        &ciphersuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    ),
+    (
+        "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        &ciphersuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    ),
+    (
+        "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        &ciphersuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ),
+    (
+        "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        &ciphersuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ),
+];
+
+fn parse_version(name: &str) -> Result<ProtocolVersion> {
+    match name.to_ascii_lowercase().trim_start_matches("tls").trim() {
+        "1.2" => Ok(ProtocolVersion::TLSv1_2),
+        "1.3" => Ok(ProtocolVersion::TLSv1_3),
+        _ => bail!("unsupported TLS version \"{}\", expected 1.2 or 1.3", name),
+    }
+}
+
+fn version_name(version: ProtocolVersion) -> &'static str {
+    match version {
+        ProtocolVersion::TLSv1_2 => "TLS 1.2",
+        ProtocolVersion::TLSv1_3 => "TLS 1.3",
+        _ => "an unsupported TLS version",
+    }
+}
+
+/// Looks up a cipher suite by its IANA name, or the name rustls uses for TLS 1.3 suites, e.g.
+/// `TLS13_AES_128_GCM_SHA256`
+fn parse_cipher_suite(name: &str) -> Result<&'static SupportedCipherSuite> {
+    let iana_name = name.to_ascii_uppercase().replacen("TLS13_", "TLS_", 1);
+    match CIPHER_SUITES
+        .iter()
+        .find(|(suite_name, _)| *suite_name == iana_name)
+    {
+        Some((_, suite)) => Ok(suite),
+        None => bail!("unknown cipher suite \"{}\"", name),
+    }
+}
+
+/// Returns the IANA name of a cipher suite
+pub fn cipher_suite_name(suite: &SupportedCipherSuite) -> &'static str {
+    CIPHER_SUITES
+        .iter()
+        .find(|(_, known)| *known == suite)
+        .map_or("unknown cipher suite", |(name, _)| name)
+}
+
+/// Protocol versions and cipher suites the proxies may negotiate
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsPolicy {
+    pub versions: Vec<ProtocolVersion>,
+    /// Cipher suites in order of preference
+    pub cipher_suites: Vec<&'static SupportedCipherSuite>,
+}
+
+impl TlsPolicy {
+    /// TLS 1.3 only, with each of its cipher suites
+    pub fn modern() -> TlsPolicy {
+        TlsPolicy {
+            versions: vec![ProtocolVersion::TLSv1_3],
+            cipher_suites: CIPHER_SUITES
+                .iter()
+                .map(|(_, suite)| *suite)
+                .filter(|suite| suite.usable_for_version(ProtocolVersion::TLSv1_3))
+                .collect(),
+        }
+    }
+
+    /// TLS 1.2 and 1.3 with every cipher suite rustls supports, the rustls defaults
+    pub fn compatible() -> TlsPolicy {
+        TlsPolicy {
+            versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            cipher_suites: CIPHER_SUITES.iter().map(|(_, suite)| *suite).collect(),
+        }
+    }
+
+    /// Replaces the allowed versions with a list such as `["1.3"]`
+    pub fn set_versions<'a>(&mut self, names: impl IntoIterator<Item = &'a str>) -> Result<()> {
+        self.versions = names
+            .into_iter()
+            .map(parse_version)
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Replaces the allowed cipher suites with a list of names, in order of preference
+    pub fn set_cipher_suites<'a>(
+        &mut self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        self.cipher_suites = names
+            .into_iter()
+            .map(parse_cipher_suite)
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Returns an error if the policy allows nothing that could be negotiated, or has cipher suites
+    /// that none of its versions can use
+    pub fn validate(&self) -> Result<()> {
+        if self.versions.is_empty() {
+            bail!("the TLS policy doesn't allow any TLS versions");
+        }
+        for suite in &self.cipher_suites {
+            if !self
+                .versions
+                .iter()
+                .any(|version| suite.usable_for_version(*version))
+            {
+                bail!(
+                    "cipher suite {} can't be used with the TLS versions the policy allows",
+                    cipher_suite_name(suite)
+                );
+            }
+        }
+        for version in &self.versions {
+            if !self
+                .cipher_suites
+                .iter()
+                .any(|suite| suite.usable_for_version(*version))
+            {
+                bail!(
+                    "the TLS policy allows {} but none of its cipher suites",
+                    version_name(*version)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an error if a certificate's key can't be used with any of the policy's cipher
+    /// suites, e.g. an RSA key when only TLS 1.2 ECDSA suites are allowed
+    pub fn check_key(&self, certified_key: &CertifiedKey) -> Result<()> {
+        let algorithm = certified_key.key.algorithm();
+        let usable = self.cipher_suites.iter().any(|suite| {
+            self.versions
+                .iter()
+                .any(|version| suite.usable_for_version(*version))
+                && suite.usable_for_sigalg(algorithm)
+        });
+        if !usable {
+            bail!(
+                "{:?} keys can't be used with any of the TLS policy's cipher suites",
+                algorithm
+            );
+        }
+        Ok(())
+    }
+
+    pub fn apply_to_client(&self, config: &mut ClientConfig) {
+        config.versions = self.versions.clone();
+        config.ciphersuites = self.cipher_suites.clone();
+    }
+
+    pub fn apply_to_server(&self, config: &mut ServerConfig) {
+        config.versions = self.versions.clone();
+        config.ciphersuites = self.cipher_suites.clone();
+    }
+}
+
+impl Default for TlsPolicy {
+    fn default() -> TlsPolicy {
+        TlsPolicy::compatible()
+    }
+}
+
+impl std::str::FromStr for TlsPolicy {
+    type Err = Error;
+
+    /// Returns the preset with the name
+    fn from_str(name: &str) -> Result<TlsPolicy> {
+        match name.to_ascii_lowercase().as_str() {
+            "modern" => Ok(TlsPolicy::modern()),
+            "compatible" => Ok(TlsPolicy::compatible()),
+            _ => bail!("unknown TLS policy \"{}\"", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::policy::{cipher_suite_name, TlsPolicy};
+    use crate::tls::{certified_key, CertKeyPaths};
+    use tokio_rustls::rustls::{ProtocolVersion, ALL_CIPHERSUITES};
+
+    #[test]
+    fn presets() {
+        let modern: TlsPolicy = "modern".parse().unwrap();
+        assert_eq!(modern.versions, vec![ProtocolVersion::TLSv1_3]);
+        assert_eq!(modern.cipher_suites.len(), 3);
+        assert!(modern.validate().is_ok());
+
+        let compatible: TlsPolicy = "Compatible".parse().unwrap();
+        assert_eq!(compatible.cipher_suites, ALL_CIPHERSUITES.to_vec());
+        assert!(compatible.validate().is_ok());
+        assert_eq!(TlsPolicy::default(), compatible);
+
+        assert!("strict".parse::<TlsPolicy>().is_err());
+    }
+
+    #[test]
+    fn explicit_lists() {
+        let mut policy = TlsPolicy::compatible();
+        policy.set_versions(vec!["1.3", "TLS1.2"]).unwrap();
+        assert_eq!(
+            policy.versions,
+            vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]
+        );
+        assert!(policy.set_versions(vec!["1.1"]).is_err());
+
+        policy
+            .set_cipher_suites(vec![
+                "TLS13_CHACHA20_POLY1305_SHA256",
+                "tls_ecdhe_rsa_with_aes_256_gcm_sha384",
+            ])
+            .unwrap();
+        let names: Vec<_> = policy
+            .cipher_suites
+            .iter()
+            .map(|suite| cipher_suite_name(suite))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "TLS_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"
+            ]
+        );
+        assert!(policy
+            .set_cipher_suites(vec!["TLS_RSA_WITH_AES_128_CBC_SHA"])
+            .is_err());
+        assert!(policy.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_combinations() {
+        // TLS 1.2 suites without TLS 1.2
+        let mut policy = TlsPolicy::modern();
+        policy
+            .set_cipher_suites(vec![
+                "TLS_AES_128_GCM_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+            ])
+            .unwrap();
+        assert!(policy.validate().is_err());
+
+        // TLS 1.2 without any of its suites
+        let mut policy = TlsPolicy::modern();
+        policy.set_versions(vec!["1.3", "1.2"]).unwrap();
+        assert!(policy.validate().is_err());
+
+        let mut policy = TlsPolicy::modern();
+        policy.set_versions(vec![]).unwrap();
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn keys_need_a_usable_suite() {
+        // tests/certs/key.pem is an RSA key
+        let key = certified_key(&CertKeyPaths::new(
+            "tests/certs/cert.pem",
+            "tests/certs/key.pem",
+        ))
+        .unwrap();
+        assert!(TlsPolicy::modern().check_key(&key).is_ok());
+
+        let mut policy = TlsPolicy::compatible();
+        policy.set_versions(vec!["1.2"]).unwrap();
+        policy
+            .set_cipher_suites(vec!["TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"])
+            .unwrap();
+        assert!(policy.check_key(&key).is_err());
+        policy
+            .set_cipher_suites(vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"])
+            .unwrap();
+        assert!(policy.check_key(&key).is_ok());
+    }
+}
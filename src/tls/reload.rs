@@ -0,0 +1,196 @@
+use crate::errors::*;
+use crate::tls::ConfigSource;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// A TLS config that can be replaced while the proxy is running. Each connection takes the current
+/// config when it starts and keeps using it, so replacing the config only affects new connections.
+pub struct ReloadableConfig<C> {
+    current: RwLock<Arc<C>>,
+}
+
+impl<C> ReloadableConfig<C> {
+    pub fn new(config: C) -> ReloadableConfig<C> {
+        ReloadableConfig {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    /// Returns the config to use for a new connection
+    pub fn get(&self) -> Arc<C> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn set(&self, config: C) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+}
+
+/// Builds a new config from the source files and swaps it in. The current config is kept if the
+/// new one can't be built.
+pub fn reload<S: ConfigSource>(source: &S, config: &ReloadableConfig<S::Config>) -> Result<()> {
+    config.set(source.build()?);
+    Ok(())
+}
+
+/// What a file looked like the last time it was checked, None if it couldn't be read
+type FileVersion = Option<(SystemTime, u64)>;
+
+fn file_versions(files: &[PathBuf]) -> HashMap<PathBuf, FileVersion> {
+    files
+        .iter()
+        .map(|path| {
+            let version = std::fs::metadata(path)
+                .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+                .ok();
+            (path.clone(), version)
+        })
+        .collect()
+}
+
+/// Reloads the config whenever the process receives SIGHUP or one of the source files changes.
+/// Files are checked for changes every `poll_interval`. Runs until the task is dropped.
+///
+/// Reload errors are logged and the current config is kept, so a partly written certificate or a
+/// key that doesn't match its certificate doesn't interrupt the proxy.
+pub async fn watch<S: ConfigSource>(
+    source: S,
+    config: Arc<ReloadableConfig<S::Config>>,
+    poll_interval: Duration,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            eprintln!(
+                "can't listen for SIGHUP, TLS files are only reloaded when they change: {}",
+                e
+            );
+            None
+        }
+    };
+    let mut versions = file_versions(&source.files());
+
+    loop {
+        let reason = tokio::select! {
+            Some(_) = async { hangup.as_mut()?.recv().await } => "SIGHUP received",
+            _ = tokio::time::sleep(poll_interval) => {
+                let current = file_versions(&source.files());
+                if current == versions {
+                    continue;
+                }
+                versions = current;
+                "TLS files changed"
+            }
+        };
+
+        match reload(&source, &config) {
+            Ok(()) => println!("{}, reloaded TLS config", reason),
+            Err(e) => eprintln!(
+                "{}, but the TLS config was not reloaded: {}",
+                reason,
+                error_chain::ChainedError::display_chain(&e)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::reload::{reload, watch, ReloadableConfig};
+    use crate::tls::{CertKeyPaths, ConfigSource, ServerTlsSettings};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Helper function that copies the test server cert and key into a new directory, and returns
+    /// settings that use the copies
+    fn copied_settings(name: &str) -> (PathBuf, ServerTlsSettings) {
+        let dir = std::env::temp_dir().join(format!(
+            "rust_tls_proxy_reload_{}_{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy("tests/certs/cert.pem", dir.join("cert.pem")).unwrap();
+        std::fs::copy("tests/certs/key.pem", dir.join("key.pem")).unwrap();
+
+        let settings =
+            ServerTlsSettings::new(CertKeyPaths::new(dir.join("cert.pem"), dir.join("key.pem")));
+        (dir, settings)
+    }
+
+    #[test]
+    fn get_keeps_old_config() {
+        let config = ReloadableConfig::new(1);
+        let old = config.get();
+        config.set(2);
+
+        assert_eq!(*old, 1);
+        assert_eq!(*config.get(), 2);
+    }
+
+    #[test]
+    fn bad_reload_keeps_config() {
+        let (dir, settings) = copied_settings("bad");
+        let config = ReloadableConfig::new(settings.build().unwrap());
+        let old = config.get();
+
+        // A key that belongs to a different certificate
+        std::fs::copy("tests/certs/client_key.pem", dir.join("key.pem")).unwrap();
+        assert!(reload(&settings, &config).is_err());
+        assert!(Arc::ptr_eq(&old, &config.get()));
+
+        std::fs::write(dir.join("cert.pem"), b"").unwrap();
+        assert!(reload(&settings, &config).is_err());
+        assert!(Arc::ptr_eq(&old, &config.get()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_changed_files() {
+        let (dir, settings) = copied_settings("watch");
+        let config = Arc::new(ReloadableConfig::new(settings.build().unwrap()));
+        let old = config.get();
+
+        tokio::spawn(watch(
+            settings.clone(),
+            Arc::clone(&config),
+            Duration::from_millis(20),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        std::fs::copy("tests/certs/sni_cert.pem", dir.join("cert.pem")).unwrap();
+        std::fs::copy("tests/certs/sni_key.pem", dir.join("key.pem")).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(!Arc::ptr_eq(&old, &config.get()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_on_sighup() {
+        let (dir, settings) = copied_settings("sighup");
+        let config = Arc::new(ReloadableConfig::new(settings.build().unwrap()));
+        let old = config.get();
+
+        // Files aren't polled during the test, so only SIGHUP triggers a reload
+        tokio::spawn(watch(
+            settings,
+            Arc::clone(&config),
+            Duration::from_secs(3600),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        nix::sys::signal::raise(nix::sys::signal::Signal::SIGHUP).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(!Arc::ptr_eq(&old, &config.get()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
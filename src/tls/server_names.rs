@@ -0,0 +1,199 @@
+use crate::errors::*;
+use error_chain::bail;
+use std::net::IpAddr;
+use tokio_rustls::webpki::DNSNameRef;
+
+/// A block of IP addresses written in CIDR notation, e.g. `10.0.0.0/24`. A bare address is a block
+/// containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<IpNetwork> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            bail!(
+                "prefix length for {} must be at most {}, got {}",
+                addr,
+                max_len,
+                prefix_len
+            );
+        }
+        Ok(IpNetwork { addr, prefix_len })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Only the first prefix_len bits are compared, so host bits set in self.addr are ignored
+        let prefix_matches = |network: &[u8], addr: &[u8]| {
+            let full_bytes = self.prefix_len as usize / 8;
+            let remaining_bits = self.prefix_len % 8;
+            network[..full_bytes] == addr[..full_bytes]
+                && (remaining_bits == 0 || {
+                    let mask = 0xff_u8 << (8 - remaining_bits);
+                    network[full_bytes] & mask == addr[full_bytes] & mask
+                })
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets())
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(network: &str) -> Result<IpNetwork> {
+        let (addr, prefix_len) = match network.split_once('/') {
+            Some((addr, prefix_len)) => (
+                addr,
+                Some(
+                    prefix_len
+                        .parse::<u8>()
+                        .chain_err(|| format!("invalid prefix length in \"{}\"", network))?,
+                ),
+            ),
+            None => (network, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .chain_err(|| format!("invalid IP address in \"{}\"", network))?;
+        let prefix_len = prefix_len.unwrap_or(match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        });
+        IpNetwork::new(addr, prefix_len)
+    }
+}
+
+/// Maps destination addresses to the name the reverse proxy's certificate is expected to have.
+/// When several networks contain an address, the most specific one wins.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerNameTable {
+    entries: Vec<(IpNetwork, String)>,
+}
+
+impl ServerNameTable {
+    pub fn new() -> ServerNameTable {
+        ServerNameTable::default()
+    }
+
+    /// Adds the server name for a network. Returns an error if the name isn't a valid DNS name.
+    pub fn add(&mut self, network: IpNetwork, name: &str) -> Result<()> {
+        DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| format!("invalid TLS server name \"{}\"", name))?;
+        self.entries.push((network, name.to_owned()));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the server name for the address, None if no network contains it
+    pub fn lookup(&self, addr: IpAddr) -> Option<&str> {
+        self.entries
+            .iter()
+            .filter(|(network, _)| network.contains(addr))
+            // max_by_key returns the last maximum, so reverse to prefer the first one added
+            .rev()
+            .max_by_key(|(network, _)| network.prefix_len())
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+impl std::str::FromStr for ServerNameTable {
+    type Err = Error;
+
+    /// Parses comma separated `network=name` entries, e.g. `10.0.0.0/24=proxy.lab,10.0.1.5=other`
+    fn from_str(entries: &str) -> Result<ServerNameTable> {
+        let mut table = ServerNameTable::new();
+        for entry in entries.split(',').filter(|entry| !entry.trim().is_empty()) {
+            match entry.split_once('=') {
+                Some((network, name)) => table.add(network.trim().parse()?, name.trim())?,
+                None => bail!("expected network=name, got \"{}\"", entry),
+            }
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::server_names::{IpNetwork, ServerNameTable};
+    use std::net::IpAddr;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn network_contains() {
+        let network: IpNetwork = "10.1.2.0/23".parse().unwrap();
+        assert!(network.contains(ip("10.1.2.7")));
+        assert!(network.contains(ip("10.1.3.255")));
+        assert!(!network.contains(ip("10.1.4.0")));
+        assert!(!network.contains(ip("::1")));
+
+        let single: IpNetwork = "192.168.0.1".parse().unwrap();
+        assert!(single.contains(ip("192.168.0.1")));
+        assert!(!single.contains(ip("192.168.0.2")));
+
+        let any: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("8.8.8.8")));
+
+        let v6: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn network_parse_errors() {
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNetwork>().is_err());
+        assert!("::/129".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn lookup_most_specific() {
+        let table: ServerNameTable =
+            "10.0.0.0/8=lab.test, 10.0.5.0/24=proxy.lab.test,10.0.5.9=other"
+                .parse()
+                .unwrap();
+
+        assert_eq!(table.lookup(ip("10.0.5.9")), Some("other"));
+        assert_eq!(table.lookup(ip("10.0.5.1")), Some("proxy.lab.test"));
+        assert_eq!(table.lookup(ip("10.200.0.1")), Some("lab.test"));
+        assert_eq!(table.lookup(ip("11.0.0.1")), None);
+    }
+
+    #[test]
+    fn lookup_prefers_first_of_equal_networks() {
+        let table: ServerNameTable = "10.0.0.0/8=first,10.0.0.0/8=second".parse().unwrap();
+        assert_eq!(table.lookup(ip("10.0.0.1")), Some("first"));
+    }
+
+    #[test]
+    fn table_parse_errors() {
+        assert!("10.0.0.0/8".parse::<ServerNameTable>().is_err());
+        assert!("10.0.0.0/8=not a name".parse::<ServerNameTable>().is_err());
+        assert!("".parse::<ServerNameTable>().unwrap().is_empty());
+    }
+}
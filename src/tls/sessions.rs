@@ -0,0 +1,302 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::{
+    ClientSessionMemoryCache, NoClientSessionStorage, NoServerSessionStorage, ProducesTickets,
+    ServerSessionMemoryCache, StoresClientSessions, StoresServerSessions, Ticketer,
+};
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Counts of session resumption. On the reverse proxy, a hit is a session ID or ticket it still
+/// had, a miss is one that had expired, been evicted or was issued before the last restart or
+/// reload. On the forward proxy, a hit is a connection that offered a stored session, a miss is
+/// one that had none to offer and made a full handshake. Clones share the same counts.
+#[derive(Clone, Default)]
+pub struct SessionStats(Arc<Counters>);
+
+impl SessionStats {
+    pub fn new() -> SessionStats {
+        SessionStats::default()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.0.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.0.misses.load(Ordering::Relaxed)
+    }
+
+    fn record<T>(&self, found: Option<T>) -> Option<T> {
+        let counter = match found {
+            Some(_) => &self.0.hits,
+            None => &self.0.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+}
+
+/// Settings that share the same counts are equal
+impl PartialEq for SessionStats {
+    fn eq(&self, other: &SessionStats) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionStats")
+            .field("hits", &self.hits())
+            .field("misses", &self.misses())
+            .finish()
+    }
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits(), self.misses())
+    }
+}
+
+/// Session ID cache that counts lookups. rustls only looks up sessions that a client asks to
+/// resume.
+struct CountingSessionStorage {
+    inner: Arc<dyn StoresServerSessions + Send + Sync>,
+    stats: SessionStats,
+}
+
+impl StoresServerSessions for CountingSessionStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.stats.record(self.inner.get(key))
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.stats.record(self.inner.take(key))
+    }
+}
+
+/// Session ticket encryption that counts the tickets it decrypts. Tickets aren't issued if `inner`
+/// is None.
+struct CountingTicketer {
+    inner: Option<Arc<dyn ProducesTickets>>,
+    stats: SessionStats,
+}
+
+impl ProducesTickets for CountingTicketer {
+    fn enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.inner.as_ref().map_or(0, |inner| inner.get_lifetime())
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.inner.as_ref()?.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let inner = self.inner.as_ref()?;
+        self.stats.record(inner.decrypt(cipher))
+    }
+}
+
+/// Client session cache that counts lookups of sessions to resume. rustls also stores key exchange
+/// hints here, which aren't counted.
+struct CountingClientSessionStorage {
+    inner: Arc<dyn StoresClientSessions>,
+    stats: SessionStats,
+}
+
+/// Prefix of the keys rustls stores resumable sessions under
+const CLIENT_SESSION_KEY: &[u8] = b"session";
+
+impl StoresClientSessions for CountingClientSessionStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let found = self.inner.get(key);
+        if key.starts_with(CLIENT_SESSION_KEY) {
+            self.stats.record(found)
+        } else {
+            found
+        }
+    }
+}
+
+/// Returns a cache holding sessions to resume with up to `size` servers. Sessions aren't stored if
+/// `size` is 0.
+pub fn client_session_storage(size: usize, stats: &SessionStats) -> Arc<dyn StoresClientSessions> {
+    let inner: Arc<dyn StoresClientSessions> = match size {
+        0 => Arc::new(NoClientSessionStorage {}),
+        size => ClientSessionMemoryCache::new(size),
+    };
+    Arc::new(CountingClientSessionStorage {
+        inner,
+        stats: stats.clone(),
+    })
+}
+
+/// Returns a cache holding up to `size` sessions for resumption by session ID (and by stateful
+/// ticket in TLS 1.3 when tickets are disabled). Sessions aren't stored if `size` is 0.
+pub fn session_storage(
+    size: usize,
+    stats: &SessionStats,
+) -> Arc<dyn StoresServerSessions + Send + Sync> {
+    let inner: Arc<dyn StoresServerSessions + Send + Sync> = match size {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+    Arc::new(CountingSessionStorage {
+        inner,
+        stats: stats.clone(),
+    })
+}
+
+/// Returns the ticket encryption for a server config. Keys are random and rotated every 6 hours, so
+/// tickets can be resumed for up to 12 hours. Tickets are only issued if `enabled` is true.
+pub fn ticketer(enabled: bool, stats: &SessionStats) -> Arc<dyn ProducesTickets> {
+    Arc::new(CountingTicketer {
+        inner: if enabled { Some(Ticketer::new()) } else { None },
+        stats: stats.clone(),
+    })
+}
+
+/// Logs the counts whenever the process receives SIGUSR1. Runs until the task is dropped.
+pub async fn log_stats(stats: SessionStats) {
+    let mut user_signal = match signal(SignalKind::user_defined1()) {
+        Ok(user_signal) => user_signal,
+        Err(e) => {
+            eprintln!(
+                "can't listen for SIGUSR1, TLS session stats won't be logged: {}",
+                e
+            );
+            return;
+        }
+    };
+    while user_signal.recv().await.is_some() {
+        println!("TLS session resumption: {}", stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::sessions::SessionStats;
+    use crate::tls::{self, CertKeyPaths, ClientTlsSettings, ServerTlsSettings};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    /// Helper function that makes `connections` connections from a client to a server with the
+    /// settings, and returns the server's session stats
+    async fn resume(
+        client: ClientTlsSettings,
+        server: ServerTlsSettings,
+        connections: usize,
+    ) -> SessionStats {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(tls::server_config(&server).unwrap()));
+        let connector = TlsConnector::from(Arc::new(tls::client_config(&client).unwrap()));
+
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                // Tickets are sent after the handshake, so the client reads a byte to receive them
+                if let Ok(mut conn) = acceptor.accept(conn).await {
+                    let _ = conn.write_all(b"x").await;
+                    let _ = conn.flush().await;
+                }
+            }
+        });
+
+        for _ in 0..connections {
+            let conn = TcpStream::connect(addr).await.unwrap();
+            let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let mut conn = connector.connect(name, conn).await.unwrap();
+            let mut byte = [0];
+            conn.read_exact(&mut byte).await.unwrap();
+        }
+        server.session_stats
+    }
+
+    fn settings() -> (ClientTlsSettings, ServerTlsSettings) {
+        (
+            ClientTlsSettings::new("tests/certs/ca_cert.pem"),
+            ServerTlsSettings::new(CertKeyPaths::new(
+                "tests/certs/cert.pem",
+                "tests/certs/key.pem",
+            )),
+        )
+    }
+
+    #[tokio::test]
+    async fn resume_with_tickets() {
+        let (client, server) = settings();
+        let client_stats = client.session_stats.clone();
+        let stats = resume(client, server, 3).await;
+        assert_eq!((stats.hits(), stats.misses()), (2, 0));
+        // The first connection has no session to offer
+        assert_eq!((client_stats.hits(), client_stats.misses()), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn resume_with_session_cache() {
+        // TLS 1.3 tickets that refer to sessions in the cache
+        let (client, mut server) = settings();
+        server.session_tickets = false;
+        let stats = resume(client, server, 3).await;
+        assert_eq!((stats.hits(), stats.misses()), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn resumption_disabled() {
+        let (client, mut server) = settings();
+        server.session_tickets = false;
+        server.session_cache_size = 0;
+        let stats = resume(client, server, 2).await;
+        assert_eq!(stats.hits(), 0);
+
+        let (mut client, server) = settings();
+        client.session_cache_size = 0;
+        let client_stats = client.session_stats.clone();
+        let stats = resume(client, server, 2).await;
+        assert_eq!((stats.hits(), stats.misses()), (0, 0));
+        assert_eq!((client_stats.hits(), client_stats.misses()), (0, 2));
+
+        // TLS 1.3 sessions can only be resumed with tickets
+        let (mut client, server) = settings();
+        client.session_tickets = false;
+        let stats = resume(client, server, 2).await;
+        assert_eq!(stats.hits(), 0);
+    }
+
+    #[test]
+    fn stats_are_shared() {
+        let stats = SessionStats::new();
+        let clone = stats.clone();
+        clone.record(Some(()));
+        clone.record::<()>(None);
+        clone.record(Some(()));
+
+        assert_eq!((stats.hits(), stats.misses()), (2, 1));
+        assert_eq!(stats.to_string(), "2 hits, 1 misses");
+        assert_eq!(stats, clone);
+        assert_ne!(stats, SessionStats::new());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::errors::*;
+use crate::tls::CertKeyPaths;
+use error_chain::bail;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{ClientHello, ResolvesServerCert};
+use tokio_rustls::webpki::DNSNameRef;
+
+/// Reads a JSON file mapping host names to the certificate chain and key to present for them:
+///
+/// ```json
+/// {
+///     "example.com": { "cert_chain": "example.com/cert.pem", "key": "example.com/key.pem" },
+///     "*.example.org": { "cert_chain": "wildcard.pem", "key": "wildcard-key.pem" },
+///     "example.net": { "cert_chain": "example.net.pem", "key": "example.net-key.pem", "ocsp": "example.net.ocsp" }
+/// }
+/// ```
+///
+/// `ocsp` is an optional DER OCSP response to staple. Relative paths are relative to the directory
+/// containing the file.
+pub fn load_sni_config(path: &Path) -> Result<HashMap<String, CertKeyPaths>> {
+    let file = File::open(path).chain_err(|| format!("error opening {}", path.display()))?;
+    let certs: HashMap<String, CertKeyPaths> = serde_json::from_reader(BufReader::new(file))
+        .chain_err(|| format!("error parsing SNI config {}", path.display()))?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    Ok(certs
+        .into_iter()
+        .map(|(name, paths)| {
+            let mut resolved = CertKeyPaths::new(dir.join(paths.cert_chain), dir.join(paths.key));
+            resolved.ocsp = paths.ocsp.map(|ocsp| dir.join(ocsp));
+            (name, resolved)
+        })
+        .collect())
+}
+
+/// Picks the certificate to present from the server name the client sent with SNI. Clients that
+/// don't send a name, or send one without a matching certificate, get the default certificate.
+///
+/// Names are either exact host names or wildcards such as `*.example.com`, which match a single
+/// label in place of the `*`. Exact names take priority over wildcards.
+pub struct SniCertResolver {
+    by_name: HashMap<String, CertifiedKey>,
+    default: CertifiedKey,
+}
+
+impl SniCertResolver {
+    pub fn new(default: CertifiedKey) -> SniCertResolver {
+        SniCertResolver {
+            by_name: HashMap::new(),
+            default,
+        }
+    }
+
+    /// Adds the certificate for a host name or wildcard. Returns an error if the name isn't valid,
+    /// or if the certificate isn't valid for an exact host name.
+    pub fn add(&mut self, name: &str, key: CertifiedKey) -> Result<()> {
+        let name = name.to_ascii_lowercase();
+        match name.strip_prefix("*.") {
+            Some(domain) => {
+                DNSNameRef::try_from_ascii_str(domain)
+                    .map_err(|_| format!("invalid SNI wildcard \"{}\"", name))?;
+                key.cross_check_end_entity_cert(None)
+            }
+            None => {
+                let dns_name = DNSNameRef::try_from_ascii_str(&name)
+                    .map_err(|_| format!("invalid SNI host name \"{}\"", name))?;
+                key.cross_check_end_entity_cert(Some(dns_name))
+            }
+        }
+        .chain_err(|| format!("certificate for \"{}\" is not usable", name))?;
+
+        if self.by_name.insert(name.clone(), key).is_some() {
+            bail!("duplicate SNI host name \"{}\"", name);
+        }
+        Ok(())
+    }
+
+    /// Returns the certificate to present for the server name sent by the client, if any
+    pub fn lookup(&self, server_name: Option<&str>) -> &CertifiedKey {
+        let name = match server_name {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return &self.default,
+        };
+
+        if let Some(key) = self.by_name.get(&name) {
+            return key;
+        }
+        name.split_once('.')
+            .and_then(|(_, domain)| self.by_name.get(&format!("*.{}", domain)))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let server_name: Option<&str> = client_hello.server_name().map(Into::into);
+        Some(self.lookup(server_name).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::sni::{load_sni_config, SniCertResolver};
+    use crate::tls::{certified_key, CertKeyPaths};
+    use std::path::Path;
+
+    fn localhost_key() -> tokio_rustls::rustls::sign::CertifiedKey {
+        certified_key(&CertKeyPaths::new(
+            "tests/certs/cert.pem",
+            "tests/certs/key.pem",
+        ))
+        .unwrap()
+    }
+
+    fn example_key() -> tokio_rustls::rustls::sign::CertifiedKey {
+        certified_key(&CertKeyPaths::new(
+            "tests/certs/sni_cert.pem",
+            "tests/certs/sni_key.pem",
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn load_config_relative_to_file() {
+        let certs = load_sni_config(Path::new("tests/certs/sni.json")).unwrap();
+        assert_eq!(
+            certs.get("example.test"),
+            Some(&CertKeyPaths::new(
+                "tests/certs/sni_cert.pem",
+                "tests/certs/sni_key.pem"
+            ))
+        );
+        assert_eq!(certs.len(), 1);
+    }
+
+    #[test]
+    fn lookup_by_name() {
+        let mut resolver = SniCertResolver::new(localhost_key());
+        resolver.add("Example.test", example_key()).unwrap();
+        let example = example_key().cert;
+        let default = localhost_key().cert;
+
+        assert_eq!(resolver.lookup(Some("example.test")).cert, example);
+        assert_eq!(resolver.lookup(Some("EXAMPLE.TEST")).cert, example);
+        assert_eq!(resolver.lookup(Some("other.test")).cert, default);
+        assert_eq!(resolver.lookup(None).cert, default);
+    }
+
+    #[test]
+    fn lookup_wildcard() {
+        let mut resolver = SniCertResolver::new(localhost_key());
+        resolver.add("*.example.test", example_key()).unwrap();
+        let example = example_key().cert;
+        let default = localhost_key().cert;
+
+        assert_eq!(resolver.lookup(Some("www.example.test")).cert, example);
+        assert_eq!(resolver.lookup(Some("example.test")).cert, default);
+        assert_eq!(resolver.lookup(Some("a.b.example.test")).cert, default);
+    }
+
+    #[test]
+    fn add_rejects_mismatched_cert() {
+        let mut resolver = SniCertResolver::new(localhost_key());
+        assert!(resolver.add("localhost", example_key()).is_err());
+        assert!(resolver.add("not a name", example_key()).is_err());
+
+        resolver.add("example.test", example_key()).unwrap();
+        assert!(resolver.add("example.test", example_key()).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// TLS for the reverse proxy's connections to backends that only accept TLS, so it re-encrypts what
+// it decrypted from forward proxies instead of only terminating TLS.
+
+use crate::errors::*;
+use crate::tls::{CertKeyPaths, ClientTlsSettings};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio_rustls::webpki::{DNSName, DNSNameRef};
+
+/// TLS settings for the reverse proxy's connections to one backend
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamTlsSettings {
+    /// Name sent to the backend with SNI and expected in its certificate
+    pub server_name: String,
+    /// Root certificates the backend is verified against, and the client certificate presented
+    /// to it. Pins, server names and ALPN protocols aren't used for backends.
+    pub client: ClientTlsSettings,
+}
+
+impl UpstreamTlsSettings {
+    /// Creates settings that verify the backend against the root certificates under the server
+    /// name, without a client certificate
+    pub fn new(server_name: &str, root_certs: impl Into<PathBuf>) -> Result<UpstreamTlsSettings> {
+        let settings = UpstreamTlsSettings {
+            server_name: server_name.to_string(),
+            client: ClientTlsSettings::new(root_certs),
+        };
+        settings.dns_name()?;
+        Ok(settings)
+    }
+
+    /// The server name in the form rustls connects with
+    pub fn dns_name(&self) -> Result<DNSName> {
+        DNSNameRef::try_from_ascii_str(&self.server_name)
+            .map(DNSName::from)
+            .map_err(|_| format!("invalid backend server name \"{}\"", self.server_name).into())
+    }
+}
+
+/// How to connect to one backend, as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    server_name: String,
+    root_certs: PathBuf,
+    #[serde(default)]
+    client_cert: Option<CertKeyPaths>,
+}
+
+/// Reads a JSON file mapping backend addresses to the TLS settings to connect to them with:
+///
+/// ```json
+/// {
+///     "10.0.0.5:8443": { "server_name": "api.internal", "root_certs": "internal_ca.pem" },
+///     "10.0.0.6:8443": {
+///         "server_name": "db.internal",
+///         "root_certs": "internal_ca.pem",
+///         "client_cert": { "cert_chain": "proxy_cert.pem", "key": "proxy_key.pem" }
+///     }
+/// }
+/// ```
+///
+/// `server_name` is sent with SNI and must be in the backend's certificate, which is verified
+/// against `root_certs`. `client_cert` is presented to backends that ask for one. Relative paths
+/// are relative to the directory containing the file.
+pub fn load_upstream_tls_config(path: &Path) -> Result<HashMap<SocketAddr, UpstreamTlsSettings>> {
+    let file = File::open(path).chain_err(|| format!("error opening {}", path.display()))?;
+    let entries: HashMap<String, Entry> = serde_json::from_reader(BufReader::new(file))
+        .chain_err(|| format!("error parsing upstream TLS config {}", path.display()))?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    entries
+        .into_iter()
+        .map(|(addr, entry)| {
+            let addr: SocketAddr = addr
+                .parse()
+                .chain_err(|| format!("error parsing backend address \"{}\"", addr))?;
+            let mut settings =
+                UpstreamTlsSettings::new(&entry.server_name, dir.join(entry.root_certs))?;
+            settings.client.client_cert = entry
+                .client_cert
+                .map(|paths| CertKeyPaths::new(dir.join(paths.cert_chain), dir.join(paths.key)));
+            Ok((addr, settings))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::upstream::{load_upstream_tls_config, UpstreamTlsSettings};
+    use crate::tls::CertKeyPaths;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn load_config() {
+        let config = load_upstream_tls_config(Path::new("tests/certs/upstream.json")).unwrap();
+        assert_eq!(config.len(), 2);
+
+        let api = &config[&"127.0.0.1:8138".parse().unwrap()];
+        assert_eq!(api.server_name, "localhost");
+        assert_eq!(
+            api.client.root_certs,
+            Some(PathBuf::from("tests/certs/ca_cert.pem"))
+        );
+        assert_eq!(api.client.client_cert, None);
+
+        let mutual = &config[&"127.0.0.1:8139".parse().unwrap()];
+        assert_eq!(
+            mutual.client.client_cert,
+            Some(CertKeyPaths::new(
+                "tests/certs/client_cert.pem",
+                "tests/certs/client_key.pem"
+            ))
+        );
+    }
+
+    #[test]
+    fn load_errors() {
+        assert!(load_upstream_tls_config(Path::new("tests/certs/missing.json")).is_err());
+        // Not a map of addresses
+        assert!(load_upstream_tls_config(Path::new("tests/certs/sni.json")).is_err());
+        assert!(UpstreamTlsSettings::new("not a host name", "ca_cert.pem").is_err());
+    }
+}
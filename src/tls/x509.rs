@@ -0,0 +1,299 @@
+// Fields of X.509 certificates that are logged or checked by the proxies. webpki verifies
+// certificates but doesn't expose what is in them.
+
+use crate::errors::*;
+use crate::tls::der;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Short names of common attributes in distinguished names, by OID
+const ATTRIBUTE_NAMES: &[(&str, &str)] = &[
+    ("2.5.4.3", "CN"),
+    ("2.5.4.6", "C"),
+    ("2.5.4.7", "L"),
+    ("2.5.4.8", "ST"),
+    ("2.5.4.10", "O"),
+    ("2.5.4.11", "OU"),
+];
+
+/// UTF8String, PrintableString, TeletexString, IA5String and BMPString tags
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const TELETEX_STRING: u8 = 0x14;
+const IA5_STRING: u8 = 0x16;
+const BMP_STRING: u8 = 0x1e;
+
+/// Explicitly tagged [3] field holding the extensions of v3 certificates
+const EXTENSIONS: u8 = 0xa3;
+const SUBJECT_ALT_NAME_OID: &str = "2.5.29.17";
+
+/// Context specific tags of the GeneralName choices that are formatted, the rest are skipped
+const RFC822_NAME: u8 = 0x81;
+const DNS_NAME: u8 = 0x82;
+const DIRECTORY_NAME: u8 = 0xa4;
+const URI: u8 = 0x86;
+const IP_ADDRESS: u8 = 0x87;
+
+/// The TBSCertificate fields up to the subject public key info, and the extensions
+struct TbsCertificate<'a> {
+    serial_number: der::Value<'a>,
+    subject: der::Value<'a>,
+    subject_public_key_info: der::Value<'a>,
+    /// Left out by v1 and v2 certificates
+    extensions: Option<der::Value<'a>>,
+}
+
+fn tbs_certificate(cert: &[u8]) -> Result<TbsCertificate<'_>> {
+    let (cert, _) = der::read_tagged(cert, der::SEQUENCE)?;
+    let (tbs, _) = der::read_tagged(cert.contents, der::SEQUENCE)?;
+    let mut fields = der::read_all(tbs.contents)?;
+
+    // The version is an explicitly tagged [0] field that is left out for v1 certificates
+    if fields.first().map(|field| field.tag) == Some(0xa0) {
+        fields.remove(0);
+    }
+    // Serial number, signature algorithm, issuer, validity, subject and subject public key info
+    let mut fields = fields.into_iter();
+    match (fields.next(), fields.nth(3), fields.next()) {
+        (Some(serial_number), Some(subject), Some(subject_public_key_info))
+            if serial_number.tag == der::INTEGER
+                && subject.tag == der::SEQUENCE
+                && subject_public_key_info.tag == der::SEQUENCE =>
+        {
+            // Follows the optional issuer and subject unique IDs
+            let extensions = fields.find(|field| field.tag == EXTENSIONS);
+            Ok(TbsCertificate {
+                serial_number,
+                subject,
+                subject_public_key_info,
+                extensions,
+            })
+        }
+        _ => Err("certificate is missing its serial number, subject or public key".into()),
+    }
+}
+
+/// Returns the contents of the serial number INTEGER of a DER certificate
+pub fn serial_number(cert: &[u8]) -> Result<&[u8]> {
+    Ok(tbs_certificate(cert)
+        .chain_err(|| "invalid certificate")?
+        .serial_number
+        .contents)
+}
+
+/// Returns the DER encoded SubjectPublicKeyInfo of a DER certificate, the part of the certificate
+/// that public key pins are computed over
+pub fn subject_public_key_info(cert: &[u8]) -> Result<&[u8]> {
+    Ok(tbs_certificate(cert)
+        .chain_err(|| "invalid certificate")?
+        .subject_public_key_info
+        .encoded)
+}
+
+/// Returns the DER encoded subject Name of a DER certificate, the part of an issuer's certificate
+/// that OCSP certificate IDs hash
+pub fn subject_name(cert: &[u8]) -> Result<&[u8]> {
+    Ok(tbs_certificate(cert)
+        .chain_err(|| "invalid certificate")?
+        .subject
+        .encoded)
+}
+
+/// Returns the public key of a DER certificate, the contents of the subjectPublicKey BIT STRING
+/// without its unused bits count
+pub fn public_key(cert: &[u8]) -> Result<&[u8]> {
+    let tbs = tbs_certificate(cert).chain_err(|| "invalid certificate")?;
+    let (_, rest) = der::read_tagged(tbs.subject_public_key_info.contents, der::SEQUENCE)?;
+    let (public_key, _) = der::read_tagged(rest, der::BIT_STRING)?;
+    match public_key.contents {
+        [0, key @ ..] => Ok(key),
+        _ => Err("certificate public key isn't a whole number of bytes".into()),
+    }
+}
+
+fn attribute_value(value: &der::Value<'_>) -> String {
+    match value.tag {
+        UTF8_STRING | PRINTABLE_STRING | TELETEX_STRING | IA5_STRING => {
+            String::from_utf8_lossy(value.contents).into_owned()
+        }
+        BMP_STRING => {
+            let utf16: Vec<u16> = value
+                .contents
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+                .collect();
+            String::from_utf16_lossy(&utf16)
+        }
+        _ => format!("#{}", hex(value.encoded)),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Formats a DER Name in the order its attributes appear, e.g. `CN=proxy.lab, O=Example`
+fn name_to_string(name: &[u8]) -> Result<String> {
+    let mut attributes = Vec::new();
+    for rdn in der::read_all(name)? {
+        if rdn.tag != der::SET {
+            return Err("expected a SET of attributes in name".into());
+        }
+        for attribute in der::read_all(rdn.contents)? {
+            let (oid, rest) = der::read_tagged(attribute.contents, der::OID)?;
+            let (value, _) = der::read(rest)?;
+            let oid = der::oid_to_string(oid.contents)?;
+            let name = ATTRIBUTE_NAMES
+                .iter()
+                .find(|(attribute_oid, _)| *attribute_oid == oid)
+                .map_or(oid.as_str(), |(_, name)| name);
+            attributes.push(format!("{}={}", name, attribute_value(&value)));
+        }
+    }
+    Ok(attributes.join(", "))
+}
+
+/// Returns the subject of a DER certificate, formatted like `CN=proxy.lab, O=Example`
+pub fn subject(cert: &[u8]) -> Result<String> {
+    let tbs = tbs_certificate(cert).chain_err(|| "invalid certificate")?;
+    name_to_string(tbs.subject.contents).chain_err(|| "invalid certificate subject")
+}
+
+/// Formats a GeneralName the way `openssl x509 -ext subjectAltName` does, e.g. `DNS:proxy.lab`.
+/// Returns None for the kinds of names that aren't formatted.
+fn general_name_to_string(name: &der::Value<'_>) -> Result<Option<String>> {
+    let text = || String::from_utf8_lossy(name.contents);
+    Ok(Some(match name.tag {
+        RFC822_NAME => format!("email:{}", text()),
+        DNS_NAME => format!("DNS:{}", text()),
+        URI => format!("URI:{}", text()),
+        DIRECTORY_NAME => {
+            let (directory_name, _) = der::read_tagged(name.contents, der::SEQUENCE)?;
+            format!("DirName:{}", name_to_string(directory_name.contents)?)
+        }
+        IP_ADDRESS => match name.contents.len() {
+            4 => format!(
+                "IP:{}",
+                Ipv4Addr::from(<[u8; 4]>::try_from(name.contents).unwrap())
+            ),
+            16 => format!(
+                "IP:{}",
+                Ipv6Addr::from(<[u8; 16]>::try_from(name.contents).unwrap())
+            ),
+            _ => return Err("invalid IP address in subject alternative name".into()),
+        },
+        _ => return Ok(None),
+    }))
+}
+
+/// Returns the subject alternative names of a DER certificate, formatted like `DNS:proxy.lab` and
+/// `IP:10.0.0.5`. Returns an empty list if the certificate doesn't have the extension.
+pub fn subject_alt_names(cert: &[u8]) -> Result<Vec<String>> {
+    let tbs = tbs_certificate(cert).chain_err(|| "invalid certificate")?;
+    let extensions = match tbs.extensions {
+        Some(extensions) => extensions,
+        None => return Ok(Vec::new()),
+    };
+    let (extensions, _) = der::read_tagged(extensions.contents, der::SEQUENCE)?;
+    for extension in der::read_all(extensions.contents)? {
+        let (oid, rest) = der::read_tagged(extension.contents, der::OID)?;
+        if der::oid_to_string(oid.contents)? != SUBJECT_ALT_NAME_OID {
+            continue;
+        }
+        // The critical flag is left out when it is false
+        let (value, _) = match der::read_tagged(rest, der::BOOLEAN) {
+            Ok((_, rest)) => der::read_tagged(rest, der::OCTET_STRING)?,
+            Err(_) => der::read_tagged(rest, der::OCTET_STRING)?,
+        };
+        let (names, _) = der::read_tagged(value.contents, der::SEQUENCE)?;
+        return der::read_all(names.contents)?
+            .iter()
+            .filter_map(|name| general_name_to_string(name).transpose())
+            .collect::<Result<_>>()
+            .chain_err(|| "invalid subject alternative names");
+    }
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::load_certs;
+    use crate::tls::x509::{
+        public_key, serial_number, subject, subject_alt_names, subject_name,
+        subject_public_key_info,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn read_subject() {
+        let cert = &load_certs(Path::new("tests/certs/cert.pem")).unwrap()[0];
+        assert_eq!(
+            subject(&cert.0).unwrap(),
+            "C=AU, ST=Some-State, O=Test Cert, CN=localhost"
+        );
+
+        let cert = &load_certs(Path::new("tests/certs/sni_cert.pem")).unwrap()[0];
+        assert_eq!(subject(&cert.0).unwrap(), "CN=example.test");
+
+        let ca_cert = &load_certs(Path::new("tests/certs/sni_ca_cert.pem")).unwrap()[0];
+        assert_eq!(subject(&ca_cert.0).unwrap(), "CN=Test_SNI_CA");
+    }
+
+    #[test]
+    fn read_subject_public_key_info() {
+        let cert = &load_certs(Path::new("tests/certs/cert.pem")).unwrap()[0];
+        let spki = subject_public_key_info(&cert.0).unwrap();
+        // SEQUENCE { SEQUENCE { rsaEncryption OID, ...
+        assert_eq!(&spki[4..8], &[0x30, 0x0d, 0x06, 0x09]);
+        assert!(cert.0.windows(spki.len()).any(|window| window == spki));
+    }
+
+    #[test]
+    fn read_subject_name_and_public_key() {
+        let cert = &load_certs(Path::new("tests/certs/sni_ca_cert.pem")).unwrap()[0];
+        // SEQUENCE { SET { SEQUENCE { commonName OID, "Test_SNI_CA" } } }
+        let name = subject_name(&cert.0).unwrap();
+        assert_eq!(&name[..2], &[0x30, 0x16]);
+        assert_eq!(&name[name.len() - 11..], b"Test_SNI_CA");
+
+        // RSAPublicKey SEQUENCE, without the BIT STRING's unused bits count
+        let spki = subject_public_key_info(&cert.0).unwrap();
+        let key = public_key(&cert.0).unwrap();
+        assert_eq!(key[0], 0x30);
+        assert!(spki.ends_with(key));
+    }
+
+    #[test]
+    fn read_serial_number() {
+        // From `openssl x509 -noout -serial`
+        let cert = &load_certs(Path::new("tests/certs/cert.pem")).unwrap()[0];
+        assert_eq!(
+            serial_number(&cert.0).unwrap(),
+            &[
+                0x58, 0xc4, 0x64, 0xc3, 0xb5, 0x6e, 0x87, 0x9c, 0xad, 0xb8, 0xc5, 0xfd, 0xc6, 0x8b,
+                0x17, 0x62, 0x22, 0xd9, 0x82, 0xd9
+            ]
+        );
+    }
+
+    #[test]
+    fn read_subject_alt_names() {
+        let cert = &load_certs(Path::new("tests/certs/client_cert.pem")).unwrap()[0];
+        assert_eq!(
+            subject_alt_names(&cert.0).unwrap(),
+            vec!["DNS:forward-proxy".to_string()]
+        );
+
+        let cert = &load_certs(Path::new("tests/certs/cert.pem")).unwrap()[0];
+        assert_eq!(
+            subject_alt_names(&cert.0).unwrap(),
+            vec!["DNS:localhost".to_string()]
+        );
+    }
+
+    #[test]
+    fn invalid_certificates() {
+        assert!(subject(&[]).is_err());
+        assert!(subject_public_key_info(&[0x30, 0x03, 0x30, 0x01, 0x00]).is_err());
+    }
+}
@@ -1,9 +1,14 @@
-use tokio;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
-use rust_tls_proxy::compression::Compressor;
-use rust_tls_proxy::{forward_proxy, reverse_proxy};
+use rust_tls_proxy::backends::Backends;
+use rust_tls_proxy::client_identity::IdentityForwarding;
+use rust_tls_proxy::compression::{CompressionSettings, Compressor, Scheme};
+use rust_tls_proxy::tls::{
+    self, CertKeyPaths, CertsSettings, ClientAuth, ClientTlsSettings, ServerTlsSettings,
+    UpstreamTlsSettings,
+};
+use rust_tls_proxy::{forward_proxy, negotiation, reverse_proxy};
 use std::fs::File;
 use std::io::{BufReader, Write};
 use std::net::SocketAddr;
@@ -11,7 +16,7 @@ use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio_rustls::rustls::internal::pemfile;
-use tokio_rustls::rustls::{ClientConfig, NoClientAuth, ServerConfig};
+use tokio_rustls::rustls::{ClientConfig, NoClientAuth, ServerConfig, Session};
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 
@@ -28,27 +33,20 @@ async fn transparent_proxy() {
     let forward_proxy_listener = TcpListener::bind(forward_in_addr).await.unwrap();
 
     tokio::spawn(async move {
-        reverse_proxy::run_async(
-            reverse_in_addr,
-            vec![reverse_out_addr],
-            false,
-            false,
-            None,
-            None,
-        )
-        .await
-        .unwrap();
+        reverse_proxy::run_async(reverse_in_addr, vec![reverse_out_addr], vec![], None)
+            .await
+            .unwrap();
     });
 
     tokio::spawn(async move {
-        forward_proxy::forward_proxy(forward_proxy_listener, false, false, None)
+        forward_proxy::forward_proxy(forward_proxy_listener, vec![], None)
             .await
             .unwrap();
     });
 
     let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
 
-    in_send_conn.write_all(&message).await.unwrap();
+    in_send_conn.write_all(message).await.unwrap();
 
     let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
 
@@ -69,13 +67,11 @@ async fn transparent_compression_proxy() {
     let response_message = "Hello! It was indeed proxied.".as_bytes();
 
     let mut ref_compressor = Compressor::new(Vec::new());
-    ref_compressor.write_all(&message).unwrap();
+    ref_compressor.write_all(message).unwrap();
     let compressed_message = ref_compressor.finish().unwrap();
 
     let mut response_ref_compressor = Compressor::new(Vec::new());
-    response_ref_compressor
-        .write_all(&response_message)
-        .unwrap();
+    response_ref_compressor.write_all(response_message).unwrap();
     let compressed_response_message = response_ref_compressor.finish().unwrap();
 
     let mut forward_out_sent = Vec::new();
@@ -83,6 +79,8 @@ async fn transparent_compression_proxy() {
     let mut forward_received_response = Vec::new();
     let mut client_received_response = Vec::new();
 
+    let compression = [CompressionSettings::new(Scheme::Deflate)];
+
     let forward_in_addr: SocketAddr = "127.0.0.1:8123".parse().unwrap();
     let forward_out_addr: SocketAddr = "127.0.0.1:9443".parse().unwrap();
     let reverse_in_addr: SocketAddr = "127.0.0.1:8124".parse().unwrap();
@@ -96,9 +94,7 @@ async fn transparent_compression_proxy() {
         reverse_proxy::run_async(
             reverse_in_addr,
             vec![reverse_out_addr],
-            true,
-            false,
-            None,
+            vec![CompressionSettings::new(Scheme::Deflate)],
             None,
         )
         .await
@@ -106,17 +102,24 @@ async fn transparent_compression_proxy() {
     });
 
     tokio::spawn(async move {
-        forward_proxy::forward_proxy(forward_proxy_listener, true, false, None)
-            .await
-            .unwrap();
+        forward_proxy::forward_proxy(
+            forward_proxy_listener,
+            vec![CompressionSettings::new(Scheme::Deflate)],
+            None,
+        )
+        .await
+        .unwrap();
     });
 
     let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
 
-    let write_fut = in_send_conn.write_all(&message);
+    let write_fut = in_send_conn.write_all(message);
 
     // Check output from forward proxy to make sure it's compressed
     let (mut forward_out_conn, _) = forward_out_listener.accept().await.unwrap();
+    negotiation::accept(&mut forward_out_conn, &compression)
+        .await
+        .unwrap();
     write_fut.await.unwrap();
     in_send_conn.shutdown().await.unwrap();
     forward_out_conn
@@ -127,6 +130,9 @@ async fn transparent_compression_proxy() {
     assert_eq!(forward_out_sent, compressed_message);
 
     let mut reverse_in_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    negotiation::offer(&mut reverse_in_conn, &compression)
+        .await
+        .unwrap();
     let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
     reverse_in_conn.write_all(&forward_out_sent).await.unwrap();
     reverse_in_conn.shutdown().await.unwrap();
@@ -135,7 +141,7 @@ async fn transparent_compression_proxy() {
     assert_eq!(received, message);
 
     // Now test the response to make sure it is compressed between the reverse and forward proxies
-    out_recv_conn.write_all(&response_message).await.unwrap();
+    out_recv_conn.write_all(response_message).await.unwrap();
     out_recv_conn.shutdown().await.unwrap();
     reverse_in_conn
         .read_to_end(&mut forward_received_response)
@@ -189,6 +195,8 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
     let mut forward_received_response = Vec::new();
     let mut client_received_response = Vec::new();
 
+    let compression = [CompressionSettings::new(Scheme::Deflate)];
+
     let forward_in_addr: SocketAddr = "127.0.0.1:8123".parse().unwrap();
     let forward_out_addr: SocketAddr = "127.0.0.1:9443".parse().unwrap();
     let reverse_in_addr: SocketAddr = "127.0.0.1:8124".parse().unwrap();
@@ -202,9 +210,7 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
         reverse_proxy::run_async(
             reverse_in_addr,
             vec![reverse_out_addr],
-            true,
-            false,
-            None,
+            vec![CompressionSettings::new(Scheme::Deflate)],
             None,
         )
         .await
@@ -212,17 +218,24 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
     });
 
     tokio::spawn(async move {
-        forward_proxy::forward_proxy(forward_proxy_listener, true, false, None)
-            .await
-            .unwrap();
+        forward_proxy::forward_proxy(
+            forward_proxy_listener,
+            vec![CompressionSettings::new(Scheme::Deflate)],
+            None,
+        )
+        .await
+        .unwrap();
     });
 
     let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
 
-    let write_fut = in_send_conn.write_all(&message);
+    let write_fut = in_send_conn.write_all(message);
 
     // Check output from forward proxy to make sure it's compressed
     let (mut forward_out_conn, _) = forward_out_listener.accept().await.unwrap();
+    negotiation::accept(&mut forward_out_conn, &compression)
+        .await
+        .unwrap();
     write_fut.await.unwrap();
     in_send_conn.shutdown().await.unwrap();
     forward_out_conn
@@ -236,6 +249,9 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
     );
 
     let mut reverse_in_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    negotiation::offer(&mut reverse_in_conn, &compression)
+        .await
+        .unwrap();
     let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
     reverse_in_conn.write_all(&forward_out_sent).await.unwrap();
     reverse_in_conn.shutdown().await.unwrap();
@@ -244,7 +260,7 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
     assert_eq!(received, message);
 
     // Now test the response to make sure it is compressed between the reverse and forward proxies
-    out_recv_conn.write_all(&response_message).await.unwrap();
+    out_recv_conn.write_all(response_message).await.unwrap();
     out_recv_conn.shutdown().await.unwrap();
     reverse_in_conn
         .read_to_end(&mut forward_received_response)
@@ -308,24 +324,28 @@ async fn transparent_encryption_proxy() {
         reverse_proxy::run_async(
             reverse_in_addr,
             vec![reverse_out_addr],
-            false,
-            true,
-            Some(cert_path),
-            Some(key_path),
+            vec![],
+            Some(ServerTlsSettings::new(CertKeyPaths::new(
+                cert_path, key_path,
+            ))),
         )
         .await
         .unwrap();
     });
 
     tokio::spawn(async move {
-        forward_proxy::forward_proxy(forward_proxy_listener, false, true, Some(ca_cert_path))
-            .await
-            .unwrap();
+        forward_proxy::forward_proxy(
+            forward_proxy_listener,
+            vec![],
+            Some(ClientTlsSettings::new(ca_cert_path)),
+        )
+        .await
+        .unwrap();
     });
 
     let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
 
-    let write_fut = in_send_conn.write_all(&message);
+    let write_fut = in_send_conn.write_all(message);
 
     // Check output from forward proxy to make sure it's compressed
     let (forward_out_tcp_conn, _) = forward_out_listener.accept().await.unwrap();
@@ -406,24 +426,28 @@ Duis efficitur, lacus a condimentum rhoncus, justo ex tristique neque, fermentum
         reverse_proxy::run_async(
             reverse_in_addr,
             vec![reverse_out_addr],
-            false,
-            true,
-            Some(cert_path),
-            Some(key_path),
+            vec![],
+            Some(ServerTlsSettings::new(CertKeyPaths::new(
+                cert_path, key_path,
+            ))),
         )
         .await
         .unwrap();
     });
 
     tokio::spawn(async move {
-        forward_proxy::forward_proxy(forward_proxy_listener, false, true, Some(ca_cert_path))
-            .await
-            .unwrap();
+        forward_proxy::forward_proxy(
+            forward_proxy_listener,
+            vec![],
+            Some(ClientTlsSettings::new(ca_cert_path)),
+        )
+        .await
+        .unwrap();
     });
 
     let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
 
-    let write_fut = in_send_conn.write_all(&message);
+    let write_fut = in_send_conn.write_all(message);
 
     // Check output from forward proxy to make sure it's compressed
     let (forward_out_tcp_conn, _) = forward_out_listener.accept().await.unwrap();
@@ -466,12 +490,14 @@ async fn transparent_compression_and_encryption_proxy() {
         "Hello world! This is message should be proxied, compressed, and encrypted.".as_bytes();
 
     let mut ref_compressor = Compressor::new(Vec::new());
-    ref_compressor.write_all(&message).unwrap();
+    ref_compressor.write_all(message).unwrap();
     let compressed_message = ref_compressor.finish().unwrap();
 
     let mut forward_out_sent = Vec::new();
     let mut received = Vec::new();
 
+    let compression = [CompressionSettings::new(Scheme::Deflate)];
+
     let forward_in_addr: SocketAddr = "127.0.0.1:8123".parse().unwrap();
     let forward_out_addr: SocketAddr = "127.0.0.1:9443".parse().unwrap();
     let reverse_in_addr: SocketAddr = "127.0.0.1:8124".parse().unwrap();
@@ -503,24 +529,28 @@ async fn transparent_compression_and_encryption_proxy() {
         reverse_proxy::run_async(
             reverse_in_addr,
             vec![reverse_out_addr],
-            true,
-            true,
-            Some(cert_path),
-            Some(key_path),
+            vec![CompressionSettings::new(Scheme::Deflate)],
+            Some(ServerTlsSettings::new(CertKeyPaths::new(
+                cert_path, key_path,
+            ))),
         )
         .await
         .unwrap();
     });
 
     tokio::spawn(async move {
-        forward_proxy::forward_proxy(forward_proxy_listener, true, true, Some(ca_cert_path))
-            .await
-            .unwrap();
+        forward_proxy::forward_proxy(
+            forward_proxy_listener,
+            vec![CompressionSettings::new(Scheme::Deflate)],
+            Some(ClientTlsSettings::new(ca_cert_path)),
+        )
+        .await
+        .unwrap();
     });
 
     let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
 
-    let write_fut = in_send_conn.write_all(&message);
+    let write_fut = in_send_conn.write_all(message);
 
     // Check output from forward proxy to make sure it's compressed
     let (forward_out_tcp_conn, _) = forward_out_listener.accept().await.unwrap();
@@ -528,6 +558,9 @@ async fn transparent_compression_and_encryption_proxy() {
         .accept(forward_out_tcp_conn)
         .await
         .unwrap();
+    negotiation::accept(&mut forward_out_conn, &compression)
+        .await
+        .unwrap();
 
     write_fut.await.unwrap();
     in_send_conn.shutdown().await.unwrap();
@@ -545,6 +578,9 @@ async fn transparent_compression_and_encryption_proxy() {
         .connect(dnsname, reverse_in_tcp_conn)
         .await
         .unwrap();
+    negotiation::offer(&mut reverse_in_conn, &compression)
+        .await
+        .unwrap();
 
     let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
     reverse_in_conn.write_all(&forward_out_sent).await.unwrap();
@@ -553,3 +589,572 @@ async fn transparent_compression_and_encryption_proxy() {
 
     assert_eq!(received, message);
 }
+
+#[tokio::test]
+async fn transparent_mutual_tls_proxy() {
+    // TODO: hack to make sure previous sockets are freed up
+    tokio::time::sleep(Duration::from_secs(6)).await;
+
+    let message = "Hello world! This is message should be proxied with a client cert.".as_bytes();
+    let mut received = Vec::new();
+
+    let forward_in_addr: SocketAddr = "127.0.0.1:8123".parse().unwrap();
+    let reverse_in_addr: SocketAddr = "127.0.0.1:9443".parse().unwrap();
+    let reverse_out_addr: SocketAddr = "127.0.0.1:8125".parse().unwrap();
+
+    let out_listener = TcpListener::bind(reverse_out_addr).await.unwrap();
+    let forward_proxy_listener = TcpListener::bind(forward_in_addr).await.unwrap();
+
+    let mut server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    server_tls.client_auth = ClientAuth::Required("tests/certs/client_ca_cert.pem".into());
+
+    let mut client_tls = ClientTlsSettings::new("tests/certs/ca_cert.pem");
+    client_tls.client_cert = Some(CertKeyPaths::new(
+        "tests/certs/client_cert.pem",
+        "tests/certs/client_key.pem",
+    ));
+
+    tokio::spawn(async move {
+        reverse_proxy::run_async(
+            reverse_in_addr,
+            vec![reverse_out_addr],
+            vec![],
+            Some(server_tls),
+        )
+        .await
+        .unwrap();
+    });
+
+    tokio::spawn(async move {
+        forward_proxy::forward_proxy(forward_proxy_listener, vec![], Some(client_tls))
+            .await
+            .unwrap();
+    });
+
+    let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
+
+    in_send_conn.write_all(message).await.unwrap();
+
+    let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
+
+    in_send_conn.shutdown().await.unwrap();
+    out_recv_conn.read_to_end(&mut received).await.unwrap();
+
+    assert_eq!(received, message);
+}
+
+#[tokio::test]
+async fn reverse_proxy_requires_client_cert() {
+    let message =
+        "Hello world! This is message should only be proxied with a client cert.".as_bytes();
+    let mut received = Vec::new();
+
+    // Only the reverse proxy runs, so these ports don't clash with the other tests
+    let reverse_in_addr: SocketAddr = "127.0.0.1:8126".parse().unwrap();
+    let reverse_out_addr: SocketAddr = "127.0.0.1:8127".parse().unwrap();
+
+    let out_listener = TcpListener::bind(reverse_out_addr).await.unwrap();
+
+    let mut server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    server_tls.client_auth = ClientAuth::Required("tests/certs/client_ca_cert.pem".into());
+
+    tokio::spawn(async move {
+        reverse_proxy::run_async(
+            reverse_in_addr,
+            vec![reverse_out_addr],
+            vec![],
+            Some(server_tls),
+        )
+        .await
+        .unwrap();
+    });
+    // Give the reverse proxy time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    // Without a client certificate the handshake fails and nothing reaches the server
+    let anonymous_connector = TlsConnector::from(Arc::new(
+        tls::client_config(&ClientTlsSettings::new("tests/certs/ca_cert.pem")).unwrap(),
+    ));
+    let tcp_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    if let Ok(mut conn) = anonymous_connector.connect(dnsname, tcp_conn).await {
+        // With TLS 1.3 the client can finish its side of the handshake before the server rejects
+        // the missing certificate
+        let _ = conn.write_all(message).await;
+        let mut response = Vec::new();
+        let _ = conn.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), out_listener.accept())
+            .await
+            .is_err()
+    );
+
+    // Plaintext connections would get around the client certificate, so they are refused
+    let mut plain_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    plain_conn.write_all(message).await.unwrap();
+    let mut response = Vec::new();
+    let _ = plain_conn.read_to_end(&mut response).await;
+    assert!(response.is_empty());
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), out_listener.accept())
+            .await
+            .is_err()
+    );
+
+    // A rejected client doesn't stop the proxy from accepting authenticated ones
+    let mut client_tls = ClientTlsSettings::new("tests/certs/ca_cert.pem");
+    client_tls.client_cert = Some(CertKeyPaths::new(
+        "tests/certs/client_cert.pem",
+        "tests/certs/client_key.pem",
+    ));
+    let connector = TlsConnector::from(Arc::new(tls::client_config(&client_tls).unwrap()));
+    let tcp_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    let mut conn = connector.connect(dnsname, tcp_conn).await.unwrap();
+
+    conn.write_all(message).await.unwrap();
+    let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
+    conn.shutdown().await.unwrap();
+    out_recv_conn.read_to_end(&mut received).await.unwrap();
+
+    assert_eq!(received, message);
+}
+
+#[tokio::test]
+async fn reverse_proxy_selects_cert_by_sni() {
+    // Only the reverse proxy runs, so these ports don't clash with the other tests
+    let reverse_in_addr: SocketAddr = "127.0.0.1:8128".parse().unwrap();
+    let reverse_out_addr: SocketAddr = "127.0.0.1:8129".parse().unwrap();
+
+    let out_listener = TcpListener::bind(reverse_out_addr).await.unwrap();
+
+    let mut server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    server_tls.sni_certs = tls::load_sni_config(Path::new("tests/certs/sni.json")).unwrap();
+
+    tokio::spawn(async move {
+        reverse_proxy::run_async(
+            reverse_in_addr,
+            vec![reverse_out_addr],
+            vec![],
+            Some(server_tls),
+        )
+        .await
+        .unwrap();
+    });
+    // Give the reverse proxy time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // Each name is only trusted with the CA that signed its certificate, so a successful handshake
+    // shows which certificate was presented
+    for (name, ca_cert) in [
+        ("example.test", "tests/certs/sni_ca_cert.pem"),
+        ("localhost", "tests/certs/ca_cert.pem"),
+    ] {
+        let message = format!("Hello {}!", name);
+        let mut received = Vec::new();
+
+        let connector = TlsConnector::from(Arc::new(
+            tls::client_config(&ClientTlsSettings::new(ca_cert)).unwrap(),
+        ));
+        let dnsname = DNSNameRef::try_from_ascii_str(name).unwrap();
+        let tcp_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+        let mut conn = connector.connect(dnsname, tcp_conn).await.unwrap();
+
+        conn.write_all(message.as_bytes()).await.unwrap();
+        let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
+        conn.shutdown().await.unwrap();
+        out_recv_conn.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, message.as_bytes());
+    }
+
+    // Unknown names fall back to the default certificate
+    let connector = TlsConnector::from(Arc::new(
+        tls::client_config(&ClientTlsSettings::new("tests/certs/sni_ca_cert.pem")).unwrap(),
+    ));
+    let dnsname = DNSNameRef::try_from_ascii_str("other.test").unwrap();
+    let tcp_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    assert!(connector.connect(dnsname, tcp_conn).await.is_err());
+}
+
+#[tokio::test]
+async fn transparent_encryption_proxy_with_server_name_table() {
+    // TODO: hack to make sure previous sockets are freed up
+    tokio::time::sleep(Duration::from_secs(7)).await;
+
+    let message = "Hello world! This is message should be proxied to example.test.".as_bytes();
+    let mut received = Vec::new();
+
+    let forward_in_addr: SocketAddr = "127.0.0.1:8123".parse().unwrap();
+    let reverse_in_addr: SocketAddr = "127.0.0.1:9443".parse().unwrap();
+    let reverse_out_addr: SocketAddr = "127.0.0.1:8125".parse().unwrap();
+
+    let out_listener = TcpListener::bind(reverse_out_addr).await.unwrap();
+    let forward_proxy_listener = TcpListener::bind(forward_in_addr).await.unwrap();
+
+    // Reverse DNS gives "localhost" for 127.0.0.1, and the example.test cert is only presented if
+    // the forward proxy asks for that name instead
+    let mut client_tls = ClientTlsSettings::new("tests/certs/sni_ca_cert.pem");
+    client_tls.server_names = "127.0.0.0/8=example.test".parse().unwrap();
+
+    tokio::spawn(async move {
+        forward_proxy::forward_proxy(forward_proxy_listener, vec![], Some(client_tls))
+            .await
+            .unwrap();
+    });
+
+    // The reverse proxy isn't running yet, so the forward proxy can't connect and closes the
+    // connection without stopping
+    let mut failed_conn = TcpStream::connect(forward_in_addr).await.unwrap();
+    let mut failed_received = Vec::new();
+    failed_conn
+        .read_to_end(&mut failed_received)
+        .await
+        .unwrap_or_default();
+    assert!(failed_received.is_empty());
+
+    let mut server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    server_tls.sni_certs = tls::load_sni_config(Path::new("tests/certs/sni.json")).unwrap();
+
+    tokio::spawn(async move {
+        reverse_proxy::run_async(
+            reverse_in_addr,
+            vec![reverse_out_addr],
+            vec![],
+            Some(server_tls),
+        )
+        .await
+        .unwrap();
+    });
+    // Give the reverse proxy time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut in_send_conn = TcpStream::connect(forward_in_addr).await.unwrap();
+
+    in_send_conn.write_all(message).await.unwrap();
+
+    let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
+
+    in_send_conn.shutdown().await.unwrap();
+    out_recv_conn.read_to_end(&mut received).await.unwrap();
+
+    assert_eq!(received, message);
+}
+
+#[tokio::test]
+async fn reverse_proxy_routes_by_alpn() {
+    // Only the reverse proxy runs, so these ports don't clash with the other tests
+    let reverse_in_addr: SocketAddr = "127.0.0.1:8130".parse().unwrap();
+    let default_out_addr: SocketAddr = "127.0.0.1:8131".parse().unwrap();
+    let h2_out_addr: SocketAddr = "127.0.0.1:8132".parse().unwrap();
+
+    let default_listener = TcpListener::bind(default_out_addr).await.unwrap();
+    let h2_listener = TcpListener::bind(h2_out_addr).await.unwrap();
+
+    let mut server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    server_tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let mut backends = Backends::new(vec![default_out_addr]);
+    backends.add_alpn_route(b"h2", vec![h2_out_addr]);
+
+    tokio::spawn(async move {
+        reverse_proxy::run_async(reverse_in_addr, backends, vec![], Some(server_tls))
+            .await
+            .unwrap();
+    });
+    // Give the reverse proxy time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    for (alpn, listener) in [
+        ("h2", &h2_listener),
+        ("http/1.1", &default_listener),
+        ("", &default_listener),
+    ] {
+        let message = format!("Hello {}!", alpn);
+        let mut received = Vec::new();
+
+        let mut client_tls = ClientTlsSettings::new("tests/certs/ca_cert.pem");
+        if !alpn.is_empty() {
+            client_tls.alpn_protocols = vec![alpn.as_bytes().to_vec()];
+        }
+        let connector = TlsConnector::from(Arc::new(tls::client_config(&client_tls).unwrap()));
+        let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let tcp_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+        let mut conn = connector.connect(dnsname, tcp_conn).await.unwrap();
+
+        let expected_alpn = Some(alpn.as_bytes()).filter(|alpn| !alpn.is_empty());
+        assert_eq!(conn.get_ref().1.get_alpn_protocol(), expected_alpn);
+
+        conn.write_all(message.as_bytes()).await.unwrap();
+        let (mut out_recv_conn, _) = listener.accept().await.unwrap();
+        conn.shutdown().await.unwrap();
+        out_recv_conn.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, message.as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn reverse_proxy_serves_tls_and_plaintext() {
+    let tls_message = "Hello world! This message is encrypted.".as_bytes();
+    let plain_message = "GET / HTTP/1.1\r\nHost: plaintext.test\r\n\r\n".as_bytes();
+
+    // Only the reverse proxy runs, so these ports don't clash with the other tests
+    let reverse_in_addr: SocketAddr = "127.0.0.1:8133".parse().unwrap();
+    let reverse_out_addr: SocketAddr = "127.0.0.1:8134".parse().unwrap();
+
+    let out_listener = TcpListener::bind(reverse_out_addr).await.unwrap();
+
+    let server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    tokio::spawn(async move {
+        reverse_proxy::run_async(
+            reverse_in_addr,
+            vec![reverse_out_addr],
+            vec![],
+            Some(server_tls),
+        )
+        .await
+        .unwrap();
+    });
+    // Give the reverse proxy time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let connector = TlsConnector::from(Arc::new(
+        tls::client_config(&ClientTlsSettings::new("tests/certs/ca_cert.pem")).unwrap(),
+    ));
+    let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let tcp_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    let mut tls_conn = connector.connect(dnsname, tcp_conn).await.unwrap();
+    tls_conn.write_all(tls_message).await.unwrap();
+    tls_conn.shutdown().await.unwrap();
+
+    let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
+    let mut received = Vec::new();
+    out_recv_conn.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, tls_message);
+
+    // Sent on the same port, and forwarded as it is
+    let mut plain_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    plain_conn.write_all(plain_message).await.unwrap();
+    plain_conn.shutdown().await.unwrap();
+
+    let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
+    let mut received = Vec::new();
+    out_recv_conn.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, plain_message);
+}
+
+#[tokio::test]
+async fn reverse_proxy_with_generated_certs() {
+    let message = "Hello world! This message is encrypted with fresh certificates.".as_bytes();
+
+    let cert_dir = std::env::temp_dir().join(format!(
+        "rust_tls_proxy_generated_certs_{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&cert_dir);
+    let mut certs = CertsSettings::new(&cert_dir);
+    certs.server_names = vec!["proxy.lab".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+    tls::write_certs(&certs).unwrap();
+
+    // Only the reverse proxy runs, so these ports don't clash with the other tests
+    let reverse_in_addr: SocketAddr = "127.0.0.1:8135".parse().unwrap();
+    let reverse_out_addr: SocketAddr = "127.0.0.1:8136".parse().unwrap();
+
+    let out_listener = TcpListener::bind(reverse_out_addr).await.unwrap();
+
+    let mut server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        cert_dir.join("cert.pem"),
+        cert_dir.join("key.pem"),
+    ));
+    server_tls.client_auth = ClientAuth::Required(cert_dir.join("ca_cert.pem"));
+    tokio::spawn(async move {
+        reverse_proxy::run_async(
+            reverse_in_addr,
+            vec![reverse_out_addr],
+            vec![],
+            Some(server_tls),
+        )
+        .await
+        .unwrap();
+    });
+    // Give the reverse proxy time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut client_tls = ClientTlsSettings::new(cert_dir.join("ca_cert.pem"));
+    client_tls.client_cert = Some(CertKeyPaths::new(
+        cert_dir.join("client_cert.pem"),
+        cert_dir.join("client_key.pem"),
+    ));
+    let connector = TlsConnector::from(Arc::new(tls::client_config(&client_tls).unwrap()));
+    let dnsname = DNSNameRef::try_from_ascii_str("proxy.lab").unwrap();
+    let tcp_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    let mut conn = connector.connect(dnsname, tcp_conn).await.unwrap();
+    conn.write_all(message).await.unwrap();
+    conn.shutdown().await.unwrap();
+
+    let (mut out_recv_conn, _) = out_listener.accept().await.unwrap();
+    let mut received = Vec::new();
+    out_recv_conn.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, message);
+
+    std::fs::remove_dir_all(&cert_dir).unwrap();
+}
+
+#[tokio::test]
+async fn reverse_proxy_connects_to_tls_backend() {
+    let message = "Hello world! This message is encrypted again for the backend.".as_bytes();
+
+    // Only the reverse proxy runs, so these ports don't clash with the other tests
+    let reverse_in_addr: SocketAddr = "127.0.0.1:8137".parse().unwrap();
+    let backend_addr: SocketAddr = "127.0.0.1:8138".parse().unwrap();
+
+    // The backend only accepts TLS from clients with a certificate
+    let mut backend_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    backend_tls.client_auth = ClientAuth::Required("tests/certs/client_ca_cert.pem".into());
+    let acceptor = TlsAcceptor::from(Arc::new(tls::server_config(&backend_tls).unwrap()));
+    let backend_listener = TcpListener::bind(backend_addr).await.unwrap();
+
+    let mut upstream_tls =
+        UpstreamTlsSettings::new("localhost", "tests/certs/ca_cert.pem").unwrap();
+    upstream_tls.client.client_cert = Some(CertKeyPaths::new(
+        "tests/certs/client_cert.pem",
+        "tests/certs/client_key.pem",
+    ));
+    let mut backends = Backends::new(vec![backend_addr]);
+    backends
+        .set_upstream_tls(backend_addr, upstream_tls)
+        .unwrap();
+
+    tokio::spawn(async move {
+        reverse_proxy::run_async(reverse_in_addr, backends, vec![], None)
+            .await
+            .unwrap();
+    });
+    // Give the reverse proxy time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut in_conn = TcpStream::connect(reverse_in_addr).await.unwrap();
+    in_conn.write_all(message).await.unwrap();
+    in_conn.shutdown().await.unwrap();
+
+    let (backend_tcp_conn, _) = backend_listener.accept().await.unwrap();
+    let mut backend_conn = acceptor.accept(backend_tcp_conn).await.unwrap();
+    let (_, session) = backend_conn.get_ref();
+    assert_eq!(session.get_sni_hostname(), Some("localhost"));
+    assert!(session.get_peer_certificates().is_some());
+
+    let mut received = Vec::new();
+    backend_conn.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, message);
+}
+
+#[tokio::test]
+async fn reverse_proxy_passes_client_identity() {
+    // Only the reverse proxies run, so these ports don't clash with the other tests
+    let tcp_in_addr: SocketAddr = "127.0.0.1:8139".parse().unwrap();
+    let tcp_backend_addr: SocketAddr = "127.0.0.1:8140".parse().unwrap();
+    let http_in_addr: SocketAddr = "127.0.0.1:8141".parse().unwrap();
+    let http_backend_addr: SocketAddr = "127.0.0.1:8142".parse().unwrap();
+
+    let tcp_listener = TcpListener::bind(tcp_backend_addr).await.unwrap();
+    let http_listener = TcpListener::bind(http_backend_addr).await.unwrap();
+
+    let mut server_tls = ServerTlsSettings::new(CertKeyPaths::new(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    ));
+    server_tls.client_auth = ClientAuth::Required("tests/certs/client_ca_cert.pem".into());
+    for (in_addr, backend_addr, mode) in [
+        (tcp_in_addr, tcp_backend_addr, "proxy-v2"),
+        (http_in_addr, http_backend_addr, "http"),
+    ] {
+        let mut backends = Backends::new(vec![backend_addr]);
+        backends
+            .set_identity_forwarding(backend_addr, IdentityForwarding::from_mode(mode).unwrap())
+            .unwrap();
+        let server_tls = server_tls.clone();
+        tokio::spawn(async move {
+            reverse_proxy::run_async(in_addr, backends, vec![], Some(server_tls))
+                .await
+                .unwrap();
+        });
+    }
+    // Give the reverse proxies time to start listening
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut client_tls = ClientTlsSettings::new("tests/certs/ca_cert.pem");
+    client_tls.client_cert = Some(CertKeyPaths::new(
+        "tests/certs/client_cert.pem",
+        "tests/certs/client_key.pem",
+    ));
+    let connector = TlsConnector::from(Arc::new(tls::client_config(&client_tls).unwrap()));
+    let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    // From `openssl x509 -noout -fingerprint -sha256`
+    let fingerprint = "399988f838a36838627976c09b8f7db53a98a989f55f0e02f7755aa996eb836c";
+
+    // Raw TCP backends get a PROXY protocol v2 header before the data
+    let tcp_conn = TcpStream::connect(tcp_in_addr).await.unwrap();
+    let client_addr = tcp_conn.local_addr().unwrap();
+    let mut conn = connector.connect(dnsname, tcp_conn).await.unwrap();
+    conn.write_all(b"hello").await.unwrap();
+    conn.shutdown().await.unwrap();
+
+    let (mut backend_conn, _) = tcp_listener.accept().await.unwrap();
+    let mut received = Vec::new();
+    backend_conn.read_to_end(&mut received).await.unwrap();
+    assert!(received.starts_with(b"\r\n\r\n\0\r\nQUIT\n\x21\x11"));
+    let len = u16::from_be_bytes([received[14], received[15]]) as usize;
+    assert_eq!(&received[16..20], &[127, 0, 0, 1]);
+    assert_eq!(&received[24..26], &client_addr.port().to_be_bytes());
+    let tlvs = &received[28..16 + len];
+    let contains = |needle: &[u8]| tlvs.windows(needle.len()).any(|window| window == needle);
+    assert!(contains(b"\xe0\x00\x10CN=forward-proxy"));
+    assert!(contains(b"\xe1\x00\x11DNS:forward-proxy"));
+    assert!(contains(fingerprint.as_bytes()));
+    assert_eq!(&received[16 + len..], b"hello");
+
+    // HTTP backends get headers, replacing forged ones
+    let tcp_conn = TcpStream::connect(http_in_addr).await.unwrap();
+    let mut conn = connector.connect(dnsname, tcp_conn).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Client-Cert-Subject: CN=admin\r\n\r\n")
+        .await
+        .unwrap();
+    conn.shutdown().await.unwrap();
+
+    let (mut backend_conn, _) = http_listener.accept().await.unwrap();
+    let mut received = String::new();
+    backend_conn.read_to_string(&mut received).await.unwrap();
+    assert_eq!(
+        received,
+        format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\n\
+             X-Client-Cert-Subject: CN=forward-proxy\r\n\
+             X-Client-Cert-SAN: DNS:forward-proxy\r\n\
+             X-Client-Cert-Fingerprint: {}\r\n\r\n",
+            fingerprint
+        )
+    );
+}